@@ -1,9 +1,58 @@
+use std::rc::Rc;
+
 use log::info;
-use mos6502::dbg::{Apple1Pia, CycleDetect, Dbg, DumpScreen};
+use mos6502::callstack::CallStack;
+use mos6502::coverage::{self, Coverage};
+use mos6502::dap;
+use mos6502::dbg::{CycleDetect, Dbg, DbgChain, MemoryMonitor, Trace};
+use mos6502::export::Export;
 use mos6502::hexdump;
+use mos6502::input::{InputSource, Live, Paste, Record, Script};
+use mos6502::loader::{self, AddressSpace};
+use mos6502::machines::apple1::{self, Apple1};
+use mos6502::machines::c64::{self, kernal, C64};
+use mos6502::machines::easy6502::{self, Easy6502};
+use mos6502::machines::eater::{self, Eater};
+use mos6502::machines::kim1::{self, Kim1};
+use mos6502::profiler::Profiler;
 use mos6502::reg::Registers;
+use mos6502::screenshot::{Screenshot, Trigger, Video};
+use mos6502::symbols::SymbolTable;
 use mos6502::{cpu::Cpu, mem::Memory};
 
+enum Machine {
+    Apple1(Apple1),
+    Easy6502(Easy6502),
+    Eater(Eater),
+    Kim1(Kim1),
+    C64(C64),
+}
+
+impl Machine {
+    // None for the machines without a screen, see HAS_VIDEO
+    fn video(&self) -> Option<Box<dyn Video>> {
+        match self {
+            Machine::Easy6502(_) => Some(Box::new(easy6502::Screen)),
+            Machine::Apple1(apple1) => Some(Box::new(apple1::Display::new(apple1.pia()))),
+            Machine::C64(_) => Some(Box::new(c64::Display)),
+            Machine::Eater(_) | Machine::Kim1(_) => None,
+        }
+    }
+    fn clock_hz(&self) -> u64 {
+        match self {
+            Machine::Apple1(_) => apple1::CLOCK_HZ,
+            // no real clock, cycles are taken as 1 MHz
+            Machine::Easy6502(_) => 1_000_000,
+            Machine::Eater(_) => eater::CLOCK_HZ,
+            Machine::Kim1(_) => kim1::CLOCK_HZ,
+            Machine::C64(_) => c64::CLOCK_HZ,
+        }
+    }
+}
+
+// the machines --screenshot works with
+const HAS_VIDEO: [&str; 3] = ["easy6502", "apple1", "c64"];
+
 struct DbgNop;
 
 impl Dbg for DbgNop {
@@ -12,8 +61,281 @@ impl Dbg for DbgNop {
     }
 }
 fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut symbols = SymbolTable::default();
+    let mut hooks = Vec::new();
+    let mut folded_out = "profile.folded".to_string();
+    let mut coverage_out = "coverage.map".to_string();
+    let mut annotate = None;
+    let mut trap_threshold = None;
+    let mut pass = None;
+    let mut machine = "apple1".to_string();
+    let mut programs = Vec::new();
+    let mut load_address = 0x600;
+    let mut format = None;
+    let mut input_script = None;
+    let mut paste = None;
+    let mut paste_rate = 60;
+    let mut record = None;
+    let mut replay = false;
+    let mut headless = false;
+    let mut seed = None;
+    let mut screenshot_out = None;
+    let mut screenshot_triggers = Vec::new();
+    let mut screenshot_scale = 1;
+    let mut export_out = None;
+    let mut export_format = None;
+    let mut export_range = None;
+    let mut export_triggers = Vec::new();
+    let mut apple1_config = apple1::Config::default();
+    let mut eater_config = eater::Config::default();
+    let mut kim1_config = kim1::Config::default();
+    let mut c64_config = c64::Config::default();
+    let mut trap_kernal = false;
+    let mut c64_roms_given = false;
+    let mut host_dir = ".".to_string();
+    let mut c64_exit = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dap" => {
+                // stdout carries the protocol, keep the log out of it
+                simple_logging::log_to_stderr(log::LevelFilter::Info);
+                dap::serve_stdio();
+                return;
+            }
+            "--symbols" => {
+                let name = args.next().expect("--symbols <file>");
+                if let Err(e) = symbols.load(&name) {
+                    panic!("failed to load symbols: {}", e);
+                }
+            }
+            "--machine" => machine = args.next().expect("--machine <apple1|easy6502|eater|kim1|c64>"),
+            // may be repeated, files placed later win where they overlap. The format is
+            // detected unless given (see loader), raw binaries are loaded at @<addr> or
+            // --load-address and o65 files relocated there. easy6502 starts at the first
+            // entry point or --load-address, the c64 calls a PRG's SYS address, the
+            // Apple-1 gets the R command of a Wozmon transcript typed, the other machines
+            // run their monitor.
+            "--program" => {
+                let arg = args.next().expect("--program <file>[@<addr|label>]");
+                programs.push(match arg.rsplit_once('@') {
+                    Some((name, addr)) => (
+                        name.to_string(),
+                        Some(symbols.eval(addr).expect("bad address")),
+                    ),
+                    None => (arg, None),
+                });
+            }
+            "--format" => {
+                format = Some(
+                    args.next()
+                        .and_then(|v| loader::Format::parse(&v))
+                        .expect("--format <bin|txt|prg|ihex|srec|o65|woz>"),
+                )
+            }
+            "--load-address" => {
+                load_address = args
+                    .next()
+                    .and_then(|v| symbols.eval(&v))
+                    .expect("--load-address <addr>")
+            }
+            // may be repeated or given as a comma separated list, hooks run in that order.
+            // 'terminal' is the screen and keyboard of the machine
+            "--hook" => hooks.extend(
+                args.next()
+                    .expect("--hook <terminal|headless|kernal|cycle|monitor|trace|backtrace|profile|coverage|screenshot|export|nop>[,...]")
+                    .split(',')
+                    .map(|h| h.to_string()),
+            ),
+            "--folded" => folded_out = args.next().expect("--folded <file>"),
+            "--trap-threshold" => {
+                trap_threshold = Some(
+                    args.next()
+                        .and_then(|v| v.parse().ok())
+                        .expect("--trap-threshold <cycles>"),
+                )
+            }
+            // test ROMs: exit with an error unless the cpu traps at this address
+            "--pass" => pass = Some(args.next().expect("--pass <addr|label>")),
+            // low RAM of the Apple-1 in KB, $E000-$EFFF is always there
+            "--ram" => {
+                apple1_config.ram_size = args
+                    .next()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .expect("--ram <KB>")
+                    * 1024
+            }
+            "--wozmon" => apple1_config.wozmon = args.next().expect("--wozmon <file>"),
+            // the Apple-1 or C64 BASIC ROM, whichever machine runs
+            "--basic" => {
+                let basic = args.next().expect("--basic <file>");
+                apple1_config.basic = Some(basic.clone());
+                c64_config.basic = Some(basic);
+                c64_roms_given = true;
+            }
+            "--no-basic" => {
+                apple1_config.basic = None;
+                c64_config.basic = None;
+                c64_roms_given = true;
+            }
+            "--aci" => apple1_config.aci_rom = Some(args.next().expect("--aci <rom>")),
+            "--tape-in" => apple1_config.tape_in = Some(args.next().expect("--tape-in <wav>")),
+            "--tape-out" => {
+                apple1_config.tape_out = Some(args.next().expect("--tape-out <wav>"))
+            }
+            "--cps" => {
+                apple1_config.chars_per_second = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .expect("--cps <characters per second>")
+            }
+            // eater: the 32K ROM image at $8000
+            "--rom" => eater_config.rom = args.next().expect("--rom <file>"),
+            "--lcd-4bit" => eater_config.lcd_wiring = eater::LcdWiring::FourBit,
+            // eater: ACIA at $5000 connected to stdio, a pty or a tcp port
+            "--serial" => {
+                eater_config.serial = Some(args.next().expect("--serial <stdio|pty|tcp:port>"))
+            }
+            // kim1: the ROMs of the two 6530s
+            "--rom-002" => kim1_config.rom_002 = args.next().expect("--rom-002 <file>"),
+            "--rom-003" => kim1_config.rom_003 = args.next().expect("--rom-003 <file>"),
+            // kim1: the monitor on the TTY line instead of keypad and display
+            "--tty" => kim1_config.tty = true,
+            "--baud" => {
+                kim1_config.baud = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    // at least a cycle a bit
+                    .filter(|v| (1..=kim1::CLOCK_HZ).contains(v))
+                    .expect("--baud <rate>")
+            }
+            // c64: the ROMs besides BASIC
+            "--kernal" => {
+                c64_config.kernal = Some(args.next().expect("--kernal <file>"));
+                c64_roms_given = true;
+            }
+            // c64: KERNAL calls serviced on the host with the console and files in
+            // --host-dir, no ROMs are needed unless given
+            "--trap-kernal" => trap_kernal = true,
+            "--host-dir" => host_dir = args.next().expect("--host-dir <dir>"),
+            "--chargen" => c64_config.chargen = Some(args.next().expect("--chargen <file>")),
+            // no raw terminal: machine output goes to stdout, the log to stderr
+            "--headless" => headless = true,
+            // keys from a script or recording instead of the terminal
+            "--input" => input_script = Some(args.next().expect("--input <script>")),
+            // text typed on the keyboard, e.g. a Wozmon transcript into the Apple-1
+            "--paste" => paste = Some(args.next().expect("--paste <file>")),
+            // characters per second, the Apple-1 display takes 60
+            "--paste-rate" => {
+                paste_rate = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|v| *v > 0)
+                    .expect("--paste-rate <chars per second>")
+            }
+            "--record" => record = Some(args.next().expect("--record <file>")),
+            // a recorded session with the random seed it ran with
+            "--replay" => {
+                input_script = Some(args.next().expect("--replay <recording>"));
+                replay = true;
+            }
+            // random numbers of easy6502, by default from the script or a random seed
+            "--seed" => seed = Some(args.next().and_then(|v| v.parse().ok()).expect("--seed <n>")),
+            // without --screenshot-at/--screenshot-pc the capture is taken when the run ends
+            "--screenshot" => {
+                screenshot_out = Some(args.next().expect("--screenshot <file.png|file.ppm>"))
+            }
+            "--screenshot-at" => screenshot_triggers.push(Trigger::Cycle(
+                args.next()
+                    .and_then(|v| v.parse().ok())
+                    .expect("--screenshot-at <cycles>"),
+            )),
+            // once, the first time the cpu gets there. May be repeated to capture again.
+            "--screenshot-pc" => {
+                let pc = args.next().expect("--screenshot-pc <addr|label>");
+                screenshot_triggers.push(Trigger::Pc(symbols.eval(&pc).expect("bad address")));
+            }
+            "--screenshot-scale" => {
+                screenshot_scale = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .expect("--screenshot-scale <factor>")
+            }
+            // memory as a program file, by default all of it when the run ends
+            "--export" => export_out = Some(args.next().expect("--export <file>")),
+            // by default from the extension
+            "--export-format" => {
+                export_format = Some(
+                    args.next()
+                        .and_then(|v| loader::Format::parse(&v))
+                        .expect("--export-format <bin|txt|prg|ihex|srec|woz>"),
+                )
+            }
+            "--export-range" => {
+                let range = args.next().expect("--export-range <start-end|start+len>");
+                export_range = Some(coverage::parse_range(&range).expect("bad address range"));
+            }
+            "--export-at" => export_triggers.push(Trigger::Cycle(
+                args.next()
+                    .and_then(|v| v.parse().ok())
+                    .expect("--export-at <cycles>"),
+            )),
+            // once, like --screenshot-pc
+            "--export-pc" => {
+                let pc = args.next().expect("--export-pc <addr|label>");
+                export_triggers.push(Trigger::Pc(symbols.eval(&pc).expect("bad address")));
+            }
+            "--coverage" => coverage_out = args.next().expect("--coverage <file>"),
+            "--annotate" => {
+                let range = args.next().expect("--annotate <start-end|start+len>");
+                annotate = Some(coverage::parse_range(&range).expect("bad address range"));
+            }
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
+    if screenshot_out.is_some() && !HAS_VIDEO.contains(&machine.as_str()) {
+        panic!(
+            "--screenshot: the {} machine has no screen to capture",
+            machine
+        );
+    }
+    if trap_kernal {
+        if !c64_roms_given {
+            c64_config.kernal = None;
+            c64_config.basic = None;
+        }
+        // the console belongs to the program
+        headless = true;
+        if !hooks.iter().any(|h| h == "kernal") {
+            hooks.insert(0, "kernal".to_string());
+        }
+    }
+    if hooks.is_empty() {
+        hooks.push(if headless { "headless" } else { "terminal" }.to_string());
+    }
+    if !screenshot_triggers.is_empty() && !hooks.iter().any(|h| h == "screenshot") {
+        hooks.push("screenshot".to_string());
+    }
+    if !export_triggers.is_empty() && !hooks.iter().any(|h| h == "export") {
+        hooks.push("export".to_string());
+    }
+    // --pass needs the trap the cycle hook finds
+    if pass.is_some() && !hooks.iter().any(|h| h == "cycle") {
+        hooks.push("cycle".to_string());
+    }
+    let has_hook = |name: &str| hooks.iter().any(|h| h == name);
+    // the ACIA reads stdin and writes stdout itself
+    let serial_stdio = machine == "eater" && eater_config.serial.as_deref() == Some("stdio");
+    if serial_stdio && has_hook("terminal") {
+        panic!("--serial stdio: the terminal hook needs the console for the LCD, use --headless");
+    }
+    let symbols = Rc::new(symbols);
     // env_logger::init();
-    simple_logging::log_to(std::io::stdout(), log::LevelFilter::Info);
+    if headless {
+        simple_logging::log_to_stderr(log::LevelFilter::Info);
+    } else {
+        simple_logging::log_to(std::io::stdout(), log::LevelFilter::Info);
+    }
     info!("test");
     // let mem = Memory::new(hexdump::read());
     // let mem = Memory::new(hexdump::read_bin(
@@ -23,31 +345,278 @@ fn main() {
 
     // let start = 0x4000u16;
     // let ram = hexdump::read_bin("6502-test-code/AllSuiteA.bin", start.into());
-    // let start = 0x600u16;
-    // let ram = hexdump::read_txt("asm/snake.txt");
-    let start = 0xe000u16;
-    let ram = hexdump::read_bin("6502-test-code/apple1basic.bin", start.into());
-    // let mem = Memory::new(hexdump::read_bin("6502-test-code/AllSuiteA.bin", 0x4000));
-    // let start = 0x600;
-
-    let mem = Memory::new(ram);
-    let mut cpu = Cpu::new(mem);
-    // cpu.set_pc(0xfff0);
-    cpu.set_pc(start);
-
-    cpu.dump_mem();
-    {
-        let mut dbg: Box<dyn Dbg> = if false {
-            Box::new(DumpScreen::default())
-        } else if false {
-            Box::new(CycleDetect::default())
-        } else if true {
-            Box::new(Apple1Pia::default())
-        } else {
-            Box::new(DbgNop)
+    let script = input_script
+        .as_ref()
+        .map(|path| Script::load(path).unwrap());
+    let script_seed = script.as_ref().and_then(|s| s.seed());
+    // only easy6502 has random numbers, the other machines record no seed
+    if replay && machine == "easy6502" && script_seed.is_none() {
+        panic!("--replay: {} has no seed", input_script.unwrap());
+    }
+    let seed = seed.or(script_seed).unwrap_or_else(rand::random);
+    if programs.is_empty() && machine == "easy6502" {
+        programs.push(("asm/snake.txt".to_string(), None));
+    }
+    let mut space = AddressSpace::new();
+    for (name, base) in &programs {
+        if let Err(e) = space.load(name, format, Some(base.unwrap_or(load_address))) {
+            panic!("failed to load program: {}", e);
+        }
+    }
+    let (machine, mut cpu) = match machine.as_str() {
+        "apple1" => {
+            let (apple1, mem) = match Apple1::new(&apple1_config) {
+                Ok(machine) => machine,
+                Err(e) => panic!("failed to set up Apple-1: {}", e),
+            };
+            let mut cpu = Cpu::new(mem);
+            // before the reset, the program may bring the reset vector
+            space.store(cpu.get_mem_mut());
+            cpu.reset();
+            (Machine::Apple1(apple1), cpu)
+        }
+        "easy6502" => {
+            info!("random seed {}", seed);
+            let (easy6502, mem) = Easy6502::new(space.to_ram(), seed);
+            let mut cpu = Cpu::new(mem);
+            cpu.set_pc(space.entry().unwrap_or(load_address));
+            (Machine::Easy6502(easy6502), cpu)
+        }
+        "eater" => {
+            let (eater, mem) = match Eater::new(&eater_config) {
+                Ok(machine) => machine,
+                Err(e) => panic!("failed to set up Ben Eater 6502: {}", e),
+            };
+            let mut cpu = Cpu::new(mem);
+            space.store(cpu.get_mem_mut());
+            cpu.reset();
+            (Machine::Eater(eater), cpu)
+        }
+        "kim1" => {
+            let (kim1, mem) = match Kim1::new(&kim1_config) {
+                Ok(machine) => machine,
+                Err(e) => panic!("failed to set up KIM-1: {}", e),
+            };
+            let mut cpu = Cpu::new(mem);
+            space.store(cpu.get_mem_mut());
+            cpu.reset();
+            (Machine::Kim1(kim1), cpu)
+        }
+        "c64" => {
+            let (c64, mem) = match C64::new(&c64_config) {
+                Ok(machine) => machine,
+                Err(e) => panic!("failed to set up C64: {}", e),
+            };
+            let mut cpu = Cpu::new(mem);
+            space.store(cpu.get_mem_mut());
+            cpu.reset();
+            c64_exit = space.entry().map(|entry| kernal::start(&mut cpu, entry));
+            (Machine::C64(c64), cpu)
+        }
+        _ => panic!("unknown machine: {}", machine),
+    };
+
+    if !headless {
+        hexdump::dump_with_symbols(cpu.get_mem().get(), Some(&symbols));
+    }
+    // the live keyboard only when a hook reads it, the kernal hook and a stdio ACIA read
+    // stdin themselves
+    let mut input: Option<Box<dyn InputSource>> = match script {
+        Some(script) => Some(Box::new(script)),
+        None if serial_stdio => Some(Box::new(Script::default())),
+        None if has_hook("terminal") || has_hook("headless") => Some(Box::new(Live::default())),
+        None => None,
+    };
+    if paste.is_some() && input.is_none() {
+        panic!("--paste: nothing reads the keyboard, use the terminal or headless hook");
+    }
+    // the run command of a transcript loaded into the Apple-1 goes to Wozmon. Without
+    // a keyboard the cpu starts there right away, Wozmon doesn't get to set up the PIA.
+    let run = match (&machine, space.entry()) {
+        (Machine::Apple1(_), Some(entry)) if input.is_some() => format!("{:04X}R\n", entry),
+        (Machine::Apple1(_), Some(entry)) => {
+            info!(
+                "no keyboard to type {:04X}R, starting the program directly",
+                entry
+            );
+            cpu.set_pc(entry);
+            String::new()
+        }
+        _ => String::new(),
+    };
+    if paste.is_some() || !run.is_empty() {
+        let source = input.take().unwrap();
+        let text = match &paste {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) => panic!("failed to read {}: {}", path, e),
+            },
+            None => String::new(),
+        };
+        let cycles_per_char = machine.clock_hz() / paste_rate;
+        input = Some(Box::new(Paste::new(
+            source,
+            &(run + &text),
+            cycles_per_char,
+        )));
+    }
+    if let Some(path) = &record {
+        let recorder = Record::new(input.take().expect("nothing to record"), path).unwrap();
+        let recorder = match machine {
+            Machine::Easy6502(_) => recorder.with_seed(seed).unwrap(),
+            Machine::Apple1(_) | Machine::Eater(_) | Machine::Kim1(_) | Machine::C64(_) => recorder,
         };
-        cpu.run(&mut *dbg);
+        input = Some(Box::new(recorder));
+    }
+    let mut profiler = Profiler::new(symbols.clone());
+    let mut coverage = Coverage::new();
+    let mut cycle_detect = CycleDetect::default();
+    if let Some(cycles) = trap_threshold {
+        cycle_detect = cycle_detect.with_idle_threshold(cycles);
+    }
+    let mut screenshot = screenshot_out.as_ref().map(|path| {
+        screenshot_triggers.iter().fold(
+            Screenshot::new(machine.video().unwrap(), path).with_scale(screenshot_scale),
+            |s, t| s.at(*t),
+        )
+    });
+    let mut export = export_out.as_ref().map(|path| {
+        let mut export = export_triggers
+            .iter()
+            .fold(Export::new(path), |e, t| e.at(*t));
+        if let Some(format) = export_format {
+            export = export.with_format(format);
+        }
+        if let Some((start, end)) = export_range {
+            export = export.with_range(start, end);
+        }
+        if let Some(entry) = space.entry() {
+            export = export.with_entry(entry);
+        }
+        export
+    });
+    {
+        // hooks that are inspected after the run can only be lent once
+        let mut profiler = Some(&mut profiler);
+        let mut coverage = Some(&mut coverage);
+        let mut cycle_detect = Some(&mut cycle_detect);
+        let mut screenshot = screenshot.as_mut();
+        let mut export = export.as_mut();
+        let mut dbg = DbgChain::new();
+        for hook in &hooks {
+            match hook.as_str() {
+                "terminal" => {
+                    let input = input.take().expect("terminal hook given twice");
+                    match &machine {
+                        Machine::Apple1(apple1) => dbg.push(apple1.terminal(input)),
+                        Machine::Easy6502(easy6502) => dbg.push(easy6502.terminal(input)),
+                        Machine::Eater(eater) => dbg.push(eater.terminal(input)),
+                        Machine::Kim1(kim1) => dbg.push(kim1.terminal(input)),
+                        Machine::C64(c64) => dbg.push(c64.terminal(input)),
+                    }
+                }
+                "headless" => {
+                    let input = input.take().expect("headless hook given twice");
+                    match &machine {
+                        Machine::Apple1(apple1) => dbg.push(
+                            apple1
+                                .headless(input)
+                                .with_echo(Box::new(std::io::stdout())),
+                        ),
+                        Machine::Easy6502(easy6502) => dbg.push(easy6502.headless(input)),
+                        Machine::Eater(eater) => dbg.push(eater.headless(input)),
+                        Machine::Kim1(kim1) => dbg.push(kim1.headless(input)),
+                        Machine::C64(c64) => dbg.push(c64.headless(input)),
+                    }
+                }
+                "kernal" => match &machine {
+                    Machine::C64(_) => {
+                        let mut traps =
+                            kernal::Traps::new(&host_dir).with_basic(c64_config.basic.is_some());
+                        if let Some(sp) = c64_exit {
+                            traps = traps.with_exit(sp);
+                        }
+                        dbg.push(traps)
+                    }
+                    _ => panic!("the kernal hook needs --machine c64"),
+                },
+                "cycle" => dbg.push(cycle_detect.take().expect("cycle hook given twice")),
+                "monitor" => dbg.push(MemoryMonitor::new(10)),
+                "trace" => dbg.push(Trace::new(symbols.clone())),
+                "backtrace" => dbg.push(CallStack::new(symbols.clone()).print_on_crash()),
+                "profile" => dbg.push(profiler.take().expect("profile hook given twice")),
+                "coverage" => dbg.push(coverage.take().expect("coverage hook given twice")),
+                "screenshot" => dbg.push(
+                    screenshot
+                        .take()
+                        .expect("screenshot hook needs a single --screenshot <file>"),
+                ),
+                "export" => dbg.push(
+                    export
+                        .take()
+                        .expect("export hook needs a single --export <file>"),
+                ),
+                "nop" => dbg.push(DbgNop),
+                _ => panic!("unknown hook: {}", hook),
+            }
+        }
+        cpu.run(&mut dbg);
+    }
+    if let Some(screenshot) = &mut screenshot {
+        if screenshot_triggers.is_empty() {
+            screenshot.save(cpu.get_mem()).unwrap();
+        }
+    }
+    if let Some(export) = &mut export {
+        if export_triggers.is_empty() {
+            export.save(cpu.get_mem(), cpu.get_reg().pc).unwrap();
+        }
+    }
+    if has_hook("profile") {
+        profiler
+            .write_report(&mut std::io::stdout(), cpu.get_mem())
+            .unwrap();
+        let mut f = std::fs::File::create(&folded_out).unwrap();
+        profiler.write_folded(&mut f).unwrap();
+        info!("folded stacks written to {}", folded_out);
+    }
+    if has_hook("coverage") {
+        // merged with earlier runs
+        coverage.save_file(&coverage_out).unwrap();
+        info!("coverage written to {}", coverage_out);
+        let mut merged = Coverage::new();
+        merged.load_file(&coverage_out).unwrap();
+        merged
+            .write_summary(&mut std::io::stdout(), 0, 0xffff)
+            .unwrap();
+        if let Some((start, end)) = annotate {
+            merged
+                .write_annotated(
+                    &mut std::io::stdout(),
+                    cpu.get_mem(),
+                    Some(&symbols),
+                    start,
+                    end,
+                )
+                .unwrap();
+        }
     }
     // println!("data: {:?}", data);
-    cpu.dump_mem();
+    if !headless {
+        hexdump::dump_with_symbols(cpu.get_mem().get(), Some(&symbols));
+    }
+    if let Some(pass) = pass {
+        let pass = symbols.eval(&pass).expect("bad --pass address");
+        match cycle_detect.trap() {
+            Some(trap) if trap.pc == pass => info!("passed"),
+            Some(trap) => {
+                info!("failed: {}", trap);
+                std::process::exit(1);
+            }
+            None => {
+                info!("failed: no trap at ${:04x}", pass);
+                std::process::exit(1);
+            }
+        }
+    }
 }
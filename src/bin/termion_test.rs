@@ -19,8 +19,7 @@ fn main() {
 
     let mut keys = stdin.keys();
     'outer: loop {
-        loop {
-            let Some(c) = keys.next() else { break };
+        for c in keys.by_ref() {
             // write!(
             //     stdout,
             //     "{}{}",
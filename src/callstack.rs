@@ -0,0 +1,266 @@
+// Shadow call stack built from JSR/RTS and interrupt entry/RTI.
+//
+// The stack layout follows Cpu::push_stack16/push_stack: 'sp' points at the last
+// byte pushed, so the 16 bit value on top of the stack is at $0100 + sp.
+use std::{collections::VecDeque, rc::Rc};
+
+use log::{debug, error};
+
+use crate::{
+    dbg::Dbg,
+    disasm::{self, Mode},
+    mem::Memory,
+    reg::Registers,
+    symbols::SymbolTable,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Interrupt,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // address of the JSR, or of the instruction that was interrupted
+    pub call_site: u16,
+    // entry point of the subroutine / interrupt handler
+    pub target: u16,
+    // value on the stack that RTS/RTI is expected to pop
+    pub return_addr: u16,
+    // stack pointer after the return address was pushed
+    pub sp: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum StackEvent {
+    // RTS popped an address that was not pushed by the matching JSR (e.g. inline parameters)
+    ModifiedReturn { pc: u16, expected: u16, actual: u16 },
+    // RTS used as an indirect jump through pushed addresses (dispatch tables)
+    RtsDispatch { pc: u16, target: u16 },
+    // frames dropped because the stack pointer moved past them (PLA/PLA, TXS)
+    Unwound { pc: u16, frames: usize },
+}
+
+impl std::fmt::Display for StackEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StackEvent::ModifiedReturn {
+                pc,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "${:04x}: RTS to ${:04x}, return address was ${:04x}",
+                pc,
+                actual.wrapping_add(1),
+                expected.wrapping_add(1)
+            ),
+            StackEvent::RtsDispatch { pc, target } => {
+                write!(f, "${:04x}: RTS dispatch to ${:04x}", pc, target)
+            }
+            StackEvent::Unwound { pc, frames } => {
+                write!(f, "${:04x}: {} frame(s) discarded", pc, frames)
+            }
+        }
+    }
+}
+
+const MAX_EVENTS: usize = 16;
+
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    // possible successors of the previous instruction, to detect interrupts
+    expected: Option<([Option<u16>; 2], u16)>,
+    events: VecDeque<StackEvent>,
+    symbols: Rc<SymbolTable>,
+    print_on_crash: bool,
+}
+
+fn stack_top16(reg: &Registers, mem: &Memory, offs: u16) -> u16 {
+    mem.load16(0x100u16.wrapping_add(reg.sp).wrapping_add(offs))
+}
+
+// where control can go after executing the instruction at reg.pc
+fn successors(reg: &Registers, mem: &Memory) -> [Option<u16>; 2] {
+    let inst = disasm::disassemble(mem, reg.pc);
+    match inst.bytes[0] {
+        0x4c | 0x20 => [Some(inst.operand()), None],
+        0x6c => [Some(mem.load16(inst.operand())), None],
+        0x60 => [Some(stack_top16(reg, mem, 0).wrapping_add(1)), None],
+        0x40 => [Some(stack_top16(reg, mem, 1)), None],
+        _ if inst.opcode.map(|o| o.mode) == Some(Mode::Relative) => {
+            [Some(inst.next_addr()), inst.target()]
+        }
+        _ => [Some(inst.next_addr()), None],
+    }
+}
+
+impl CallStack {
+    pub fn new(symbols: Rc<SymbolTable>) -> Self {
+        Self {
+            symbols,
+            ..Default::default()
+        }
+    }
+    // print a backtrace before the cpu stops on BRK or an unknown opcode
+    pub fn print_on_crash(mut self) -> Self {
+        self.print_on_crash = true;
+        self
+    }
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+    pub fn events(&self) -> impl Iterator<Item = &StackEvent> {
+        self.events.iter()
+    }
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.expected = None;
+        self.events.clear();
+    }
+    fn event(&mut self, event: StackEvent) {
+        debug!("call stack: {}", event);
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    // the cpu entered an interrupt handler (reg.pc) from the instruction at 'pc'. Saves
+    // observe() from having to guess it.
+    pub fn interrupt(&mut self, reg: &Registers, pc: u16) {
+        self.frames.push(Frame {
+            kind: FrameKind::Interrupt,
+            call_site: pc,
+            target: reg.pc,
+            return_addr: pc,
+            sp: reg.sp,
+        });
+        self.expected = None;
+    }
+
+    // to be called before each instruction is executed
+    pub fn observe(&mut self, reg: &Registers, mem: &Memory) {
+        let pc = reg.pc;
+        if let Some((expected, prev_sp)) = self.expected.take() {
+            // control went somewhere the previous instruction cannot lead to. If PC and SR
+            // were pushed on the way, this was an interrupt.
+            if !expected.contains(&Some(pc)) && reg.sp == prev_sp.wrapping_sub(3) {
+                let return_addr = stack_top16(reg, mem, 1);
+                self.frames.push(Frame {
+                    kind: FrameKind::Interrupt,
+                    call_site: return_addr,
+                    target: pc,
+                    return_addr,
+                    sp: reg.sp,
+                });
+            }
+        }
+
+        // anything below the stack pointer is gone
+        let live = self.frames.iter().take_while(|f| f.sp >= reg.sp).count();
+        if live < self.frames.len() {
+            let frames = self.frames.len() - live;
+            self.frames.truncate(live);
+            self.event(StackEvent::Unwound { pc, frames });
+        }
+
+        match mem.load(pc) {
+            // JSR
+            0x20 => {
+                self.frames.push(Frame {
+                    kind: FrameKind::Call,
+                    call_site: pc,
+                    target: mem.load16(pc.wrapping_add(1)),
+                    return_addr: pc.wrapping_add(2),
+                    sp: reg.sp.wrapping_sub(2),
+                });
+            }
+            // RTS
+            0x60 => {
+                let actual = stack_top16(reg, mem, 0);
+                match self.frames.last().copied() {
+                    Some(frame) if frame.kind == FrameKind::Call && frame.sp == reg.sp => {
+                        if frame.return_addr != actual {
+                            self.event(StackEvent::ModifiedReturn {
+                                pc,
+                                expected: frame.return_addr,
+                                actual,
+                            });
+                        }
+                        self.frames.pop();
+                    }
+                    // the popped address was pushed on top of the frame (or without any
+                    // frame): this is a computed jump, not a return
+                    _ => self.event(StackEvent::RtsDispatch {
+                        pc,
+                        target: actual.wrapping_add(1),
+                    }),
+                }
+            }
+            // RTI
+            0x40 => {
+                if let Some(frame) = self.frames.last() {
+                    if frame.kind == FrameKind::Interrupt && frame.sp == reg.sp {
+                        self.frames.pop();
+                    }
+                }
+            }
+            opc => {
+                if self.print_on_crash && (opc == 0x00 || disasm::lookup(opc).is_none()) {
+                    error!("cpu stopped at ${:04x} (opcode ${:02x})", pc, opc);
+                    for line in self.backtrace(pc, Some(&self.symbols)) {
+                        error!("{}", line);
+                    }
+                }
+            }
+        }
+        self.expected = Some((successors(reg, mem), reg.sp));
+    }
+
+    // innermost frame first. Each entry is (pc, frame) where frame is the call that
+    // led there, None for the outermost level.
+    pub fn walk(&self, pc: u16) -> Vec<(u16, Option<&Frame>)> {
+        let mut out = Vec::new();
+        let mut pc = pc;
+        for frame in self.frames.iter().rev() {
+            out.push((pc, Some(frame)));
+            pc = frame.call_site;
+        }
+        out.push((pc, None));
+        out
+    }
+
+    pub fn backtrace(&self, pc: u16, symbols: Option<&SymbolTable>) -> Vec<String> {
+        let describe = |addr: u16| match symbols {
+            Some(symbols) => format!("${:04x} {}", addr, symbols.describe(addr, 0xffff)),
+            None => format!("${:04x}", addr),
+        };
+        let mut out = self
+            .walk(pc)
+            .iter()
+            .enumerate()
+            .map(|(i, (pc, frame))| match frame {
+                Some(frame) if frame.kind == FrameKind::Interrupt => {
+                    format!("#{:<2} {}  <interrupt>", i, describe(*pc))
+                }
+                _ => format!("#{:<2} {}", i, describe(*pc)),
+            })
+            .collect::<Vec<_>>();
+        if !self.events.is_empty() {
+            out.push("recent stack manipulation:".into());
+            out.extend(self.events.iter().map(|e| format!("    {}", e)));
+        }
+        out
+    }
+}
+
+impl Dbg for CallStack {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        self.observe(reg, mem);
+        false
+    }
+}
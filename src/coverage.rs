@@ -0,0 +1,273 @@
+// Code and data coverage: for each address, whether it was executed as an opcode,
+// fetched as an operand, read as data or written.
+//
+// Map files have one line per 64 addresses that were touched at all, each address
+// a hex digit of the flags below:
+//   e000 1221221221000000...
+// Loading a map into an existing Coverage merges the flags, so several runs can be
+// accumulated in one file.
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+};
+
+use crate::{
+    cpu::StepInfo,
+    dbg::Dbg,
+    disasm,
+    mem::Memory,
+    reg::Registers,
+    symbols::{self, SymbolTable},
+};
+
+pub const OPCODE: u8 = 1;
+pub const OPERAND: u8 = 2;
+pub const READ: u8 = 4;
+pub const WRITE: u8 = 8;
+
+const LINE_LEN: usize = 64;
+
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self {
+            flags: vec![0; 0x10000],
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn class(flags: u8) -> String {
+    let mut out = Vec::new();
+    if flags & OPCODE != 0 {
+        out.push("code");
+    }
+    if flags & OPERAND != 0 {
+        out.push("operand");
+    }
+    if flags & READ != 0 {
+        out.push("read");
+    }
+    if flags & WRITE != 0 {
+        out.push("written");
+    }
+    if out.is_empty() {
+        "untouched".into()
+    } else {
+        out.join(", ")
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn flags(&self, addr: u16) -> u8 {
+        self.flags[addr as usize]
+    }
+    pub fn mark(&mut self, addr: u16, flags: u8) {
+        self.flags[addr as usize] |= flags;
+    }
+    pub fn merge(&mut self, other: &Coverage) {
+        for (f, o) in self.flags.iter_mut().zip(other.flags.iter()) {
+            *f |= o;
+        }
+    }
+
+    pub fn count(&self, flags: u8) -> usize {
+        self.flags.iter().filter(|f| **f & flags != 0).count()
+    }
+
+    // per-class counts for the range start..=end
+    pub fn write_summary(&self, out: &mut dyn Write, start: u16, end: u16) -> io::Result<()> {
+        let range = &self.flags[start as usize..=end as usize];
+        let total = range.len();
+        for (name, flag) in [
+            ("code", OPCODE | OPERAND),
+            ("read", READ),
+            ("written", WRITE),
+        ] {
+            let n = range.iter().filter(|f| **f & flag != 0).count();
+            writeln!(
+                out,
+                "{:<8} {:>6} bytes {:>6.2}%",
+                name,
+                n,
+                n as f64 * 100.0 / total as f64
+            )?;
+        }
+        let untouched = range.iter().filter(|f| **f == 0).count();
+        writeln!(
+            out,
+            "{:<8} {:>6} bytes {:>6.2}%",
+            "untouched",
+            untouched,
+            untouched as f64 * 100.0 / total as f64
+        )
+    }
+
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (line, chunk) in self.flags.chunks(LINE_LEN).enumerate() {
+            if chunk.iter().all(|f| *f == 0) {
+                continue;
+            }
+            let digits = chunk.iter().map(|f| format!("{:x}", f)).collect::<String>();
+            writeln!(out, "{:04x} {}", line * LINE_LEN, digits)?;
+        }
+        Ok(())
+    }
+
+    // merges into the current flags
+    pub fn load(&mut self, input: &mut dyn BufRead) -> io::Result<()> {
+        for (i, line) in input.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((addr, digits)) = line.split_once(' ') else {
+                return Err(invalid(format!(
+                    "line {}: expected '<addr> <flags>'",
+                    i + 1
+                )));
+            };
+            let Ok(addr) = usize::from_str_radix(addr, 16) else {
+                return Err(invalid(format!("line {}: bad address '{}'", i + 1, addr)));
+            };
+            for (j, c) in digits.trim().chars().enumerate() {
+                let Some(flags) = c.to_digit(16) else {
+                    return Err(invalid(format!("line {}: bad flags '{}'", i + 1, c)));
+                };
+                if let Some(f) = self.flags.get_mut(addr + j) {
+                    *f |= flags as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_file(&mut self, name: &str) -> io::Result<()> {
+        self.load(&mut BufReader::new(File::open(name)?))
+            .map_err(|e| invalid(format!("{}: {}", name, e)))
+    }
+
+    // merge with the contents of 'name' if it exists, then write it back
+    pub fn save_file(&self, name: &str) -> io::Result<()> {
+        let mut merged = Coverage::new();
+        match merged.load_file(name) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            res => res?,
+        }
+        merged.merge(self);
+        let mut out = BufWriter::new(File::create(name)?);
+        merged.save(&mut out)?;
+        out.flush()
+    }
+
+    // disassembly of start..=end: executed instructions as code, everything else as
+    // .byte runs grouped by how it was accessed
+    pub fn write_annotated(
+        &self,
+        out: &mut dyn Write,
+        mem: &Memory,
+        symbols: Option<&SymbolTable>,
+        start: u16,
+        end: u16,
+    ) -> io::Result<()> {
+        let mut addr = start as usize;
+        let end = end as usize;
+        while addr <= end {
+            if let Some(symbols) = symbols {
+                for (_, name) in symbols.labels_in(addr as u16, addr as u16) {
+                    writeln!(out, "{}:", name)?;
+                }
+            }
+            let flags = self.flags[addr];
+            if flags & OPCODE != 0 {
+                let inst = disasm::disassemble(mem, addr as u16);
+                let mut line = format!(
+                    "{:04x}: {:<8}  {}",
+                    addr,
+                    inst.bytes_string(),
+                    inst.text_with(symbols)
+                );
+                if flags & !OPCODE != 0 {
+                    line = format!("{:<40} ; also {}", line, class(flags & !OPCODE));
+                }
+                writeln!(out, "{}", line)?;
+                addr += inst.size() as usize;
+                continue;
+            }
+            // data run: same flags, at most 8 bytes, up to the next label
+            let mut len = 1;
+            while len < 8
+                && addr + len <= end
+                && self.flags[addr + len] == flags
+                && symbols.is_none_or(|s| s.lookup((addr + len) as u16).is_none())
+            {
+                len += 1;
+            }
+            let bytes = (addr..addr + len)
+                .map(|a| format!("${:02x}", mem.load(a as u16)))
+                .collect::<Vec<_>>()
+                .join(",");
+            writeln!(
+                out,
+                "{:<40} ; {}",
+                format!("{:04x}: .byte {}", addr, bytes),
+                class(flags)
+            )?;
+            addr += len;
+        }
+        Ok(())
+    }
+}
+
+// 'start-end' or 'start+len' with numbers as accepted by symbols::parse_number. None
+// if the range is empty, backwards or beyond $FFFF.
+pub fn parse_range(s: &str) -> Option<(u16, u16)> {
+    let addr = |s: &str| u16::try_from(symbols::parse_number(s)?).ok();
+    if let Some((start, len)) = s.split_once('+') {
+        let start = addr(start)?;
+        let len = symbols::parse_number(len)?;
+        if len == 0 {
+            return None;
+        }
+        let end = u16::try_from((start as u32).checked_add(len - 1)?).ok()?;
+        return Some((start, end));
+    }
+    let (start, end) = s.split_once('-')?;
+    let (start, end) = (addr(start)?, addr(end)?);
+    (start <= end).then_some((start, end))
+}
+
+impl Dbg for Coverage {
+    fn step(&mut self, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        false
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.mark(info.pc, OPCODE);
+        let size = disasm::lookup(info.opcode).map_or(1, |o| o.size());
+        for i in 1..size {
+            self.mark(info.pc.wrapping_add(i), OPERAND);
+        }
+        false
+    }
+    fn mem_read(&mut self, addr: u16, _value: u8, _pc: u16) {
+        self.mark(addr, READ);
+    }
+    fn mem_write(&mut self, addr: u16, _value: u8, _pc: u16) {
+        self.mark(addr, WRITE);
+    }
+    fn wants_accesses(&self) -> bool {
+        true
+    }
+}
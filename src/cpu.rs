@@ -1,9 +1,42 @@
-use crate::{dbg::Dbg, hexdump, mem::Memory, reg::Registers};
+use crate::{
+    dbg::Dbg,
+    disasm, hexdump,
+    mem::{Access, Memory},
+    reg::Registers,
+};
 use log::{debug, info};
 
 pub struct Cpu {
     reg: Registers,
     mem: Memory,
+    cycles: u64,
+    // pushes (Write) and pops (Read) of the current instruction
+    stack_ops: Vec<(u16, u8, Access)>,
+    // interrupt entered since the hooks were last called, with the interrupted PC
+    entered: Option<(Interrupt, u16)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StepInfo {
+    pub pc: u16,
+    pub opcode: u8,
+    // including page crossing and branch penalties
+    pub cycles: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    Irq,
+    Nmi,
+}
+
+impl Interrupt {
+    pub fn vector(&self) -> u16 {
+        match self {
+            Interrupt::Irq => 0xfffe,
+            Interrupt::Nmi => 0xfffa,
+        }
+    }
 }
 
 impl Cpu {
@@ -11,17 +44,39 @@ impl Cpu {
         Self {
             mem,
             reg: Registers::default(),
+            cycles: 0,
+            stack_ops: Vec::new(),
+            entered: None,
             // dbg: Box::new(CycleDetect::default()),
         }
     }
     pub fn set_pc(&mut self, pc: u16) {
         self.reg.pc = pc;
     }
+    // start at the reset vector with interrupts disabled
+    pub fn reset(&mut self) {
+        self.reg = Registers::default();
+        self.reg.sr.i = true;
+        self.reg.pc = self.mem.load16(0xfffc);
+    }
     pub fn get_mem(&self) -> &Memory {
         &self.mem
     }
+    pub fn get_mem_mut(&mut self) -> &mut Memory {
+        &mut self.mem
+    }
+    pub fn get_reg(&self) -> &Registers {
+        &self.reg
+    }
+    pub fn get_reg_mut(&mut self) -> &mut Registers {
+        &mut self.reg
+    }
+    // cycles executed since the cpu was created
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
     pub fn dump_mem(&self) {
-        hexdump::dump(&self.get_mem().get());
+        hexdump::dump(self.get_mem().get());
     }
 }
 impl Cpu {
@@ -51,7 +106,7 @@ impl Cpu {
             }
             0x6c => {
                 let addr = self.mem.load16(self.reg.pc + 1);
-                self.reg.pc = self.mem.load16(addr);
+                self.reg.pc = self.mem.read16(addr);
                 info!("JMP ind: {addr:x} {:x}", self.reg.pc);
                 ((), 0)
             }
@@ -241,7 +296,6 @@ impl Cpu {
             0xd0 => {
                 if !self.reg.sr.z {
                     self.branch_relative();
-                } else {
                 }
                 ((), 2)
             }
@@ -468,26 +522,26 @@ impl Cpu {
 
             0x46 => {
                 let addr = self.addr_zeropage() as u16;
-                let v = self.reg.lsr(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.lsr(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 2)
             }
             0x56 => {
                 let addr = self.addr_zeropage_x() as u16;
-                let v = self.reg.lsr(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.lsr(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 2)
             }
             0x4e => {
                 let addr = self.addr_absolute();
-                let v = self.reg.lsr(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.lsr(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 3)
             }
             0x5e => {
                 let addr = self.addr_absolute_x();
-                let v = self.reg.lsr(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.lsr(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 3)
             }
 
@@ -510,26 +564,26 @@ impl Cpu {
 
             0x06 => {
                 let addr = self.addr_zeropage() as u16;
-                let v = self.reg.asl(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.asl(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 2)
             }
             0x16 => {
                 let addr = self.addr_zeropage_x() as u16;
-                let v = self.reg.asl(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.asl(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 2)
             }
             0x0e => {
                 let addr = self.addr_absolute();
-                let v = self.reg.asl(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.asl(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 3)
             }
             0x1e => {
                 let addr = self.addr_absolute_x();
-                let v = self.reg.asl(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.asl(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 3)
             }
             // ROL  Rotate One Bit Left (Memory or Accumulator)
@@ -549,26 +603,26 @@ impl Cpu {
 
             0x26 => {
                 let addr = self.addr_zeropage() as u16;
-                let v = self.reg.rol(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.rol(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 2)
             }
             0x36 => {
                 let addr = self.addr_zeropage_x() as u16;
-                let v = self.reg.rol(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.rol(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 2)
             }
             0x2e => {
                 let addr = self.addr_absolute();
-                let v = self.reg.rol(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.rol(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 3)
             }
             0x3e => {
                 let addr = self.addr_absolute_x();
-                let v = self.reg.rol(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.rol(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 3)
             }
             // ROR  Rotate One Bit Right (Memory or Accumulator)
@@ -588,26 +642,26 @@ impl Cpu {
 
             0x66 => {
                 let addr = self.addr_zeropage() as u16;
-                let v = self.reg.ror(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.ror(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 2)
             }
             0x76 => {
                 let addr = self.addr_zeropage_x() as u16;
-                let v = self.reg.ror(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.ror(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 2)
             }
             0x6e => {
                 let addr = self.addr_absolute();
-                let v = self.reg.ror(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.ror(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 3)
             }
             0x7e => {
                 let addr = self.addr_absolute_x();
-                let v = self.reg.ror(self.mem.load(addr));
-                self.mem.store(addr, v);
+                let v = self.reg.ror(self.mem.read(addr));
+                self.mem.write(addr, v);
                 ((), 3)
             }
             // BIT  Test Bits in Memory with Accumulator
@@ -620,7 +674,7 @@ impl Cpu {
             //      zeropage      BIT oper      24    2     3
             //      absolute      BIT oper      2C    3     4
             0x24 => (self.reg.bit(self.load_zeropage()), 2),
-            0x2c => (self.reg.bit(self.load_zeropage()), 3),
+            0x2c => (self.reg.bit(self.load_absolute()), 3),
 
             // TAX
             0xaa => {
@@ -746,7 +800,7 @@ impl Cpu {
             // NOP
             0xea => ((), 1),
             0 => {
-                println!("break on 00");
+                info!("break on 00");
                 return None;
             }
             _ => panic!("unhandled opcode: {:x} pc: {:x}", opc, self.reg.pc),
@@ -754,26 +808,36 @@ impl Cpu {
         Some(size)
     }
 
+    // the stack accesses are also recorded as memory accesses, real hardware pushes the
+    // high byte first
     fn pop_stack16(&mut self) -> u16 {
-        let ret = self.mem.load16(self.reg.sp as u16 + 0x100);
-        self.reg.sp += 2;
-        ret
+        let l = self.pop_stack() as u16;
+        let h = self.pop_stack() as u16;
+        l + (h << 8)
     }
 
     fn push_stack16(&mut self, ret: u16) {
-        self.reg.sp -= 2;
-        self.mem.store16(self.reg.sp as u16 + 0x100, ret);
+        self.push_stack((ret >> 8) as u8);
+        self.push_stack(ret as u8);
     }
 
     fn pop_stack(&mut self) -> u8 {
-        let ret = self.mem.load(self.reg.sp as u16 + 0x100);
+        let addr = self.reg.sp + 0x100;
+        let ret = self.mem.read(addr);
+        if self.mem.is_recording() {
+            self.stack_ops.push((addr, ret, Access::Read));
+        }
         self.reg.sp += 1;
         ret
     }
 
     fn push_stack(&mut self, ret: u8) {
         self.reg.sp -= 1;
-        self.mem.store(self.reg.sp as u16 + 0x100, ret);
+        let addr = self.reg.sp + 0x100;
+        if self.mem.is_recording() {
+            self.stack_ops.push((addr, ret, Access::Write));
+        }
+        self.mem.write(addr, ret);
     }
     fn branch_relative(&mut self) {
         let offs = self.mem.load(self.reg.pc + 1) as i8;
@@ -784,153 +848,197 @@ impl Cpu {
 impl Cpu {
     fn load_zeropage(&self) -> u8 {
         let zp_addr = self.addr_zeropage();
-        let oper = self.mem.load(zp_addr as u16);
-        oper
+        self.mem.read(zp_addr as u16)
     }
 
     fn load_zeropage_x(&self) -> u8 {
         let addr = self.addr_zeropage_x();
-        let oper = self.mem.load(addr as u16);
-        oper
+        self.mem.read(addr as u16)
     }
 
     fn load_zeropage_y(&self) -> u8 {
         let zp_addr = self.mem.load(self.reg.pc + 1);
         let addr = zp_addr.wrapping_add(self.reg.y);
-        let oper = self.mem.load(addr as u16);
-        oper
+        self.mem.read(addr as u16)
     }
     fn load_absolute(&self) -> u8 {
         let addr = self.addr_absolute();
-        let oper = self.mem.load(addr);
-        oper
+        self.mem.read(addr)
     }
 
     fn load_absolute_x(&self) -> u8 {
         let addr = self.addr_absolute_x();
-        let oper = self.mem.load(addr);
-        oper
+        self.mem.read(addr)
     }
     fn load_absolute_y(&self) -> u8 {
         let addr = self.addr_absolute_y();
-        let oper = self.mem.load(addr);
-        oper
+        self.mem.read(addr)
     }
     fn load_immediate(&self) -> u8 {
-        let oper = self.mem.load(self.reg.pc + 1);
-        oper
+        self.mem.load(self.reg.pc + 1)
     }
     fn load_indirect_y(&self) -> u8 {
         let eff_addr = self.addr_indirect_y();
-        self.mem.load(eff_addr)
+        self.mem.read(eff_addr)
     }
     fn load_indirect_x(&self) -> u8 {
         let eff_addr = self.addr_indirect_x();
-        self.mem.load(eff_addr)
+        self.mem.read(eff_addr)
     }
     fn store_zeropage(&mut self, v: u8) {
-        self.mem.store(self.mem.load(self.reg.pc + 1) as u16, v);
+        self.mem.write(self.mem.load(self.reg.pc + 1) as u16, v);
     }
     fn store_zeropage_x(&mut self, v: u8) {
         let zp_addr = self.mem.load(self.reg.pc + 1);
         let addr = zp_addr.wrapping_add(self.reg.x);
-        self.mem.store(addr as u16, v);
+        self.mem.write(addr as u16, v);
     }
     fn store_zeropage_y(&mut self, v: u8) {
         let zp_addr = self.mem.load(self.reg.pc + 1);
         let addr = zp_addr.wrapping_add(self.reg.y);
-        self.mem.store(addr as u16, v);
+        self.mem.write(addr as u16, v);
     }
     fn store_absolute(&mut self, v: u8) {
-        self.mem.store(self.mem.load16(self.reg.pc + 1), v);
+        self.mem.write(self.mem.load16(self.reg.pc + 1), v);
     }
     fn store_absolute_x(&mut self, v: u8) {
         let addr = self.addr_absolute_x();
-        self.mem.store(addr, v);
+        self.mem.write(addr, v);
     }
 
     fn store_absolute_y(&mut self, v: u8) {
         let addr = self.addr_absolute_y();
-        self.mem.store(addr, v);
+        self.mem.write(addr, v);
     }
 
     fn store_indirect_y(&mut self, v: u8) {
         let eff_addr = self.addr_indirect_y();
-        self.mem.store(eff_addr, v);
+        self.mem.write(eff_addr, v);
     }
 
     fn store_indirect_x(&mut self, v: u8) {
         let zp_addr = self.addr_indirect_x();
-        self.mem.store(zp_addr as u16, v);
+        self.mem.write(zp_addr, v);
     }
 
     fn addr_zeropage(&self) -> u8 {
-        let zp_addr = self.mem.load(self.reg.pc + 1);
-        zp_addr
+        self.mem.load(self.reg.pc + 1)
     }
     fn addr_zeropage_x(&self) -> u8 {
         let zp_addr = self.mem.load(self.reg.pc + 1);
-        let addr = zp_addr.wrapping_add(self.reg.x);
-        addr
+        zp_addr.wrapping_add(self.reg.x)
     }
     fn addr_absolute(&self) -> u16 {
-        let addr = self.mem.load16(self.reg.pc + 1);
-        addr
+        self.mem.load16(self.reg.pc + 1)
     }
     fn addr_absolute_x(&self) -> u16 {
         let addr = self.mem.load16(self.reg.pc + 1);
-        let addr = addr
-            .wrapping_add(self.reg.x as u16)
-            // .wrapping_add(self.reg.sr.carry())
-        ;
-        addr
+        // .wrapping_add(self.reg.sr.carry())
+        addr.wrapping_add(self.reg.x as u16)
     }
     fn addr_absolute_y(&self) -> u16 {
         let addr = self.mem.load16(self.reg.pc + 1);
-        let addr = addr
-            .wrapping_add(self.reg.y as u16)
-            // .wrapping_add(self.reg.sr.carry())
-        ;
-        addr
+        // .wrapping_add(self.reg.sr.carry())
+        addr.wrapping_add(self.reg.y as u16)
     }
     fn addr_indirect_y(&self) -> u16 {
         let zp_addr = self.mem.load(self.reg.pc + 1);
-        let addr = self.mem.load16(zp_addr as u16);
+        let addr = self.mem.read16(zp_addr as u16);
 
         debug!("zp_addr: {:x} {:x}", zp_addr, addr);
-        let eff_addr = addr
-            .wrapping_add(self.reg.y as u16)
-            // .wrapping_add(self.reg.sr.carry())
-        ;
-        eff_addr
+        // .wrapping_add(self.reg.sr.carry())
+        addr.wrapping_add(self.reg.y as u16)
     }
     fn addr_indirect_x(&self) -> u16 {
         // let zp_addr = self.mem.load(self.reg.pc + 1).wrapping_add(self.reg.x);
         // self.mem.load16(zp_addr as u16)
         let ll = self.mem.load(self.reg.pc + 1);
         let ll = ll.wrapping_add(self.reg.x);
-        self.mem.load16(ll as u16)
+        self.mem.read16(ll as u16)
+    }
+    // push PC and SR and continue at the interrupt vector. Returns false if an IRQ is
+    // masked by the I flag.
+    pub fn interrupt(&mut self, kind: Interrupt) -> bool {
+        if kind == Interrupt::Irq && self.reg.sr.i {
+            return false;
+        }
+        let pc = self.reg.pc;
+        self.push_stack16(pc);
+        self.push_stack(self.reg.sr.to_u8() & !0b10000);
+        self.reg.sr.i = true;
+        self.reg.pc = self.mem.read16(kind.vector());
+        self.cycles += 7;
+        self.mem.tick(7);
+        self.entered = Some((kind, pc));
+        debug!("{:?} -> {:x}", kind, self.reg.pc);
+        true
+    }
+    // execute a single instruction. Returns None if the cpu stopped (BRK)
+    pub fn step(&mut self) -> Option<StepInfo> {
+        // accesses are only reported by run(), drop those nobody picked up
+        self.stack_ops.clear();
+        self.mem.drain_accesses();
+        let pc = self.reg.pc;
+        let opc = self.mem.load(pc);
+        debug!("pc: {:03x}, opc: {:02x}, reg: {}", pc, opc, self.reg);
+        let opcode = disasm::lookup(opc);
+        let mut cycles = opcode.map_or(0, |o| o.cycles_at(pc, &self.reg, &self.mem));
+        let size = self.dispatch_opcode(opc)?;
+        self.reg.pc += size as u16;
+        cycles += opcode.map_or(0, |o| o.branch_cycles(pc, self.reg.pc));
+        self.cycles += cycles as u64;
+        self.mem.tick(cycles);
+        Some(StepInfo {
+            pc,
+            opcode: opc,
+            cycles,
+        })
+    }
+    // take a pending NMI or IRQ before the next instruction. Returns the interrupt and
+    // the address of the interrupted instruction if one was entered.
+    pub fn poll_interrupts(&mut self) -> Option<(Interrupt, u16)> {
+        if self.mem.nmi() {
+            self.interrupt(Interrupt::Nmi);
+        }
+        // IRQ is level triggered: taken before the next instruction while I is clear
+        if self.mem.irq() {
+            self.interrupt(Interrupt::Irq);
+        }
+        self.entered.take()
+    }
+    fn report_accesses(&mut self, dbg: &mut dyn Dbg, pc: u16) {
+        for (addr, value, access) in self.mem.drain_accesses() {
+            match access {
+                Access::Read => dbg.mem_read(addr, value, pc),
+                Access::Write => dbg.mem_write(addr, value, pc),
+            }
+        }
+        for (addr, value, access) in self.stack_ops.drain(..) {
+            match access {
+                Access::Read => dbg.stack_pop(addr, value, pc),
+                Access::Write => dbg.stack_push(addr, value, pc),
+            }
+        }
     }
     pub fn run(&mut self, dbg: &mut dyn Dbg) {
-        // let reg = &mut self.reg;
-        // let mem = &mut self.mem;
-        // stdout.flush().unwrap();
+        self.mem.set_recording(dbg.wants_accesses());
         loop {
-            // rng.gen()
+            if let Some((kind, pc)) = self.poll_interrupts() {
+                self.report_accesses(dbg, pc);
+                dbg.interrupt(kind, &self.reg);
+            }
             if dbg.step(&mut self.reg, &mut self.mem) {
                 info!("break");
                 break;
             }
-            let opc = self.mem.load(self.reg.pc);
-            debug!(
-                "pc: {:03x}, opc: {:02x}, reg: {}",
-                self.reg.pc, opc, self.reg
-            );
-            let size = match self.dispatch_opcode(opc) {
-                Some(value) => value,
-                None => break,
+            let Some(info) = self.step() else {
+                break;
             };
-            self.reg.pc += size as u16;
+            self.report_accesses(dbg, info.pc);
+            if dbg.post_step(&info, &mut self.reg, &mut self.mem) {
+                info!("break");
+                break;
+            }
         }
     }
 }
@@ -0,0 +1,730 @@
+// Debug Adapter Protocol server. Messages are exchanged as 'Content-Length' framed json,
+// see https://microsoft.github.io/debug-adapter-protocol/specification
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    io::{BufRead, BufReader, Read, Write},
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::{
+    callstack::{CallStack, FrameKind},
+    coverage,
+    cpu::Cpu,
+    disasm,
+    export::Export,
+    loader::{AddressSpace, Format},
+    machines::easy6502,
+    mem::Memory,
+    screenshot::Video,
+    symbols::SymbolTable,
+};
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const STATUS_REGISTER_REF: i64 = 2;
+// number of instructions executed between polling for new requests while running
+const RUN_SLICE: usize = 10000;
+
+enum RunMode {
+    Continue,
+    StepIn,
+    Next { return_addr: u16, sp: u16 },
+    StepOut { sp: u16 },
+}
+
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: i64,
+    requests: Receiver<Value>,
+    cpu: Option<Cpu>,
+    stop_on_entry: bool,
+    running: Option<RunMode>,
+    symbols: SymbolTable,
+    callstack: CallStack,
+    // requested lines per source path, resolved through the symbols
+    source_breakpoints: HashMap<String, Vec<u32>>,
+    function_breakpoints: Vec<u16>,
+    instruction_breakpoints: Vec<u16>,
+    breakpoints: HashSet<u16>,
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> Option<Value> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            if key.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0u8; content_length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+// accepts json numbers as well as "0x1234", "$1234" and decimal strings
+pub fn parse_addr(v: &Value) -> Option<u16> {
+    if let Some(n) = v.as_u64() {
+        return u16::try_from(n).ok();
+    }
+    let s = v.as_str()?.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix('$')) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - i * 6)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// the program in a 64K image and its entry point, if the format has one
+fn load_image(
+    program: &str,
+    format: Option<&str>,
+    load_address: u16,
+) -> std::io::Result<(Vec<u8>, Option<u16>)> {
+    let mut space = AddressSpace::new();
+    space.load(program, format.and_then(Format::parse), Some(load_address))?;
+    Ok((space.to_ram(), space.entry()))
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new<R: Read + Send + 'static>(input: R, out: W) -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            while let Some(msg) = read_message(&mut input) {
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            out,
+            seq: 1,
+            requests: rx,
+            cpu: None,
+            stop_on_entry: false,
+            running: None,
+            symbols: Default::default(),
+            callstack: Default::default(),
+            source_breakpoints: Default::default(),
+            function_breakpoints: Default::default(),
+            instruction_breakpoints: Default::default(),
+            breakpoints: Default::default(),
+        }
+    }
+
+    pub fn serve(&mut self) {
+        loop {
+            let msg = if self.running.is_some() {
+                match self.requests.try_recv() {
+                    Ok(msg) => Some(msg),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match self.requests.recv() {
+                    Ok(msg) => Some(msg),
+                    Err(_) => return,
+                }
+            };
+            match msg {
+                Some(msg) => {
+                    if !self.handle(&msg) {
+                        return;
+                    }
+                }
+                None => self.run_slice(),
+            }
+        }
+    }
+
+    fn send(&mut self, mut msg: Value) {
+        msg["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = msg.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.out.flush().unwrap();
+    }
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+    fn respond_error(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }));
+    }
+    fn stopped(&mut self, reason: &str, text: Option<String>) {
+        self.running = None;
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "text": text,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        );
+    }
+
+    // returns false if the session is finished
+    fn handle(&mut self, request: &Value) -> bool {
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let args = &request["arguments"];
+        info!("dap request: {}", command);
+        let needs_cpu = !matches!(
+            command.as_str(),
+            "initialize" | "launch" | "configurationDone" | "disconnect"
+        ) && !command.starts_with("set");
+        if needs_cpu && self.cpu.is_none() {
+            self.respond_error(request, "no program launched");
+            return true;
+        }
+        match command.as_str() {
+            "initialize" => {
+                self.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsFunctionBreakpoints": true,
+                        "supportsInstructionBreakpoints": true,
+                        "supportsReadMemoryRequest": true,
+                        "supportsDisassembleRequest": true,
+                        "supportsSteppingGranularity": true,
+                        "supportsEvaluateForHovers": true,
+                    }),
+                );
+                self.event("initialized", json!({}));
+            }
+            "launch" => {
+                let Some(program) = args["program"].as_str() else {
+                    self.respond_error(request, "launch: missing 'program'");
+                    return true;
+                };
+                if !std::path::Path::new(program).exists() {
+                    self.respond_error(request, &format!("launch: {} not found", program));
+                    return true;
+                }
+                let load_address = parse_addr(&args["loadAddress"]).unwrap_or(0);
+                let symbol_files = match &args["symbols"] {
+                    Value::String(name) => vec![name.clone()],
+                    Value::Array(names) => names
+                        .iter()
+                        .filter_map(|n| n.as_str().map(|s| s.to_string()))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                for name in symbol_files {
+                    if let Err(e) = self.symbols.load(&name) {
+                        self.respond_error(request, &format!("launch: {}", e));
+                        return true;
+                    }
+                }
+                self.update_breakpoints();
+                let (ram, entry) = match load_image(program, args["format"].as_str(), load_address)
+                {
+                    Ok(image) => image,
+                    Err(e) => {
+                        self.respond_error(request, &format!("launch: {}", e));
+                        return true;
+                    }
+                };
+                let start_pc = parse_addr(&args["startPc"])
+                    .or(entry)
+                    .unwrap_or(load_address);
+                let mut cpu = Cpu::new(Memory::new(ram));
+                cpu.set_pc(start_pc);
+                self.cpu = Some(cpu);
+                self.callstack.clear();
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.respond(request, json!({}));
+            }
+            "configurationDone" => {
+                self.respond(request, json!({}));
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.running = Some(RunMode::Continue);
+                }
+            }
+            "setBreakpoints" => {
+                let path = args["source"]["path"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                let lines = args["breakpoints"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|bp| bp["line"].as_u64())
+                    .map(|line| line as u32)
+                    .collect::<Vec<_>>();
+                let breakpoints = lines
+                    .iter()
+                    .map(|line| match self.symbols.line_address(&path, *line) {
+                        Some(addr) => json!({
+                            "verified": true,
+                            "line": line,
+                            "instructionReference": format!("0x{:04x}", addr),
+                        }),
+                        None if !self.symbols.has_lines() => json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "no symbols loaded",
+                        }),
+                        None => json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "no code at this line",
+                        }),
+                    })
+                    .collect::<Vec<_>>();
+                self.source_breakpoints.insert(path, lines);
+                self.update_breakpoints();
+                self.respond(request, json!({ "breakpoints": breakpoints }));
+            }
+            "setFunctionBreakpoints" => {
+                let mut breakpoints = Vec::new();
+                self.function_breakpoints.clear();
+                for bp in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+                    match self.symbols.eval(bp["name"].as_str().unwrap_or_default()) {
+                        Some(addr) => {
+                            self.function_breakpoints.push(addr);
+                            breakpoints.push(json!({
+                                "verified": true,
+                                "instructionReference": format!("0x{:04x}", addr),
+                            }));
+                        }
+                        None => breakpoints.push(json!({
+                            "verified": false,
+                            "message": "unknown symbol",
+                        })),
+                    }
+                }
+                self.update_breakpoints();
+                self.respond(request, json!({ "breakpoints": breakpoints }));
+            }
+            "setInstructionBreakpoints" => {
+                let mut breakpoints = Vec::new();
+                self.instruction_breakpoints.clear();
+                for bp in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+                    match parse_addr(&bp["instructionReference"]) {
+                        Some(addr) => {
+                            let addr =
+                                addr.wrapping_add_signed(bp["offset"].as_i64().unwrap_or(0) as i16);
+                            self.instruction_breakpoints.push(addr);
+                            breakpoints.push(json!({
+                                "verified": true,
+                                "instructionReference": format!("0x{:04x}", addr),
+                            }));
+                        }
+                        None => breakpoints.push(json!({
+                            "verified": false,
+                            "message": "invalid instruction reference",
+                        })),
+                    }
+                }
+                self.update_breakpoints();
+                self.respond(request, json!({ "breakpoints": breakpoints }));
+            }
+            "setExceptionBreakpoints" => {
+                self.respond(request, json!({}));
+            }
+            "threads" => {
+                self.respond(
+                    request,
+                    json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] }),
+                );
+            }
+            "stackTrace" => {
+                let frames = self.stack_frames();
+                let total = frames.len();
+                self.respond(
+                    request,
+                    json!({ "stackFrames": frames, "totalFrames": total }),
+                );
+            }
+            "scopes" => {
+                self.respond(
+                    request,
+                    json!({
+                        "scopes": [{
+                            "name": "Registers",
+                            "presentationHint": "registers",
+                            "variablesReference": REGISTERS_REF,
+                            "expensive": false,
+                        }]
+                    }),
+                );
+            }
+            "variables" => {
+                let variables = self.variables(args["variablesReference"].as_i64().unwrap_or(0));
+                self.respond(request, json!({ "variables": variables }));
+            }
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }));
+                self.running = Some(RunMode::Continue);
+            }
+            "next" => {
+                self.respond(request, json!({}));
+                let reg = self.cpu.as_ref().unwrap().get_reg();
+                let pc = reg.pc;
+                let sp = reg.sp;
+                self.running = if self.cpu.as_ref().unwrap().get_mem().load(pc) == 0x20 {
+                    Some(RunMode::Next {
+                        return_addr: pc.wrapping_add(3),
+                        sp,
+                    })
+                } else {
+                    Some(RunMode::StepIn)
+                };
+            }
+            "stepIn" => {
+                self.respond(request, json!({}));
+                self.running = Some(RunMode::StepIn);
+            }
+            "stepOut" => {
+                self.respond(request, json!({}));
+                let sp = self.cpu.as_ref().unwrap().get_reg().sp;
+                self.running = Some(RunMode::StepOut { sp });
+            }
+            "pause" => {
+                self.respond(request, json!({}));
+                if self.running.is_some() {
+                    self.stopped("pause", None);
+                }
+            }
+            "readMemory" => {
+                let Some(addr) = parse_addr(&args["memoryReference"]) else {
+                    self.respond_error(request, "invalid memory reference");
+                    return true;
+                };
+                let addr = addr.wrapping_add_signed(args["offset"].as_i64().unwrap_or(0) as i16);
+                let count = args["count"].as_u64().unwrap_or(0).min(0x10000) as usize;
+                let mem = self.cpu.as_ref().unwrap().get_mem();
+                let data = (0..count)
+                    .map(|i| mem.load(addr.wrapping_add(i as u16)))
+                    .collect::<Vec<_>>();
+                self.respond(
+                    request,
+                    json!({
+                        "address": format!("0x{:04x}", addr),
+                        "data": base64(&data),
+                    }),
+                );
+            }
+            "disassemble" => {
+                let Some(addr) = parse_addr(&args["memoryReference"]) else {
+                    self.respond_error(request, "invalid memory reference");
+                    return true;
+                };
+                let addr = addr.wrapping_add_signed(args["offset"].as_i64().unwrap_or(0) as i16);
+                let instructions = self.disassemble(
+                    addr,
+                    args["instructionOffset"].as_i64().unwrap_or(0),
+                    args["instructionCount"].as_u64().unwrap_or(0) as usize,
+                );
+                self.respond(request, json!({ "instructions": instructions }));
+            }
+            "evaluate" => {
+                let expr = args["expression"].as_str().unwrap_or_default();
+                // 'screenshot <file.png|file.ppm>' captures the easy6502 screen
+                if let Some(path) = expr.strip_prefix("screenshot ") {
+                    let mem = self.cpu.as_ref().unwrap().get_mem();
+                    match easy6502::Screen.capture(mem).save(path.trim()) {
+                        Ok(()) => self.respond(
+                            request,
+                            json!({ "result": format!("saved {}", path.trim()), "variablesReference": 0 }),
+                        ),
+                        Err(e) => self.respond_error(request, &format!("screenshot: {}", e)),
+                    }
+                    return true;
+                }
+                // 'export <file> [start-end|start+len]' writes memory, the format by the
+                // extension
+                if let Some(args) = expr.strip_prefix("export ") {
+                    let mut args = args.split_whitespace();
+                    let path = args.next().unwrap_or_default();
+                    let range = args.next().map(coverage::parse_range);
+                    let mut export = Export::new(path);
+                    match range {
+                        Some(Some((start, end))) => export = export.with_range(start, end),
+                        Some(None) => {
+                            self.respond_error(request, "export: bad address range");
+                            return true;
+                        }
+                        None => (),
+                    }
+                    let cpu = self.cpu.as_ref().unwrap();
+                    match export.save(cpu.get_mem(), cpu.get_reg().pc) {
+                        Ok(path) => self.respond(
+                            request,
+                            json!({ "result": format!("saved {}", path), "variablesReference": 0 }),
+                        ),
+                        Err(e) => self.respond_error(request, &format!("export: {}", e)),
+                    }
+                    return true;
+                }
+                let Some(addr) = self.symbols.eval(expr) else {
+                    self.respond_error(request, &format!("cannot evaluate '{}'", expr));
+                    return true;
+                };
+                let value = self.cpu.as_ref().unwrap().get_mem().load(addr);
+                self.respond(
+                    request,
+                    json!({
+                        "result": format!("${:02x} ({}) at {}", value, value, self.symbols.describe(addr, 0xffff)),
+                        "variablesReference": 0,
+                        "memoryReference": format!("0x{:04x}", addr),
+                    }),
+                );
+            }
+            "disconnect" => {
+                self.respond(request, json!({}));
+                return false;
+            }
+            _ => {
+                warn!("unsupported dap request: {}", command);
+                self.respond_error(request, &format!("unsupported request: {}", command));
+            }
+        }
+        true
+    }
+
+    fn update_breakpoints(&mut self) {
+        let symbols = &self.symbols;
+        self.breakpoints = self
+            .source_breakpoints
+            .iter()
+            .flat_map(|(path, lines)| {
+                lines
+                    .iter()
+                    .filter_map(move |line| symbols.line_address(path, *line))
+            })
+            .collect();
+        self.breakpoints.extend(
+            self.function_breakpoints
+                .iter()
+                .chain(self.instruction_breakpoints.iter()),
+        );
+    }
+
+    fn run_slice(&mut self) {
+        let Some(cpu) = self.cpu.as_mut() else {
+            self.running = None;
+            return;
+        };
+        for _ in 0..RUN_SLICE {
+            if let Some((_, pc)) = cpu.poll_interrupts() {
+                self.callstack.interrupt(cpu.get_reg(), pc);
+            }
+            self.callstack.observe(cpu.get_reg(), cpu.get_mem());
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cpu.step()));
+            let info = match result {
+                Ok(Some(info)) => info,
+                Ok(None) => {
+                    self.stopped("exception", Some("BRK".into()));
+                    return;
+                }
+                Err(e) => {
+                    let text = e
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()));
+                    self.stopped("exception", text);
+                    return;
+                }
+            };
+            let reg = cpu.get_reg();
+            let done = match self.running {
+                Some(RunMode::StepIn) => Some("step"),
+                Some(RunMode::Next { return_addr, sp })
+                    if reg.pc == return_addr && reg.sp >= sp =>
+                {
+                    Some("step")
+                }
+                // RTS/RTI leaving the frame we started in
+                Some(RunMode::StepOut { sp })
+                    if (info.opcode == 0x60 || info.opcode == 0x40) && reg.sp > sp =>
+                {
+                    Some("step")
+                }
+                _ => None,
+            };
+            if let Some(reason) = done {
+                self.stopped(reason, None);
+                return;
+            }
+            if self.breakpoints.contains(&reg.pc) {
+                self.stopped("breakpoint", None);
+                return;
+            }
+        }
+    }
+
+    fn stack_frames(&self) -> Vec<Value> {
+        let cpu = self.cpu.as_ref().unwrap();
+        let walk = self.callstack.walk(cpu.get_reg().pc);
+        walk.iter()
+            .enumerate()
+            .map(|(id, (pc, frame))| {
+                let inst = disasm::disassemble(cpu.get_mem(), *pc);
+                let mut name = format!(
+                    "{}: {}",
+                    self.symbols.describe(*pc, 0xffff),
+                    inst.text_with(Some(&self.symbols))
+                );
+                if matches!(frame, Some(f) if f.kind == FrameKind::Interrupt) {
+                    name.push_str(" <interrupt>");
+                }
+                let mut frame = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:04x}", pc),
+                });
+                self.add_source(&mut frame, *pc);
+                frame
+            })
+            .collect()
+    }
+
+    fn variables(&self, reference: i64) -> Vec<Value> {
+        let reg = self.cpu.as_ref().unwrap().get_reg();
+        fn var(name: &str, value: String, reference: i64) -> Value {
+            json!({ "name": name, "value": value, "variablesReference": reference })
+        }
+        match reference {
+            REGISTERS_REF => {
+                let mut pc = var("PC", format!("${:04x}", reg.pc), 0);
+                pc["memoryReference"] = json!(format!("0x{:04x}", reg.pc));
+                vec![
+                    var("A", format!("${:02x}", reg.a), 0),
+                    var("X", format!("${:02x}", reg.x), 0),
+                    var("Y", format!("${:02x}", reg.y), 0),
+                    var("SP", format!("${:02x}", reg.sp), 0),
+                    pc,
+                    var("SR", format!("{}", reg.sr), STATUS_REGISTER_REF),
+                ]
+            }
+            STATUS_REGISTER_REF => {
+                let sr = &reg.sr;
+                [
+                    ("N", sr.n),
+                    ("V", sr.v),
+                    ("B", sr.b),
+                    ("I", sr.i),
+                    ("D", sr.d),
+                    ("Z", sr.z),
+                    ("C", sr.c),
+                ]
+                .iter()
+                .map(|(name, flag)| var(name, (*flag as u8).to_string(), 0))
+                .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn disassemble(&self, addr: u16, instruction_offset: i64, count: usize) -> Vec<Value> {
+        let mem = self.cpu.as_ref().unwrap().get_mem();
+        // more than 64K instructions only repeat memory
+        let count = count.min(0x10000);
+        let mut instructions = Vec::new();
+        let mut start = addr;
+        if instruction_offset < 0 {
+            let back = instruction_offset.unsigned_abs().min(0x10000) as usize;
+            instructions = disasm::disassemble_before(mem, addr, back);
+            instructions.truncate(count);
+        } else {
+            for _ in 0..instruction_offset.min(0x10000) {
+                start = disasm::disassemble(mem, start).next_addr();
+            }
+        }
+        let remaining = count - instructions.len();
+        instructions.extend(disasm::disassemble_range(mem, start, remaining));
+        instructions
+            .iter()
+            .map(|inst| {
+                let mut out = json!({
+                    "address": format!("0x{:04x}", inst.addr),
+                    "instructionBytes": inst.bytes_string(),
+                    "instruction": inst.text_with(Some(&self.symbols)),
+                });
+                if let Some(label) = self.symbols.lookup(inst.addr) {
+                    out["symbol"] = json!(label);
+                }
+                self.add_source(&mut out, inst.addr);
+                out
+            })
+            .collect()
+    }
+
+    fn add_source(&self, v: &mut Value, addr: u16) {
+        if let Some((file, line)) = self.symbols.source_line(addr) {
+            let name = std::path::Path::new(file)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            v["source"] = json!({ "name": name, "path": file });
+            v["line"] = json!(line);
+            v["location"] = v["source"].clone();
+        }
+    }
+}
+
+pub fn serve_stdio() {
+    let mut server = DapServer::new(std::io::stdin(), std::io::stdout());
+    server.serve();
+}
@@ -1,233 +1,341 @@
 use std::{
     collections::{HashMap, HashSet},
     hash::{DefaultHasher, Hash, Hasher},
-    io::{Bytes, Read, Stdout, Write},
+    io::{Stdout, Write},
+    rc::Rc,
 };
 
-use rand::Rng;
-use termion::{
-    async_stdin,
-    raw::{IntoRawMode, RawTerminal},
-    AsyncReader,
-};
+use log::{debug, info};
 
 use crate::{
-    mem::{self, Memory},
+    cpu::{Interrupt, StepInfo},
+    disasm,
+    mem::Memory,
     reg::Registers,
+    symbols::SymbolTable,
 };
 
 pub trait Dbg {
+    // before each instruction, true stops the cpu
     fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool;
+    // after an instruction was executed, true stops the cpu
+    fn post_step(&mut self, _info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        false
+    }
+    // data accesses of the instruction at 'pc', reported before post_step. Instruction
+    // fetches are not included, stack accesses are reported here and as push/pop.
+    fn mem_read(&mut self, _addr: u16, _value: u8, _pc: u16) {}
+    fn mem_write(&mut self, _addr: u16, _value: u8, _pc: u16) {}
+    fn stack_push(&mut self, _addr: u16, _value: u8, _pc: u16) {}
+    fn stack_pop(&mut self, _addr: u16, _value: u8, _pc: u16) {}
+    // true if the hook uses the four above. Recording accesses costs time, so run()
+    // only does it when asked.
+    fn wants_accesses(&self) -> bool {
+        false
+    }
+    // PC and SR were pushed and reg.pc is the handler about to be executed
+    fn interrupt(&mut self, _kind: Interrupt, _reg: &Registers) {}
 }
-pub struct CycleDetect {
-    pc_trace: [u8; 0xffff],
-}
-
-impl Default for CycleDetect {
-    fn default() -> Self {
-        Self {
-            pc_trace: [0u8; 0xffff],
-        }
+// allows lending a hook to Cpu::run and inspecting it afterwards
+impl<T: Dbg + ?Sized> Dbg for &mut T {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        (**self).step(reg, mem)
+    }
+    fn post_step(&mut self, info: &StepInfo, reg: &mut Registers, mem: &mut Memory) -> bool {
+        (**self).post_step(info, reg, mem)
+    }
+    fn mem_read(&mut self, addr: u16, value: u8, pc: u16) {
+        (**self).mem_read(addr, value, pc)
+    }
+    fn mem_write(&mut self, addr: u16, value: u8, pc: u16) {
+        (**self).mem_write(addr, value, pc)
+    }
+    fn stack_push(&mut self, addr: u16, value: u8, pc: u16) {
+        (**self).stack_push(addr, value, pc)
+    }
+    fn stack_pop(&mut self, addr: u16, value: u8, pc: u16) {
+        (**self).stack_pop(addr, value, pc)
+    }
+    fn wants_accesses(&self) -> bool {
+        (**self).wants_accesses()
+    }
+    fn interrupt(&mut self, kind: Interrupt, reg: &Registers) {
+        (**self).interrupt(kind, reg)
     }
 }
-
-impl Dbg for CycleDetect {
-    fn step(&mut self, reg: &mut Registers, _mem: &mut Memory) -> bool {
-        self.pc_trace[reg.pc as usize] += 1;
-        if self.pc_trace[reg.pc as usize] > 2 {
-            println!("cycle");
-            true
-        } else {
-            false
-        }
+impl<T: Dbg + ?Sized> Dbg for Box<T> {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        (**self).step(reg, mem)
+    }
+    fn post_step(&mut self, info: &StepInfo, reg: &mut Registers, mem: &mut Memory) -> bool {
+        (**self).post_step(info, reg, mem)
+    }
+    fn mem_read(&mut self, addr: u16, value: u8, pc: u16) {
+        (**self).mem_read(addr, value, pc)
+    }
+    fn mem_write(&mut self, addr: u16, value: u8, pc: u16) {
+        (**self).mem_write(addr, value, pc)
+    }
+    fn stack_push(&mut self, addr: u16, value: u8, pc: u16) {
+        (**self).stack_push(addr, value, pc)
+    }
+    fn stack_pop(&mut self, addr: u16, value: u8, pc: u16) {
+        (**self).stack_pop(addr, value, pc)
+    }
+    fn wants_accesses(&self) -> bool {
+        (**self).wants_accesses()
+    }
+    fn interrupt(&mut self, kind: Interrupt, reg: &Registers) {
+        (**self).interrupt(kind, reg)
     }
 }
-pub struct DumpScreen {
-    stdout: RawTerminal<Stdout>,
-    input: Bytes<AsyncReader>,
-    trigger_pc: u16,
+// several hooks run in the order they were added. Every hook sees every step, even if
+// an earlier one already asked to stop, so devices and recorders stay consistent. The
+// cpu stops if any hook asks for it.
+#[derive(Default)]
+pub struct DbgChain<'a> {
+    hooks: Vec<Box<dyn Dbg + 'a>>,
 }
-impl Drop for DumpScreen {
-    fn drop(&mut self) {
-        println!("drop");
+impl<'a> DbgChain<'a> {
+    pub fn new() -> Self {
+        Self::default()
     }
-}
-impl Default for DumpScreen {
-    fn default() -> Self {
-        Self {
-            stdout: std::io::stdout().into_raw_mode().unwrap(),
-            input: async_stdin().bytes(),
-            trigger_pc: 0x734,
-        }
+    pub fn push(&mut self, hook: impl Dbg + 'a) {
+        self.hooks.push(Box::new(hook));
+    }
+    pub fn with(mut self, hook: impl Dbg + 'a) -> Self {
+        self.push(hook);
+        self
+    }
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
     }
 }
-impl Dbg for DumpScreen {
+impl Dbg for DbgChain<'_> {
     fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
-        mem.store(0xfe, rand::thread_rng().gen());
-        loop {
-            let Some(key) = self.input.next() else { break };
-
-            println!("key: {:?}", key);
-            match key.unwrap() {
-                c if c.is_ascii() => mem.store(0xff, c as u8),
-                _ => (),
-            }
+        let mut stop = false;
+        for hook in &mut self.hooks {
+            stop |= hook.step(reg, mem);
         }
-        if self.trigger_pc != 0 && reg.pc == self.trigger_pc {
-            let mut offs = 0x200u16;
-            write!(
-                self.stdout,
-                "{}{}",
-                termion::clear::All,
-                termion::cursor::Hide
-            )
-            .unwrap();
-            for y in 0..32 {
-                write!(self.stdout, "{}", termion::cursor::Goto(1, y + 1)).unwrap();
-                for _x in 0..32 {
-                    let pixel = mem.load(offs);
-                    offs += 1;
-                    print!("{}", if pixel == 0 { ' ' } else { 'X' });
-                }
-                println!();
-            }
-            println!("PC={:x}", reg.pc);
-            writeln!(self.stdout, "{}", termion::cursor::Show).unwrap();
-            std::thread::sleep(std::time::Duration::from_millis(100));
+        stop
+    }
+    fn post_step(&mut self, info: &StepInfo, reg: &mut Registers, mem: &mut Memory) -> bool {
+        let mut stop = false;
+        for hook in &mut self.hooks {
+            stop |= hook.post_step(info, reg, mem);
+        }
+        stop
+    }
+    fn mem_read(&mut self, addr: u16, value: u8, pc: u16) {
+        for hook in &mut self.hooks {
+            hook.mem_read(addr, value, pc);
+        }
+    }
+    fn mem_write(&mut self, addr: u16, value: u8, pc: u16) {
+        for hook in &mut self.hooks {
+            hook.mem_write(addr, value, pc);
+        }
+    }
+    fn stack_push(&mut self, addr: u16, value: u8, pc: u16) {
+        for hook in &mut self.hooks {
+            hook.stack_push(addr, value, pc);
+        }
+    }
+    fn stack_pop(&mut self, addr: u16, value: u8, pc: u16) {
+        for hook in &mut self.hooks {
+            hook.stack_pop(addr, value, pc);
+        }
+    }
+    fn wants_accesses(&self) -> bool {
+        self.hooks.iter().any(|hook| hook.wants_accesses())
+    }
+    fn interrupt(&mut self, kind: Interrupt, reg: &Registers) {
+        for hook in &mut self.hooks {
+            hook.interrupt(kind, reg);
         }
-        false
     }
 }
-pub struct Apple1Pia {
-    stdout: RawTerminal<Stdout>,
-    input: Bytes<AsyncReader>,
-    trigger_pc: u16,
-    textbuf: [[u8; 80]; 10],
-    outline: usize,
-    outcol: usize,
-    screen_dirty: bool,
-    monitor_lastchunks: HashMap<u16, u64>,
-    keyb_strobe: usize,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapKind {
+    // JMP or taken branch to itself
+    SelfJump,
+    // registers and RAM are the same as 'period' instructions before
+    RepeatedState { period: u64 },
+}
+#[derive(Clone, Copy, Debug)]
+pub struct Trap {
+    pub pc: u16,
+    pub kind: TrapKind,
+}
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            TrapKind::SelfJump => write!(f, "trap at ${:04x} (jump to self)", self.pc),
+            TrapKind::RepeatedState { period } => write!(
+                f,
+                "trap at ${:04x} (machine state repeats every {} instructions)",
+                self.pc, period
+            ),
+        }
+    }
+}
+
+// longest loop (in instructions) that is checked for repeated machine state
+const MAX_PERIOD: u64 = 1 << 16;
+// cycles in a loop before it counts as trapped, unless set with with_idle_threshold
+const IDLE_THRESHOLD: u64 = 100_000;
+
+// Detects the cpu getting stuck: a jump to itself, or the whole machine state
+// (registers and RAM) repeating. Loops are found by comparing against a checkpoint
+// taken at exponentially growing intervals (Brent's algorithm), so RAM is only hashed
+// when the registers match. A loop that read a device since the checkpoint waits for
+// input, not stuck. Stops the cpu once it stayed in the loop for 'idle_threshold'
+// cycles; an interrupt or leaving the loop resets that.
+pub struct CycleDetect {
+    idle_threshold: u64,
+    steps: u64,
+    cycles: u64,
+    // registers, RAM hash, step and device reads at the checkpoint
+    checkpoint: Option<(Registers, u64, u64, u64)>,
+    power: u64,
+    // PCs executed since the checkpoint, i.e. the loop body once a loop was found
+    pcs: HashSet<u16>,
+    idle: Option<(Trap, u64)>,
+    trap: Option<Trap>,
 }
-impl Default for Apple1Pia {
+
+impl Default for CycleDetect {
     fn default() -> Self {
         Self {
-            stdout: std::io::stdout().into_raw_mode().unwrap(),
-            input: async_stdin().bytes(),
-            trigger_pc: 0x734,
-            textbuf: [[0x20u8; 80]; 10],
-            outcol: 0,
-            outline: 0,
-            screen_dirty: true,
-            monitor_lastchunks: Default::default(),
-            keyb_strobe: 0,
+            idle_threshold: IDLE_THRESHOLD,
+            steps: 0,
+            cycles: 0,
+            checkpoint: None,
+            power: 1,
+            pcs: HashSet::new(),
+            idle: None,
+            trap: None,
         }
     }
 }
-impl Dbg for Apple1Pia {
+
+fn hash_ram(mem: &Memory) -> u64 {
+    let mut hasher = DefaultHasher::default();
+    mem.get().hash(&mut hasher);
+    hasher.finish()
+}
+
+impl CycleDetect {
+    // cycles the cpu has to spend in a loop before it counts as trapped
+    pub fn with_idle_threshold(mut self, cycles: u64) -> Self {
+        self.idle_threshold = cycles;
+        self
+    }
+    pub fn trap(&self) -> Option<Trap> {
+        self.trap
+    }
+    fn found(&mut self, trap: Trap) {
+        if self.idle.is_none() {
+            debug!("loop: {}", trap);
+            self.idle = Some((trap, self.cycles));
+        }
+    }
+    fn reset(&mut self) {
+        self.checkpoint = None;
+        self.power = 1;
+        self.pcs.clear();
+        self.idle = None;
+    }
+}
+
+impl Dbg for CycleDetect {
     fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
-        if self.keyb_strobe != 0 {
-            self.keyb_strobe -= 1;
-            if self.keyb_strobe == 0 {
-                mem.store(0xd011, mem.load(0xd011) & 0b1111111);
-            }
+        if self.idle.is_some() && !self.pcs.contains(&reg.pc) {
+            // left the loop after all, e.g. because of input
+            self.reset();
         }
-        loop {
-            let Some(key) = self.input.next() else { break };
-            match key.unwrap() {
-                0x1b => return true,
-                c if c.is_ascii() => {
-                    // let c = if c == 0xd { 0xa } else { c };
-                    let v = mem.load(0xd11);
-                    // println!("key: {:x} {:x}", c, v);
-                    mem.store(0xd011, v | 0b10000000);
-                    mem.store(0xd010, (c as u8) | 0b10000000);
-                    self.keyb_strobe = 2;
+        if self.idle.is_none() {
+            match self.checkpoint {
+                Some((regs, hash, steps, reads))
+                    if regs == *reg && reads == mem.device_reads() && hash == hash_ram(mem) =>
+                {
+                    self.found(Trap {
+                        pc: reg.pc,
+                        kind: TrapKind::RepeatedState {
+                            period: self.steps - steps,
+                        },
+                    });
+                }
+                Some((_, _, steps, _)) if self.steps - steps < self.power => (),
+                _ => {
+                    self.power = (self.power * 2).min(MAX_PERIOD);
+                    self.checkpoint = Some((*reg, hash_ram(mem), self.steps, mem.device_reads()));
+                    self.pcs.clear();
                 }
-
-                _ => (),
             }
+            self.pcs.insert(reg.pc);
         }
-        // check display register
-        let v = mem.load(0xd012);
-        // if (v & 0b10000000) != 0 {
-        if v != 0x0 {
-            let c = v & 0b1111111;
-            // print!("{}", c as char);
-            // self.stdout.flush().unwrap();
-            self.putc(c);
-            mem.store(0xd012, 0x0);
-        }
-        if self.trigger_pc != 0 && reg.pc == self.trigger_pc {
-            let mut offs = 0x200u16;
-            write!(
-                self.stdout,
-                "{}{}",
-                termion::clear::All,
-                termion::cursor::Hide
-            )
-            .unwrap();
-            for y in 0..32 {
-                write!(self.stdout, "{}", termion::cursor::Goto(1, y + 1)).unwrap();
-                for _x in 0..32 {
-                    let pixel = mem.load(offs);
-                    offs += 1;
-                    print!("{}", if pixel == 0 { ' ' } else { 'X' });
-                }
-                println!();
+        self.steps += 1;
+        match self.idle {
+            Some((trap, since)) if self.cycles - since >= self.idle_threshold => {
+                info!("{}", trap);
+                self.trap = Some(trap);
+                true
             }
-            println!("PC={:x}", reg.pc);
-            writeln!(self.stdout, "{}", termion::cursor::Show).unwrap();
-            std::thread::sleep(std::time::Duration::from_millis(100));
+            _ => false,
+        }
+    }
+    fn post_step(&mut self, info: &StepInfo, reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        if reg.pc == info.pc {
+            self.pcs.insert(info.pc);
+            self.found(Trap {
+                pc: info.pc,
+                kind: TrapKind::SelfJump,
+            });
         }
-        self.draw_textbuf(1, 1);
-        self.draw_monitor(10, mem);
-        self.stdout.flush().unwrap();
         false
     }
+    fn interrupt(&mut self, _kind: Interrupt, _reg: &Registers) {
+        self.reset();
+    }
 }
-impl Apple1Pia {
-    pub fn putc(&mut self, c: u8) {
-        if c == 0x0d {
-            // self.outline += 1;
-            self.outcol = 0;
-        }
-        if c == 0x0a {
-            self.outline += 1;
-        }
-        if self.outcol >= 80 {
-            self.outcol = 0;
-            self.outline += 1;
-        }
-        if self.outline >= 10 {
-            self.outline = 9;
-            self.textbuf.copy_within(1..9, 0);
-            self.textbuf[9] = [0x20u8; 80];
-        }
-        self.textbuf[self.outline][self.outcol] = c;
-        self.outcol += 1;
-        self.screen_dirty = true;
+// log every instruction with registers, labels taken from the symbol table
+pub struct Trace {
+    symbols: Rc<SymbolTable>,
+}
+impl Trace {
+    pub fn new(symbols: Rc<SymbolTable>) -> Self {
+        Self { symbols }
     }
-    pub fn draw_textbuf(&mut self, screenline: u16, screencol: u16) {
-        if !self.screen_dirty {
-            return;
-        }
-        for (y, line) in self.textbuf.iter().enumerate() {
-            write!(
-                self.stdout,
-                "{}{}",
-                termion::cursor::Goto(screencol, (y as u16) + screenline),
-                termion::clear::CurrentLine
-            )
-            .unwrap();
-            for (x, c) in line.iter().enumerate() {
-                write!(self.stdout, "{}", *c as char).unwrap();
-            }
+}
+impl Dbg for Trace {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        let inst = disasm::disassemble(mem, reg.pc);
+        info!("{}  {}", inst.listing(Some(&self.symbols)), reg);
+        false
+    }
+}
+// live hex view of all non-zero 16 byte chunks of memory, starting at terminal line
+// 'first_line'. Only lines that changed are redrawn.
+pub struct MemoryMonitor {
+    stdout: Stdout,
+    first_line: u16,
+    monitor_lastchunks: HashMap<u16, u64>,
+}
+impl MemoryMonitor {
+    pub fn new(first_line: u16) -> Self {
+        Self {
+            stdout: std::io::stdout(),
+            first_line,
+            monitor_lastchunks: Default::default(),
         }
-        self.screen_dirty = false;
     }
-    pub fn draw_monitor(&mut self, mut screenline: u16, mem: &mem::Memory) {
-        //
+    pub fn draw_monitor(&mut self, mem: &Memory) {
+        let mut screenline = self.first_line;
         let Ok((_width, height)) = termion::terminal_size() else {
             return;
         };
@@ -267,3 +375,10 @@ impl Apple1Pia {
         }
     }
 }
+impl Dbg for MemoryMonitor {
+    fn step(&mut self, _reg: &mut Registers, mem: &mut Memory) -> bool {
+        self.draw_monitor(mem);
+        self.stdout.flush().unwrap();
+        false
+    }
+}
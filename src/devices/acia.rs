@@ -0,0 +1,379 @@
+// MOS 6551 Asynchronous Communication Interface Adapter: data, status, command and
+// control registers at A0/A1.
+//
+// Characters move at the programmed baud rate, counted in cpu cycles. The other end of
+// the line is a SerialHost: stdin/stdout, a pseudo terminal or a TCP connection.
+// Received bytes wait on the host side until the receive register is free, which works
+// like hardware flow control, so pasting into the emulated machine loses nothing.
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    fs::File,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::io::{AsRawFd, FromRawFd},
+    sync::mpsc::{self, Receiver},
+};
+
+use log::info;
+
+use crate::mem::Device;
+
+// status register
+const PARITY_ERROR: u8 = 0x01;
+const FRAMING_ERROR: u8 = 0x02;
+const OVERRUN: u8 = 0x04;
+const RDRF: u8 = 0x08;
+const TDRE: u8 = 0x10;
+const IRQ: u8 = 0x80;
+
+// baud rates selected by control bits 0-3, 0 is the external 16x clock which is
+// taken as 115200
+const BAUD: [u64; 16] = [
+    115200, 50, 75, 110, 135, 150, 300, 600, 1200, 1800, 2400, 3600, 4800, 7200, 9600, 19200,
+];
+
+// the other end of the serial line
+pub trait SerialHost {
+    fn send(&mut self, value: u8);
+    // next received byte, if any. Must not block.
+    fn receive(&mut self) -> Option<u8>;
+}
+
+// bytes from a reader, read on a thread so receive() doesn't block
+pub(crate) fn spawn_reader<R: Read + Send + 'static>(mut input: R) -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        loop {
+            match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if buf[..n].iter().any(|b| tx.send(*b).is_err()) {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+// the emulator's own stdin and stdout. Newlines from the host are sent as CR, which is
+// what monitors and BASIC expect, output is passed through unchanged.
+pub struct Stdio {
+    input: Receiver<u8>,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self {
+            input: spawn_reader(io::stdin()),
+        }
+    }
+}
+
+impl SerialHost for Stdio {
+    fn send(&mut self, value: u8) {
+        let mut out = io::stdout();
+        out.write_all(&[value]).unwrap();
+        out.flush().unwrap();
+    }
+    fn receive(&mut self) -> Option<u8> {
+        match self.input.try_recv().ok()? {
+            b'\n' => Some(b'\r'),
+            b => Some(b),
+        }
+    }
+}
+
+// A pseudo terminal: connect a terminal program to the printed device, e.g.
+// 'screen /dev/pts/5' or 'picocom /dev/pts/5'.
+pub struct Pty {
+    master: File,
+    // kept open so the master doesn't see a hangup before a terminal program connects
+    _slave: File,
+    input: Receiver<u8>,
+    path: String,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        // SAFETY: plain libc calls on a descriptor we own, the name is copied out of
+        // the buffer ptsname_r filled in
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut name = [0 as libc::c_char; 128];
+            if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().to_string();
+            let slave = File::options().read(true).write(true).open(&path)?;
+            // no echo or line editing, the terminal program sets its own mode anyway
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+            }
+            info!("serial port on {}", path);
+            Ok(Self {
+                input: spawn_reader(master.try_clone()?),
+                master,
+                _slave: slave,
+                path,
+            })
+        }
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl SerialHost for Pty {
+    fn send(&mut self, value: u8) {
+        // nobody listening is not an error
+        let _ = self.master.write_all(&[value]);
+    }
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+// Listens on a local TCP port, one client at a time: 'nc localhost 6551' or
+// 'telnet localhost 6551'. Output without a client is dropped.
+pub struct Tcp {
+    listener: TcpListener,
+    client: Option<(TcpStream, Receiver<u8>)>,
+}
+
+impl Tcp {
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        info!("serial port on tcp 127.0.0.1:{}", port);
+        Ok(Self {
+            listener,
+            client: None,
+        })
+    }
+    fn accept(&mut self) {
+        if self.client.is_some() {
+            return;
+        }
+        if let Ok((stream, addr)) = self.listener.accept() {
+            info!("serial client {} connected", addr);
+            let Ok(reader) = stream.try_clone() else {
+                return;
+            };
+            // the listener is non-blocking, the reader thread wants to block
+            let _ = reader.set_nonblocking(false);
+            self.client = Some((stream, spawn_reader(reader)));
+        }
+    }
+}
+
+impl SerialHost for Tcp {
+    fn send(&mut self, value: u8) {
+        self.accept();
+        if let Some((stream, _)) = &mut self.client {
+            if stream.write_all(&[value]).is_err() {
+                info!("serial client disconnected");
+                self.client = None;
+            }
+        }
+    }
+    fn receive(&mut self) -> Option<u8> {
+        self.accept();
+        let (_, input) = self.client.as_ref()?;
+        match input.try_recv() {
+            Ok(b) => Some(b),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                info!("serial client disconnected");
+                self.client = None;
+                None
+            }
+        }
+    }
+}
+
+// 'stdio', 'pty' or 'tcp:<port>'
+pub fn open_host(spec: &str) -> io::Result<Box<dyn SerialHost>> {
+    match spec {
+        "stdio" => Ok(Box::new(Stdio::default())),
+        "pty" => Ok(Box::new(Pty::open()?)),
+        _ => match spec.strip_prefix("tcp:").and_then(|p| p.parse().ok()) {
+            Some(port) => Ok(Box::new(Tcp::listen(port)?)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad serial host '{}', use stdio, pty or tcp:<port>", spec),
+            )),
+        },
+    }
+}
+
+pub struct Acia {
+    host: Option<Box<dyn SerialHost>>,
+    clock_hz: u64,
+    rx: u8,
+    tx: u8,
+    status: u8,
+    command: u8,
+    control: u8,
+    // cpu cycles
+    clock: u64,
+    rx_ready_at: u64,
+    // byte in the transmit shift register and when it is out
+    shifting: Option<(u8, u64)>,
+    // bytes for the host when there is none
+    output: VecDeque<u8>,
+}
+
+impl Acia {
+    pub fn new(clock_hz: u64) -> Self {
+        Self {
+            host: None,
+            clock_hz,
+            rx: 0,
+            tx: 0,
+            status: TDRE,
+            command: 0,
+            control: 0,
+            clock: 0,
+            rx_ready_at: 0,
+            shifting: None,
+            output: VecDeque::new(),
+        }
+    }
+    pub fn with_host(mut self, host: Box<dyn SerialHost>) -> Self {
+        self.host = Some(host);
+        self
+    }
+    // transmitted bytes, only collected without a host
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.drain(..).collect()
+    }
+
+    // cycles per character: start bit, data bits, parity and stop bits
+    fn char_cycles(&self) -> u64 {
+        let data = 8 - ((self.control >> 5) & 3) as u64;
+        let parity = (self.command >> 5) & 1;
+        let stop = if self.control & 0x80 != 0 { 2 } else { 1 };
+        let bits = 1 + data + parity as u64 + stop;
+        self.clock_hz * bits / BAUD[(self.control & 0x0f) as usize]
+    }
+    // DTR: the receiver and transmitter are off while it is low
+    fn enabled(&self) -> bool {
+        self.command & 0x01 != 0
+    }
+    fn rx_irq_enabled(&self) -> bool {
+        self.command & 0x02 == 0
+    }
+    fn tx_irq_enabled(&self) -> bool {
+        self.command & 0x0c == 0x04
+    }
+    fn update_irq(&mut self) {
+        let rx = self.status & RDRF != 0 && self.rx_irq_enabled();
+        let tx = self.status & TDRE != 0 && self.tx_irq_enabled();
+        if self.enabled() && (rx || tx) {
+            self.status |= IRQ;
+        }
+    }
+    fn transmit(&mut self, value: u8) {
+        match &mut self.host {
+            Some(host) => host.send(value),
+            None => self.output.push_back(value),
+        }
+    }
+    // the transmit data register moves to the shift register as soon as it is free
+    fn start_transmit(&mut self) {
+        if self.shifting.is_none() && self.status & TDRE == 0 {
+            self.shifting = Some((self.tx, self.clock + self.char_cycles()));
+            self.status |= TDRE;
+            self.update_irq();
+        }
+    }
+    fn poll_receive(&mut self) {
+        if self.status & RDRF != 0 || self.clock < self.rx_ready_at || !self.enabled() {
+            return;
+        }
+        let Some(value) = self.host.as_mut().and_then(|h| h.receive()) else {
+            // don't ask the host on every instruction
+            self.rx_ready_at = self.clock + self.char_cycles();
+            return;
+        };
+        // echo mode sends received characters straight back
+        if self.command & 0x10 != 0 {
+            self.transmit(value);
+        }
+        self.rx = value;
+        self.status |= RDRF;
+        self.rx_ready_at = self.clock + self.char_cycles();
+        self.update_irq();
+    }
+}
+
+impl Device for Acia {
+    fn read(&mut self, addr: u16) -> u8 {
+        let v = self.peek(addr);
+        match addr & 3 {
+            0 => {
+                self.status &= !(RDRF | OVERRUN | FRAMING_ERROR | PARITY_ERROR);
+            }
+            // reading the status acknowledges the interrupt
+            1 => self.status &= !IRQ,
+            _ => (),
+        }
+        v
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        match addr & 3 {
+            0 => self.rx,
+            1 => self.status,
+            2 => self.command,
+            _ => self.control,
+        }
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 3 {
+            0 => {
+                self.tx = value;
+                self.status &= !TDRE;
+                self.start_transmit();
+            }
+            // programmed reset
+            1 => {
+                self.command &= 0xe0;
+                self.status &= !OVERRUN;
+            }
+            2 => {
+                self.command = value;
+                self.update_irq();
+            }
+            _ => self.control = value,
+        }
+    }
+    fn tick(&mut self, cycles: u32) {
+        self.clock += cycles as u64;
+        if let Some((value, done)) = self.shifting {
+            if self.clock >= done {
+                self.shifting = None;
+                self.transmit(value);
+                self.start_transmit();
+            }
+        }
+        self.poll_receive();
+    }
+    fn irq(&self) -> bool {
+        self.status & IRQ != 0
+    }
+}
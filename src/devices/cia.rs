@@ -0,0 +1,326 @@
+// MOS 6526 Complex Interface Adapter: two 8 bit ports, two interval timers, a time of
+// day clock with alarm and a serial register. Registers are selected by A0-A3.
+//
+// Timer A counts cycles, timer B cycles or timer A underflows. The CNT pin isn't
+// emulated: it stays high and has no edges to count. The TOD clock is assumed to get the mains
+// frequency its control bit says, so it simply advances every tenth of a second.
+use crate::mem::Device;
+
+const PRA: u16 = 0x0;
+const PRB: u16 = 0x1;
+const DDRA: u16 = 0x2;
+const DDRB: u16 = 0x3;
+const TALO: u16 = 0x4;
+const TAHI: u16 = 0x5;
+const TBLO: u16 = 0x6;
+const TBHI: u16 = 0x7;
+const TOD_10THS: u16 = 0x8;
+const TOD_SEC: u16 = 0x9;
+const TOD_MIN: u16 = 0xa;
+const TOD_HR: u16 = 0xb;
+const SDR: u16 = 0xc;
+const ICR: u16 = 0xd;
+const CRA: u16 = 0xe;
+// $F is CRB
+
+// interrupt flags
+pub const TIMER_A: u8 = 0x01;
+pub const TIMER_B: u8 = 0x02;
+pub const ALARM: u8 = 0x04;
+pub const SERIAL: u8 = 0x08;
+pub const FLAG: u8 = 0x10;
+
+#[derive(Default)]
+struct Timer {
+    counter: u16,
+    latch: u16,
+    control: u8,
+}
+
+impl Timer {
+    fn running(&self) -> bool {
+        self.control & 0x01 != 0
+    }
+    fn one_shot(&self) -> bool {
+        self.control & 0x08 != 0
+    }
+    fn write_control(&mut self, value: u8) {
+        // bit 4 is a strobe: load the latch now
+        if value & 0x10 != 0 {
+            self.counter = self.latch;
+        }
+        self.control = value & !0x10;
+    }
+    fn write_high(&mut self, value: u8) {
+        self.latch = (self.latch & 0xff) | (value as u16) << 8;
+        if !self.running() {
+            self.counter = self.latch;
+        }
+    }
+    // one count, true on underflow
+    fn count(&mut self) -> bool {
+        if self.counter > 0 {
+            self.counter -= 1;
+            return false;
+        }
+        self.counter = self.latch;
+        if self.one_shot() {
+            self.control &= !0x01;
+        }
+        true
+    }
+}
+
+// hours, minutes, seconds and tenths as in the registers: BCD, hours 1-12 with bit 7
+// for PM
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Time([u8; 4]);
+
+fn bcd_increment(value: u8) -> u8 {
+    if value & 0x0f == 9 {
+        (value & 0xf0) + 0x10
+    } else {
+        value + 1
+    }
+}
+
+impl Time {
+    fn advance(&mut self) {
+        let [hr, min, sec, tenths] = &mut self.0;
+        *tenths = (*tenths + 1) % 10;
+        if *tenths != 0 {
+            return;
+        }
+        *sec = bcd_increment(*sec);
+        if *sec != 0x60 {
+            return;
+        }
+        *sec = 0;
+        *min = bcd_increment(*min);
+        if *min != 0x60 {
+            return;
+        }
+        *min = 0;
+        let pm = *hr & 0x80;
+        *hr = match *hr & 0x1f {
+            0x11 => 0x12 | (pm ^ 0x80),
+            0x12 => 0x01 | pm,
+            h => bcd_increment(h) | pm,
+        };
+    }
+}
+
+pub struct Cia {
+    pra: u8,
+    prb: u8,
+    ddra: u8,
+    ddrb: u8,
+    input_a: u8,
+    input_b: u8,
+    timer_a: Timer,
+    timer_b: Timer,
+    tod: Time,
+    alarm: Time,
+    // reading the hours freezes what the registers show until the tenths are read
+    tod_latch: Option<Time>,
+    // writing the hours stops the clock until the tenths are written
+    tod_stopped: bool,
+    cycles_per_tenth: u64,
+    tod_cycles: u64,
+    sdr: u8,
+    icr: u8,
+    mask: u8,
+    // the interrupt output drives NMI instead of IRQ
+    nmi_output: bool,
+    nmi_level: bool,
+}
+
+impl Cia {
+    pub fn new(clock_hz: u64) -> Self {
+        Self {
+            pra: 0,
+            prb: 0,
+            ddra: 0,
+            ddrb: 0,
+            input_a: 0xff,
+            input_b: 0xff,
+            timer_a: Timer {
+                counter: 0xffff,
+                latch: 0xffff,
+                control: 0,
+            },
+            timer_b: Timer {
+                counter: 0xffff,
+                latch: 0xffff,
+                control: 0,
+            },
+            tod: Time([0x01, 0, 0, 0]),
+            alarm: Time([0, 0, 0, 0]),
+            tod_latch: None,
+            tod_stopped: false,
+            cycles_per_tenth: clock_hz / 10,
+            tod_cycles: 0,
+            sdr: 0,
+            icr: 0,
+            mask: 0,
+            nmi_output: false,
+            nmi_level: false,
+        }
+    }
+    // the interrupt output is wired to NMI, like CIA 2 in the C64
+    pub fn with_nmi_output(mut self) -> Self {
+        self.nmi_output = true;
+        self
+    }
+    pub fn set_input_a(&mut self, value: u8) {
+        self.input_a = value;
+    }
+    pub fn set_input_b(&mut self, value: u8) {
+        self.input_b = value;
+    }
+    // levels of the port pins, inputs read as 1 (pull-up)
+    pub fn port_a(&self) -> u8 {
+        self.pra | !self.ddra
+    }
+    pub fn port_b(&self) -> u8 {
+        self.prb | !self.ddrb
+    }
+    // the FLAG pin saw a falling edge
+    pub fn flag(&mut self) {
+        self.icr |= FLAG;
+    }
+
+    fn interrupt(&self) -> bool {
+        self.icr & self.mask != 0
+    }
+    fn shown_time(&self) -> Time {
+        self.tod_latch.unwrap_or(self.tod)
+    }
+    fn write_time(&mut self, index: usize, value: u8) {
+        let alarm = self.timer_b.control & 0x80 != 0;
+        let time = if alarm {
+            &mut self.alarm
+        } else {
+            &mut self.tod
+        };
+        time.0[index] = value;
+        if !alarm {
+            match index {
+                0 => self.tod_stopped = true,
+                3 => self.tod_stopped = false,
+                _ => (),
+            }
+        }
+        if self.tod == self.alarm {
+            self.icr |= ALARM;
+        }
+    }
+}
+
+impl Device for Cia {
+    fn read(&mut self, addr: u16) -> u8 {
+        let v = self.peek(addr);
+        match addr & 0xf {
+            TOD_HR => self.tod_latch = Some(self.tod),
+            TOD_10THS => self.tod_latch = None,
+            ICR => self.icr = 0,
+            _ => (),
+        }
+        v
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        match addr & 0xf {
+            PRA => (self.pra & self.ddra) | (self.input_a & !self.ddra),
+            PRB => (self.prb & self.ddrb) | (self.input_b & !self.ddrb),
+            DDRA => self.ddra,
+            DDRB => self.ddrb,
+            TALO => self.timer_a.counter as u8,
+            TAHI => (self.timer_a.counter >> 8) as u8,
+            TBLO => self.timer_b.counter as u8,
+            TBHI => (self.timer_b.counter >> 8) as u8,
+            TOD_10THS => self.shown_time().0[3],
+            TOD_SEC => self.shown_time().0[2],
+            TOD_MIN => self.shown_time().0[1],
+            TOD_HR => self.shown_time().0[0],
+            SDR => self.sdr,
+            ICR => self.icr | if self.interrupt() { 0x80 } else { 0 },
+            CRA => self.timer_a.control,
+            _ => self.timer_b.control,
+        }
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0xf {
+            PRA => self.pra = value,
+            PRB => self.prb = value,
+            DDRA => self.ddra = value,
+            DDRB => self.ddrb = value,
+            TALO => self.timer_a.latch = (self.timer_a.latch & 0xff00) | value as u16,
+            TAHI => self.timer_a.write_high(value),
+            TBLO => self.timer_b.latch = (self.timer_b.latch & 0xff00) | value as u16,
+            TBHI => self.timer_b.write_high(value),
+            TOD_10THS => self.write_time(3, value & 0x0f),
+            TOD_SEC => self.write_time(2, value & 0x7f),
+            TOD_MIN => self.write_time(1, value & 0x7f),
+            TOD_HR => self.write_time(0, value & 0x9f),
+            SDR => {
+                self.sdr = value;
+                // output mode: the byte is shifted out, taken as done at once
+                if self.timer_a.control & 0x40 != 0 {
+                    self.icr |= SERIAL;
+                }
+            }
+            ICR => {
+                // bit 7 says whether the other bits set or clear mask bits
+                if value & 0x80 != 0 {
+                    self.mask |= value & 0x1f;
+                } else {
+                    self.mask &= !value;
+                }
+            }
+            CRA => self.timer_a.write_control(value),
+            _ => self.timer_b.write_control(value),
+        }
+    }
+    fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            let mut a_underflow = false;
+            // timer A counts cycles unless CRA bit 5 selects CNT
+            if self.timer_a.running() && self.timer_a.control & 0x20 == 0 {
+                a_underflow = self.timer_a.count();
+                if a_underflow {
+                    self.icr |= TIMER_A;
+                }
+            }
+            let b_counts = match (self.timer_b.control >> 5) & 3 {
+                0 => true,
+                // the second one while CNT is high, which it is without a driver
+                2 | 3 => a_underflow,
+                // CNT edges
+                _ => false,
+            };
+            if self.timer_b.running() && b_counts && self.timer_b.count() {
+                self.icr |= TIMER_B;
+            }
+        }
+        if self.tod_stopped {
+            return;
+        }
+        self.tod_cycles += cycles as u64;
+        while self.tod_cycles >= self.cycles_per_tenth {
+            self.tod_cycles -= self.cycles_per_tenth;
+            self.tod.advance();
+            if self.tod == self.alarm {
+                self.icr |= ALARM;
+            }
+        }
+    }
+    fn irq(&self) -> bool {
+        !self.nmi_output && self.interrupt()
+    }
+    fn nmi(&mut self) -> bool {
+        let level = self.nmi_output && self.interrupt();
+        let edge = level && !self.nmi_level;
+        self.nmi_level = level;
+        edge
+    }
+}
@@ -0,0 +1,301 @@
+// Hitachi HD44780 character LCD controller with the A00 (Japanese) character ROM, as
+// on the common 16x2 modules. Not memory mapped: it sits on the ports of another chip,
+// which passes on the levels of RS, R/W, E and the data lines with bus().
+//
+// Transfers happen on the falling edge of E. In 4 bit mode (function set with DL=0)
+// every transfer is two nibbles on D4-D7, high nibble first. Instructions keep the busy
+// flag set for their execution time.
+pub const COLUMNS: usize = 16;
+pub const ROWS: usize = 2;
+
+// execution times in microseconds
+const CLEAR_US: u64 = 1520;
+const INSTRUCTION_US: u64 = 37;
+
+pub struct Hd44780 {
+    ddram: [u8; 0x80],
+    cgram: [u8; 0x40],
+    ac: u8,
+    // the address counter points into CGRAM (set by 'set CGRAM address')
+    cgram_selected: bool,
+    increment: bool,
+    shift_on_write: bool,
+    display_on: bool,
+    cursor_on: bool,
+    blink_on: bool,
+    eight_bit: bool,
+    two_lines: bool,
+    // display shift in characters
+    shift: u8,
+    // 4 bit mode: high nibble of a write, or the half of a read that comes next
+    high_nibble: Option<u8>,
+    read_low: bool,
+    e: bool,
+    clock: u64,
+    busy_until: u64,
+    cycles_per_us: u64,
+    dirty: bool,
+}
+
+impl Hd44780 {
+    pub fn new(clock_hz: u64) -> Self {
+        Self {
+            ddram: [b' '; 0x80],
+            cgram: [0; 0x40],
+            ac: 0,
+            cgram_selected: false,
+            increment: true,
+            shift_on_write: false,
+            display_on: false,
+            cursor_on: false,
+            blink_on: false,
+            eight_bit: true,
+            two_lines: false,
+            shift: 0,
+            high_nibble: None,
+            read_low: false,
+            e: false,
+            clock: 0,
+            busy_until: 0,
+            cycles_per_us: (clock_hz / 1_000_000).max(1),
+            dirty: true,
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.clock += cycles as u64;
+    }
+    fn busy(&self) -> bool {
+        self.clock < self.busy_until
+    }
+    fn execute_for(&mut self, us: u64) {
+        self.busy_until = self.clock + us * self.cycles_per_us;
+    }
+
+    // levels on the control and data lines. In 4 bit mode only D4-D7 are used.
+    pub fn bus(&mut self, rs: bool, rw: bool, e: bool, data: u8) {
+        let falling = self.e && !e;
+        self.e = e;
+        if !falling {
+            return;
+        }
+        if rw {
+            // a read ends: the address counter moves after data reads
+            if self.eight_bit || self.read_low {
+                if rs {
+                    self.step_ac();
+                }
+                self.read_low = false;
+            } else {
+                self.read_low = true;
+            }
+            return;
+        }
+        let value = if self.eight_bit {
+            data
+        } else {
+            match self.high_nibble.take() {
+                None => {
+                    self.high_nibble = Some(data & 0xf0);
+                    return;
+                }
+                Some(high) => high | (data >> 4),
+            }
+        };
+        if rs {
+            self.write_data(value);
+        } else {
+            self.instruction(value);
+        }
+    }
+    // what the controller drives on D0-D7 while E is high and R/W selects read
+    pub fn read_bus(&self, rs: bool) -> u8 {
+        let value = if rs {
+            if self.cgram_selected {
+                self.cgram[(self.ac & 0x3f) as usize]
+            } else {
+                self.ddram[self.ac as usize]
+            }
+        } else {
+            self.ac | if self.busy() { 0x80 } else { 0 }
+        };
+        if self.eight_bit || !self.read_low {
+            value
+        } else {
+            value << 4
+        }
+    }
+
+    fn step_ac(&mut self) {
+        if self.cgram_selected {
+            self.ac = if self.increment {
+                self.ac.wrapping_add(1)
+            } else {
+                self.ac.wrapping_sub(1)
+            } & 0x3f;
+            return;
+        }
+        self.ac = match (self.two_lines, self.increment, self.ac) {
+            // two lines: $00-$27 and $40-$67. Set DDRAM address can point past the end
+            // of a line, the next step wraps like from the last valid address.
+            (true, true, 0x27..=0x3f) => 0x40,
+            (true, true, 0x67..) => 0x00,
+            (true, false, 0x00) => 0x67,
+            (true, false, 0x40) => 0x27,
+            (false, true, 0x4f..) => 0x00,
+            (false, false, 0x00) => 0x4f,
+            (_, true, ac) => ac + 1,
+            (_, false, ac) => ac - 1,
+        };
+    }
+    fn shift_display(&mut self, right: bool) {
+        let width = if self.two_lines { 40 } else { 80 };
+        self.shift = if right {
+            (self.shift + width - 1) % width
+        } else {
+            (self.shift + 1) % width
+        };
+        self.dirty = true;
+    }
+
+    fn write_data(&mut self, value: u8) {
+        if self.cgram_selected {
+            self.cgram[(self.ac & 0x3f) as usize] = value & 0x1f;
+        } else {
+            self.ddram[self.ac as usize] = value;
+            if self.shift_on_write {
+                self.shift_display(!self.increment);
+            }
+        }
+        self.step_ac();
+        self.dirty = true;
+        self.execute_for(INSTRUCTION_US);
+    }
+
+    fn instruction(&mut self, v: u8) {
+        let mut us = INSTRUCTION_US;
+        if v & 0x80 != 0 {
+            self.ac = v & 0x7f;
+            self.cgram_selected = false;
+        } else if v & 0x40 != 0 {
+            self.ac = v & 0x3f;
+            self.cgram_selected = true;
+        } else if v & 0x20 != 0 {
+            self.eight_bit = v & 0x10 != 0;
+            self.two_lines = v & 0x08 != 0;
+            self.high_nibble = None;
+        } else if v & 0x10 != 0 {
+            let right = v & 0x04 != 0;
+            if v & 0x08 != 0 {
+                self.shift_display(right);
+            } else {
+                let increment = self.increment;
+                self.increment = right;
+                self.step_ac();
+                self.increment = increment;
+            }
+        } else if v & 0x08 != 0 {
+            self.display_on = v & 0x04 != 0;
+            self.cursor_on = v & 0x02 != 0;
+            self.blink_on = v & 0x01 != 0;
+        } else if v & 0x04 != 0 {
+            self.increment = v & 0x02 != 0;
+            self.shift_on_write = v & 0x01 != 0;
+        } else if v & 0x02 != 0 {
+            self.ac = 0;
+            self.shift = 0;
+            self.cgram_selected = false;
+            us = CLEAR_US;
+        } else if v & 0x01 != 0 {
+            self.ddram = [b' '; 0x80];
+            self.ac = 0;
+            self.shift = 0;
+            self.increment = true;
+            self.cgram_selected = false;
+            us = CLEAR_US;
+        }
+        self.dirty = true;
+        self.execute_for(us);
+    }
+
+    // DDRAM address shown at a position of the display
+    fn address(&self, col: usize, row: usize) -> Option<u8> {
+        if self.two_lines {
+            Some(row as u8 * 0x40 + ((col as u8 + self.shift) % 40))
+        } else if row == 0 {
+            Some((col as u8 + self.shift) % 80)
+        } else {
+            None
+        }
+    }
+    // character codes on the display, None where nothing is shown
+    pub fn rows(&self) -> [[Option<u8>; COLUMNS]; ROWS] {
+        let mut rows = [[None; COLUMNS]; ROWS];
+        if !self.display_on {
+            return rows;
+        }
+        for (y, row) in rows.iter_mut().enumerate() {
+            for (x, c) in row.iter_mut().enumerate() {
+                *c = self.address(x, y).map(|a| self.ddram[a as usize]);
+            }
+        }
+        rows
+    }
+    pub fn text(&self) -> String {
+        self.rows()
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|c| c.map_or(' ', to_char))
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    // position of the cursor on the display if it is shown
+    pub fn cursor(&self) -> Option<(usize, usize)> {
+        if !self.display_on || !(self.cursor_on || self.blink_on) || self.cgram_selected {
+            return None;
+        }
+        (0..ROWS)
+            .flat_map(|y| (0..COLUMNS).map(move |x| (x, y)))
+            .find(|(x, y)| self.address(*x, *y) == Some(self.ac))
+    }
+    // the 5x8 dots of a user defined character (codes 0-15, 8-15 repeat 0-7)
+    pub fn glyph(&self, code: u8) -> Option<[u8; 8]> {
+        if code >= 0x10 {
+            return None;
+        }
+        let base = (code as usize & 7) * 8;
+        let mut glyph = [0; 8];
+        glyph.copy_from_slice(&self.cgram[base..base + 8]);
+        Some(glyph)
+    }
+    // true if the display changed since the last call
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+}
+
+const HIGH_CHARS: &str = "αäβεμσρg√¹jˣ¢£ñöpqθ∞ΩüΣπxy???÷ █";
+
+// closest unicode character for a code of the A00 ROM
+pub fn to_char(code: u8) -> char {
+    match code {
+        // user defined characters
+        0x00..=0x0f => '▒',
+        0x5c => '¥',
+        0x7e => '→',
+        0x7f => '←',
+        0x20..=0x7d => code as char,
+        // half width katakana, in the same order as in unicode
+        0xa1..=0xdf => char::from_u32(0xff61 + (code - 0xa1) as u32).unwrap_or(' '),
+        0xe0..=0xff => HIGH_CHARS
+            .chars()
+            .nth((code - 0xe0) as usize)
+            .unwrap_or(' '),
+        _ => ' ',
+    }
+}
@@ -0,0 +1,7 @@
+// Interface chips used by several machines, mapped through mem::Device or wired to
+// another chip's ports
+pub mod acia;
+pub mod cia;
+pub mod hd44780;
+pub mod riot;
+pub mod via;
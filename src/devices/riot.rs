@@ -0,0 +1,183 @@
+// MOS 6530 RAM-ROM-I/O-Timer: the I/O and timer part. The 1K ROM and 64 bytes of RAM
+// of the chip are plain memory and mapped by the machine.
+//
+// Registers by A0-A3: port A data/direction, port B data/direction, and the interval
+// timer at 4-7 (write: start with divider 1/8/64/1024, read: counter or flag). A3 set
+// enables the timer interrupt. After the counter passes zero the flag is set and it
+// keeps counting down every cycle.
+use crate::mem::Device;
+
+const DIVIDERS: [u16; 4] = [1, 8, 64, 1024];
+
+// levels of the port pins, pins configured as inputs read as 1 (pull-up)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pins {
+    pub pa: u8,
+    pub pb: u8,
+    pub ddra: u8,
+    pub ddrb: u8,
+}
+
+// a device wired to the ports
+pub trait RiotPort {
+    // called whenever the driven pins change
+    fn output(&mut self, _pins: &Pins) {}
+    // levels on port A and B, only the bits set as inputs are used
+    fn input(&mut self) -> (u8, u8) {
+        (0xff, 0xff)
+    }
+    // time passing, in cpu cycles
+    fn tick(&mut self, _cycles: u32) {}
+    // edge on NMI from the hardware around the ports, e.g. the KIM-1 ST key
+    fn nmi(&mut self) -> bool {
+        false
+    }
+}
+
+pub struct Riot {
+    port: Option<Box<dyn RiotPort>>,
+    pa: u8,
+    pb: u8,
+    ddra: u8,
+    ddrb: u8,
+    timer: u8,
+    divider: u16,
+    // cycles until the next decrement
+    prescale: u16,
+    expired: bool,
+    irq_enabled: bool,
+    pins: Pins,
+}
+
+impl Default for Riot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Riot {
+    pub fn new() -> Self {
+        let mut riot = Self {
+            port: None,
+            pa: 0,
+            pb: 0,
+            ddra: 0,
+            ddrb: 0,
+            timer: 0xff,
+            divider: 1024,
+            prescale: 1024,
+            expired: false,
+            irq_enabled: false,
+            pins: Pins::default(),
+        };
+        riot.pins = riot.compute_pins();
+        riot
+    }
+    pub fn with_port(mut self, port: Box<dyn RiotPort>) -> Self {
+        self.port = Some(port);
+        self.update_pins();
+        self
+    }
+    pub fn pins(&self) -> Pins {
+        self.pins
+    }
+
+    fn compute_pins(&self) -> Pins {
+        Pins {
+            pa: self.pa | !self.ddra,
+            pb: self.pb | !self.ddrb,
+            ddra: self.ddra,
+            ddrb: self.ddrb,
+        }
+    }
+    fn update_pins(&mut self) {
+        let pins = self.compute_pins();
+        if pins != self.pins {
+            self.pins = pins;
+            if let Some(port) = &mut self.port {
+                port.output(&pins);
+            }
+        }
+    }
+    fn inputs(&mut self) -> (u8, u8) {
+        let (a, b) = match &mut self.port {
+            Some(port) => port.input(),
+            None => (0xff, 0xff),
+        };
+        (
+            (self.pa & self.ddra) | (a & !self.ddra),
+            (self.pb & self.ddrb) | (b & !self.ddrb),
+        )
+    }
+}
+
+impl Device for Riot {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr & 7 {
+            0 => self.inputs().0,
+            2 => self.inputs().1,
+            4 | 6 => {
+                self.irq_enabled = addr & 8 != 0;
+                self.expired = false;
+                self.timer
+            }
+            _ => self.peek(addr),
+        }
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        match addr & 7 {
+            // without asking the port, inputs read as pulled up
+            0 => self.pins.pa,
+            1 => self.ddra,
+            2 => self.pins.pb,
+            3 => self.ddrb,
+            4 | 6 => self.timer,
+            _ => {
+                if self.expired {
+                    0x80
+                } else {
+                    0
+                }
+            }
+        }
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 7 {
+            0 => self.pa = value,
+            1 => self.ddra = value,
+            2 => self.pb = value,
+            3 => self.ddrb = value,
+            _ => {
+                self.timer = value;
+                self.divider = DIVIDERS[(addr & 3) as usize];
+                self.prescale = self.divider;
+                self.expired = false;
+                self.irq_enabled = addr & 8 != 0;
+            }
+        }
+        self.update_pins();
+    }
+    fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.prescale -= 1;
+            if self.prescale > 0 {
+                continue;
+            }
+            if self.timer == 0 {
+                self.expired = true;
+                self.divider = 1;
+            }
+            self.timer = self.timer.wrapping_sub(1);
+            self.prescale = self.divider;
+        }
+        if let Some(port) = &mut self.port {
+            port.tick(cycles);
+        }
+    }
+    fn irq(&self) -> bool {
+        self.expired && self.irq_enabled
+    }
+    fn nmi(&mut self) -> bool {
+        self.port.as_mut().is_some_and(|port| port.nmi())
+    }
+}
@@ -0,0 +1,760 @@
+// MOS 6522 Versatile Interface Adapter: two 8 bit ports with handshake lines, two
+// timers and a shift register. Registers are selected by A0-A3 and mirrored over the
+// mapped range.
+//
+// Emulated cycle by cycle from tick(). Whatever is connected to the pins implements
+// ViaPort; without one the inputs are set with set_input_a/set_input_b and the
+// set_ca1/... methods.
+use crate::mem::Device;
+
+const ORB: u16 = 0x0;
+const ORA: u16 = 0x1;
+const DDRB: u16 = 0x2;
+const DDRA: u16 = 0x3;
+const T1CL: u16 = 0x4;
+const T1CH: u16 = 0x5;
+const T1LL: u16 = 0x6;
+const T1LH: u16 = 0x7;
+const T2CL: u16 = 0x8;
+const T2CH: u16 = 0x9;
+const SR: u16 = 0xa;
+const ACR: u16 = 0xb;
+const PCR: u16 = 0xc;
+const IFR: u16 = 0xd;
+// $E is IER
+const ORA_NO_HANDSHAKE: u16 = 0xf;
+
+// interrupt flags
+pub const CA2: u8 = 0x01;
+pub const CA1: u8 = 0x02;
+pub const SHIFT: u8 = 0x04;
+pub const CB2: u8 = 0x08;
+pub const CB1: u8 = 0x10;
+pub const TIMER2: u8 = 0x20;
+pub const TIMER1: u8 = 0x40;
+
+// levels of the pins the VIA drives. Port pins configured as inputs read as 1 (pull-up).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pins {
+    pub pa: u8,
+    pub pb: u8,
+    pub ddra: u8,
+    pub ddrb: u8,
+    pub ca2: bool,
+    pub cb1: bool,
+    pub cb2: bool,
+}
+
+// a device wired to the ports
+pub trait ViaPort {
+    // called whenever the driven pins change
+    fn output(&mut self, _pins: &Pins) {}
+    // levels on port A and B, only the bits set as inputs are used
+    fn input(&mut self) -> (u8, u8) {
+        (0xff, 0xff)
+    }
+    // a byte was shifted out on CB2
+    fn shift_out(&mut self, _value: u8) {}
+    // time passing, in cpu cycles
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+// what the PCR says about CA2/CB2
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Control {
+    // active edge sets the flag, 'independent' means port accesses don't clear it
+    Input { positive: bool, independent: bool },
+    // low on port access, high again on the active edge of CA1/CB1
+    Handshake,
+    // low for one cycle after a port access
+    Pulse,
+    Manual(bool),
+}
+
+fn control(bits: u8) -> Control {
+    match bits & 7 {
+        0 => Control::Input {
+            positive: false,
+            independent: false,
+        },
+        1 => Control::Input {
+            positive: false,
+            independent: true,
+        },
+        2 => Control::Input {
+            positive: true,
+            independent: false,
+        },
+        3 => Control::Input {
+            positive: true,
+            independent: true,
+        },
+        4 => Control::Handshake,
+        5 => Control::Pulse,
+        6 => Control::Manual(false),
+        _ => Control::Manual(true),
+    }
+}
+
+#[derive(Default)]
+pub struct Via {
+    port: Option<Box<dyn ViaPort>>,
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    // port input levels when no ViaPort is connected
+    input_a: u8,
+    input_b: u8,
+    // IRA/IRB latched on the CA1/CB1 edge
+    latch_a: u8,
+    latch_b: u8,
+    t1: u16,
+    t1_latch: u16,
+    t1_armed: bool,
+    t1_reload: bool,
+    pb7: bool,
+    t2: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+    last_pb6: bool,
+    sr: u8,
+    // bits left to shift, 0 when idle
+    sr_bits: u8,
+    // cycles until the next shift clock edge in the T2 and phi2 modes
+    sr_timer: u16,
+    sr_clock: bool,
+    acr: u8,
+    pcr: u8,
+    ifr: u8,
+    ier: u8,
+    ca1: bool,
+    ca2_in: bool,
+    cb1_in: bool,
+    cb2_in: bool,
+    ca2_out: bool,
+    cb2_out: bool,
+    // CA2/CB2 pulse, ends with the next cycle
+    ca2_pulse: bool,
+    cb2_pulse: bool,
+    pins: Pins,
+}
+
+impl Via {
+    pub fn new() -> Self {
+        let mut via = Self {
+            input_a: 0xff,
+            input_b: 0xff,
+            last_pb6: true,
+            t1: 0xffff,
+            t1_latch: 0xffff,
+            t2: 0xffff,
+            ca1: true,
+            ca2_in: true,
+            cb1_in: true,
+            cb2_in: true,
+            ca2_out: true,
+            cb2_out: true,
+            pb7: true,
+            sr_clock: true,
+            ..Default::default()
+        };
+        via.pins = via.compute_pins();
+        via
+    }
+    pub fn with_port(mut self, port: Box<dyn ViaPort>) -> Self {
+        self.port = Some(port);
+        self.update_pins();
+        self
+    }
+    pub fn port_mut(&mut self) -> Option<&mut (dyn ViaPort + 'static)> {
+        self.port.as_deref_mut()
+    }
+    pub fn pins(&self) -> Pins {
+        self.pins
+    }
+    pub fn set_input_a(&mut self, value: u8) {
+        self.input_a = value;
+    }
+    pub fn set_input_b(&mut self, value: u8) {
+        self.input_b = value;
+        self.count_pb6();
+    }
+
+    fn inputs(&mut self) -> (u8, u8) {
+        match &mut self.port {
+            Some(port) => port.input(),
+            None => (self.input_a, self.input_b),
+        }
+    }
+    fn pin_a(&mut self) -> u8 {
+        let (a, _) = self.inputs();
+        (self.ora & self.ddra) | (a & !self.ddra)
+    }
+    fn pin_b(&mut self) -> u8 {
+        let (_, b) = self.inputs();
+        (b & !self.ddrb) | (self.orb & self.ddrb)
+    }
+
+    fn compute_pins(&self) -> Pins {
+        let mut pb = self.orb | !self.ddrb;
+        if self.acr & 0x80 != 0 {
+            pb = (pb & 0x7f) | if self.pb7 { 0x80 } else { 0 };
+        }
+        let shifting_out = self.acr & 0x10 != 0;
+        Pins {
+            pa: self.ora | !self.ddra,
+            pb,
+            ddra: self.ddra,
+            ddrb: self.ddrb,
+            ca2: self.ca2_out,
+            cb1: self.sr_clock,
+            cb2: if shifting_out {
+                self.sr & 0x80 != 0
+            } else {
+                self.cb2_out
+            },
+        }
+    }
+    fn update_pins(&mut self) {
+        let pins = self.compute_pins();
+        if pins != self.pins {
+            self.pins = pins;
+            if let Some(port) = &mut self.port {
+                port.output(&pins);
+            }
+        }
+    }
+
+    fn set_flag(&mut self, flag: u8) {
+        self.ifr |= flag;
+    }
+    fn clear_flag(&mut self, flag: u8) {
+        self.ifr &= !flag;
+    }
+
+    fn ca2_control(&self) -> Control {
+        control(self.pcr >> 1)
+    }
+    fn cb2_control(&self) -> Control {
+        control(self.pcr >> 5)
+    }
+
+    // read or write of ORA/ORB with handshake
+    fn port_a_access(&mut self) {
+        self.clear_flag(CA1);
+        match self.ca2_control() {
+            Control::Input {
+                independent: false, ..
+            } => self.clear_flag(CA2),
+            Control::Handshake => self.ca2_out = false,
+            Control::Pulse => {
+                self.ca2_out = false;
+                self.ca2_pulse = true;
+            }
+            _ => (),
+        }
+        self.update_pins();
+    }
+    fn port_b_access(&mut self, write: bool) {
+        self.clear_flag(CB1);
+        match self.cb2_control() {
+            Control::Input {
+                independent: false, ..
+            } => self.clear_flag(CB2),
+            // CB2 handshakes are for output only
+            Control::Handshake if write => self.cb2_out = false,
+            Control::Pulse if write => {
+                self.cb2_out = false;
+                self.cb2_pulse = true;
+            }
+            _ => (),
+        }
+        self.update_pins();
+    }
+
+    // control line inputs
+    pub fn set_ca1(&mut self, level: bool) {
+        if level == self.ca1 {
+            return;
+        }
+        self.ca1 = level;
+        if level == (self.pcr & 0x01 != 0) {
+            self.set_flag(CA1);
+            self.latch_a = self.pin_a();
+            if self.ca2_control() == Control::Handshake {
+                self.ca2_out = true;
+                self.update_pins();
+            }
+        }
+    }
+    pub fn set_ca2(&mut self, level: bool) {
+        if level == self.ca2_in {
+            return;
+        }
+        self.ca2_in = level;
+        if let Control::Input { positive, .. } = self.ca2_control() {
+            if level == positive {
+                self.set_flag(CA2);
+            }
+        }
+    }
+    pub fn set_cb1(&mut self, level: bool) {
+        if level == self.cb1_in {
+            return;
+        }
+        self.cb1_in = level;
+        if level == (self.pcr & 0x10 != 0) {
+            self.set_flag(CB1);
+            self.latch_b = self.pin_b();
+            if self.cb2_control() == Control::Handshake {
+                self.cb2_out = true;
+                self.update_pins();
+            }
+        }
+        // external shift clock: data moves on the rising edge
+        if self.acr & 0x0c == 0x0c && level {
+            self.shift();
+        }
+    }
+    pub fn set_cb2(&mut self, level: bool) {
+        if level == self.cb2_in {
+            return;
+        }
+        self.cb2_in = level;
+        if let Control::Input { positive, .. } = self.cb2_control() {
+            if level == positive {
+                self.set_flag(CB2);
+            }
+        }
+    }
+
+    // timer 2 in pulse counting mode counts falling edges on PB6
+    fn count_pb6(&mut self) {
+        let pb6 = self.pin_b() & 0x40 != 0;
+        let falling = self.last_pb6 && !pb6;
+        self.last_pb6 = pb6;
+        if falling && self.acr & 0x20 != 0 {
+            self.t2 = self.t2.wrapping_sub(1);
+            if self.t2 == 0 && self.t2_armed {
+                self.t2_armed = false;
+                self.set_flag(TIMER2);
+            }
+        }
+    }
+
+    fn sr_mode(&self) -> u8 {
+        (self.acr >> 2) & 7
+    }
+    fn start_shift(&mut self) {
+        self.clear_flag(SHIFT);
+        self.sr_bits = if self.sr_mode() == 0 { 0 } else { 8 };
+        self.sr_timer = self.sr_period();
+    }
+    // cycles per half period of the shift clock
+    fn sr_period(&self) -> u16 {
+        match self.sr_mode() {
+            2 | 6 => 1,
+            _ => self.t2_latch_low as u16 + 2,
+        }
+    }
+    // one bit in or out
+    fn shift(&mut self) {
+        let mode = self.sr_mode();
+        // free running output keeps going
+        if self.sr_bits == 0 && mode != 4 {
+            return;
+        }
+        if mode & 4 != 0 {
+            self.sr = self.sr.rotate_left(1);
+        } else {
+            self.sr = (self.sr << 1) | self.cb2_in as u8;
+        }
+        if mode != 4 {
+            self.sr_bits -= 1;
+            if self.sr_bits == 0 {
+                self.set_flag(SHIFT);
+                if mode & 4 != 0 {
+                    let sr = self.sr;
+                    if let Some(port) = &mut self.port {
+                        port.shift_out(sr);
+                    }
+                }
+            }
+        }
+        self.update_pins();
+    }
+
+    fn clock(&mut self) {
+        // timer 1: N, N-1, ... 0, $FFFF (flag), N, ...
+        if self.t1_reload {
+            self.t1 = self.t1_latch;
+            self.t1_reload = false;
+        } else {
+            let (t1, underflow) = self.t1.overflowing_sub(1);
+            self.t1 = t1;
+            if underflow {
+                self.t1_reload = true;
+                let free_run = self.acr & 0x40 != 0;
+                if free_run {
+                    self.set_flag(TIMER1);
+                    self.pb7 = !self.pb7;
+                } else if self.t1_armed {
+                    self.t1_armed = false;
+                    self.set_flag(TIMER1);
+                    self.pb7 = true;
+                }
+                if self.acr & 0x80 != 0 {
+                    self.update_pins();
+                }
+            }
+        }
+        // timer 2 in timed mode, one-shot only
+        if self.acr & 0x20 == 0 {
+            let (t2, underflow) = self.t2.overflowing_sub(1);
+            self.t2 = t2;
+            if underflow && self.t2_armed {
+                self.t2_armed = false;
+                self.set_flag(TIMER2);
+            }
+        }
+        // shift clock from timer 2 or phi2
+        let mode = self.sr_mode();
+        if mode != 0 && mode != 3 && mode != 7 && (self.sr_bits > 0 || mode == 4) {
+            self.sr_timer = self.sr_timer.saturating_sub(1);
+            if self.sr_timer == 0 {
+                self.sr_timer = self.sr_period();
+                self.sr_clock = !self.sr_clock;
+                if self.sr_clock {
+                    self.shift();
+                } else {
+                    self.update_pins();
+                }
+            }
+        }
+        // pulse outputs last a cycle
+        if self.ca2_pulse {
+            self.ca2_pulse = false;
+            self.ca2_out = true;
+            self.update_pins();
+        }
+        if self.cb2_pulse {
+            self.cb2_pulse = false;
+            self.cb2_out = true;
+            self.update_pins();
+        }
+    }
+
+    fn read_ifr(&self) -> u8 {
+        let irq = if self.irq() { 0x80 } else { 0 };
+        self.ifr | irq
+    }
+}
+
+impl Device for Via {
+    fn read(&mut self, addr: u16) -> u8 {
+        let reg = addr & 0xf;
+        let v = match reg {
+            ORB => {
+                let v = if self.acr & 0x02 != 0 {
+                    (self.latch_b & !self.ddrb) | (self.orb & self.ddrb)
+                } else {
+                    self.pin_b()
+                };
+                self.port_b_access(false);
+                v
+            }
+            ORA | ORA_NO_HANDSHAKE => {
+                let v = if self.acr & 0x01 != 0 {
+                    self.latch_a
+                } else {
+                    self.pin_a()
+                };
+                if reg == ORA {
+                    self.port_a_access();
+                }
+                v
+            }
+            _ => self.peek(addr),
+        };
+        match reg {
+            T1CL => self.clear_flag(TIMER1),
+            T2CL => self.clear_flag(TIMER2),
+            SR => self.start_shift(),
+            _ => (),
+        }
+        v
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        match addr & 0xf {
+            ORB => self.orb,
+            ORA | ORA_NO_HANDSHAKE => self.ora,
+            DDRB => self.ddrb,
+            DDRA => self.ddra,
+            T1CL => self.t1 as u8,
+            T1CH => (self.t1 >> 8) as u8,
+            T1LL => self.t1_latch as u8,
+            T1LH => (self.t1_latch >> 8) as u8,
+            T2CL => self.t2 as u8,
+            T2CH => (self.t2 >> 8) as u8,
+            SR => self.sr,
+            ACR => self.acr,
+            PCR => self.pcr,
+            IFR => self.read_ifr(),
+            // IER
+            _ => self.ier | 0x80,
+        }
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0xf {
+            ORB => {
+                self.orb = value;
+                self.port_b_access(true);
+            }
+            ORA => {
+                self.ora = value;
+                self.port_a_access();
+            }
+            ORA_NO_HANDSHAKE => self.ora = value,
+            DDRB => self.ddrb = value,
+            DDRA => self.ddra = value,
+            T1CL | T1LL => self.t1_latch = (self.t1_latch & 0xff00) | value as u16,
+            T1CH => {
+                self.t1_latch = (self.t1_latch & 0x00ff) | (value as u16) << 8;
+                self.t1 = self.t1_latch;
+                self.t1_reload = false;
+                self.t1_armed = true;
+                self.clear_flag(TIMER1);
+                if self.acr & 0x80 != 0 {
+                    self.pb7 = false;
+                }
+            }
+            T1LH => {
+                self.t1_latch = (self.t1_latch & 0x00ff) | (value as u16) << 8;
+                self.clear_flag(TIMER1);
+            }
+            T2CL => self.t2_latch_low = value,
+            T2CH => {
+                self.t2 = (value as u16) << 8 | self.t2_latch_low as u16;
+                self.t2_armed = true;
+                self.clear_flag(TIMER2);
+            }
+            SR => {
+                self.sr = value;
+                self.start_shift();
+            }
+            ACR => {
+                // timer 1 output starts out high when switched on, until T1CH is
+                // written. Switched off, PB7 is ORB again (see compute_pins).
+                if value & 0x80 != 0 && self.acr & 0x80 == 0 {
+                    self.pb7 = true;
+                }
+                self.acr = value;
+            }
+            PCR => {
+                self.pcr = value;
+                if let Control::Manual(level) = self.ca2_control() {
+                    self.ca2_out = level;
+                } else if self.ca2_control() != Control::Handshake {
+                    self.ca2_out = true;
+                }
+                if let Control::Manual(level) = self.cb2_control() {
+                    self.cb2_out = level;
+                } else if self.cb2_control() != Control::Handshake {
+                    self.cb2_out = true;
+                }
+            }
+            IFR => self.ifr &= !(value & 0x7f),
+            // IER: bit 7 says whether the other bits are set or cleared
+            _ => {
+                if value & 0x80 != 0 {
+                    self.ier |= value & 0x7f;
+                } else {
+                    self.ier &= !value;
+                }
+            }
+        }
+        self.update_pins();
+    }
+    fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.clock();
+        }
+        if self.acr & 0x20 != 0 {
+            self.count_pb6();
+        }
+        if let Some(port) = &mut self.port {
+            port.tick(cycles);
+        }
+    }
+    fn irq(&self) -> bool {
+        self.ifr & self.ier & 0x7f != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    const IER: u16 = 0xe;
+
+    // collects the bytes shifted out
+    struct Shifted(Rc<RefCell<Vec<u8>>>);
+    impl ViaPort for Shifted {
+        fn shift_out(&mut self, value: u8) {
+            self.0.borrow_mut().push(value);
+        }
+    }
+
+    fn flags(via: &Via) -> u8 {
+        via.peek(IFR) & 0x7f
+    }
+    fn pb7(via: &Via) -> bool {
+        via.pins().pb & 0x80 != 0
+    }
+
+    #[test]
+    fn timer1_one_shot() {
+        let mut via = Via::new();
+        via.write(ACR, 0x80);
+        via.write(IER, 0x80 | TIMER1);
+        assert!(pb7(&via));
+        via.write(T1CL, 10);
+        via.write(T1CH, 0);
+        assert!(!pb7(&via));
+        // 10 down to 0, the flag comes with the underflow
+        via.tick(10);
+        assert_eq!(via.peek(T1CL), 0);
+        assert_eq!(flags(&via), 0);
+        via.tick(1);
+        assert_eq!(flags(&via), TIMER1);
+        assert!(via.irq());
+        assert!(pb7(&via));
+        via.read(T1CL);
+        assert_eq!(flags(&via), 0);
+        assert!(!via.irq());
+        // reloaded from the latch, but it doesn't fire again
+        via.tick(1);
+        assert_eq!(via.peek(T1CL), 10);
+        via.tick(20);
+        assert_eq!(flags(&via), 0);
+        assert!(pb7(&via));
+    }
+
+    #[test]
+    fn timer1_free_running() {
+        let mut via = Via::new();
+        via.write(ACR, 0xc0);
+        via.write(T1CL, 4);
+        via.write(T1CH, 0);
+        assert!(!pb7(&via));
+        via.tick(5);
+        assert_eq!(flags(&via), TIMER1);
+        assert!(pb7(&via));
+        via.read(T1CL);
+        // N + 2 cycles a period
+        via.tick(5);
+        assert_eq!(flags(&via), 0);
+        via.tick(1);
+        assert_eq!(flags(&via), TIMER1);
+        assert!(!pb7(&via));
+    }
+
+    #[test]
+    fn pb7_follows_orb_without_timer_output() {
+        let mut via = Via::new();
+        via.write(DDRB, 0xff);
+        via.write(ORB, 0x00);
+        assert!(!pb7(&via));
+        via.write(ACR, 0x80);
+        assert!(pb7(&via));
+        via.write(ACR, 0x00);
+        assert!(!pb7(&via));
+    }
+
+    #[test]
+    fn timer2_one_shot() {
+        let mut via = Via::new();
+        via.write(T2CL, 5);
+        via.write(T2CH, 0);
+        via.tick(5);
+        assert_eq!(flags(&via), 0);
+        via.tick(1);
+        assert_eq!(flags(&via), TIMER2);
+        via.read(T2CL);
+        // keeps counting down from $FFFF without firing
+        via.tick(0x10000);
+        assert_eq!(flags(&via), 0);
+    }
+
+    #[test]
+    fn timer2_counts_pb6_pulses() {
+        let mut via = Via::new();
+        via.write(ACR, 0x20);
+        via.write(T2CL, 3);
+        via.write(T2CH, 0);
+        // cycles don't count
+        via.tick(100);
+        assert_eq!(via.peek(T2CL), 3);
+        for _ in 0..2 {
+            via.set_input_b(0xbf);
+            via.set_input_b(0xff);
+        }
+        assert_eq!(via.peek(T2CL), 1);
+        assert_eq!(flags(&via), 0);
+        // rising edges don't count either
+        via.set_input_b(0xff);
+        via.set_input_b(0xbf);
+        assert_eq!(flags(&via), TIMER2);
+        assert_eq!(via.peek(T2CL), 0);
+    }
+
+    #[test]
+    fn shift_out_under_phi2() {
+        let shifted = Rc::new(RefCell::new(Vec::new()));
+        let mut via = Via::new().with_port(Box::new(Shifted(shifted.clone())));
+        via.write(ACR, 0x18);
+        via.write(SR, 0xa5);
+        // two cycles a bit
+        via.tick(15);
+        assert_eq!(flags(&via), 0);
+        via.tick(1);
+        assert_eq!(flags(&via), SHIFT);
+        assert_eq!(*shifted.borrow(), vec![0xa5]);
+        // stops after 8 bits
+        via.tick(32);
+        assert_eq!(*shifted.borrow(), vec![0xa5]);
+    }
+
+    #[test]
+    fn shift_in_under_phi2() {
+        let mut via = Via::new();
+        via.write(ACR, 0x08);
+        via.set_cb2(true);
+        via.read(SR);
+        via.tick(16);
+        assert_eq!(flags(&via), SHIFT);
+        assert_eq!(via.read(SR), 0xff);
+        assert_eq!(flags(&via), 0);
+    }
+
+    #[test]
+    fn shift_in_under_cb1() {
+        let mut via = Via::new();
+        via.write(ACR, 0x0c);
+        via.read(SR);
+        // the clock of the SR modes doesn't matter here
+        via.tick(100);
+        assert_eq!(flags(&via), 0);
+        for bit in [true, false, true, false, false, false, false, true] {
+            via.set_cb2(bit);
+            via.set_cb1(false);
+            via.set_cb1(true);
+        }
+        // CB1 and CB2 are inputs as well, with the PCR at 0 their edges set flags too
+        assert_eq!(flags(&via), SHIFT | CB1 | CB2);
+        assert_eq!(via.peek(SR), 0xa1);
+    }
+}
@@ -0,0 +1,469 @@
+use crate::{mem::Memory, reg::Registers, symbols::SymbolTable};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl Mode {
+    pub fn operand_len(&self) -> u16 {
+        match self {
+            Mode::Implied | Mode::Accumulator => 0,
+            Mode::Immediate
+            | Mode::ZeroPage
+            | Mode::ZeroPageX
+            | Mode::ZeroPageY
+            | Mode::IndirectX
+            | Mode::IndirectY
+            | Mode::Relative => 1,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    // base cycle count, without page crossing and branch penalties
+    pub cycles: u8,
+}
+
+impl Opcode {
+    pub fn size(&self) -> u16 {
+        1 + self.mode.operand_len()
+    }
+    // indexed reads take an extra cycle when the index crosses a page boundary.
+    // Stores and read-modify-write instructions always take the long path.
+    pub fn has_page_penalty(&self) -> bool {
+        matches!(
+            self.mode,
+            Mode::AbsoluteX | Mode::AbsoluteY | Mode::IndirectY
+        ) && !matches!(
+            self.mnemonic,
+            "STA" | "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC"
+        )
+    }
+    // cycles for executing this opcode at 'addr' with the given registers, not counting
+    // the branch penalty (see branch_cycles)
+    pub fn cycles_at(&self, addr: u16, reg: &Registers, mem: &Memory) -> u32 {
+        let mut cycles = self.cycles as u32;
+        if self.has_page_penalty() {
+            let lo = mem.load(addr.wrapping_add(1));
+            let absolute = || lo as u16 | (mem.load(addr.wrapping_add(2)) as u16) << 8;
+            let (base, index) = match self.mode {
+                Mode::AbsoluteX => (absolute(), reg.x),
+                Mode::AbsoluteY => (absolute(), reg.y),
+                _ => (zp_pointer(mem, lo), reg.y),
+            };
+            if (base & 0xff00) != (base.wrapping_add(index as u16) & 0xff00) {
+                cycles += 1;
+            }
+        }
+        cycles
+    }
+    // taken branches need one extra cycle, two if the target is on another page
+    pub fn branch_cycles(&self, addr: u16, next_pc: u16) -> u32 {
+        let next_addr = addr.wrapping_add(self.size());
+        if self.mode != Mode::Relative || next_pc == next_addr {
+            0
+        } else if (next_pc & 0xff00) != (next_addr & 0xff00) {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+// documented NMOS opcodes only, everything else disassembles as '???'
+pub fn lookup(opc: u8) -> Option<Opcode> {
+    use Mode::*;
+    let (mnemonic, mode, cycles) = match opc {
+        0x69 => ("ADC", Immediate, 2),
+        0x65 => ("ADC", ZeroPage, 3),
+        0x75 => ("ADC", ZeroPageX, 4),
+        0x6d => ("ADC", Absolute, 4),
+        0x7d => ("ADC", AbsoluteX, 4),
+        0x79 => ("ADC", AbsoluteY, 4),
+        0x61 => ("ADC", IndirectX, 6),
+        0x71 => ("ADC", IndirectY, 5),
+        0x29 => ("AND", Immediate, 2),
+        0x25 => ("AND", ZeroPage, 3),
+        0x35 => ("AND", ZeroPageX, 4),
+        0x2d => ("AND", Absolute, 4),
+        0x3d => ("AND", AbsoluteX, 4),
+        0x39 => ("AND", AbsoluteY, 4),
+        0x21 => ("AND", IndirectX, 6),
+        0x31 => ("AND", IndirectY, 5),
+        0x0a => ("ASL", Accumulator, 2),
+        0x06 => ("ASL", ZeroPage, 5),
+        0x16 => ("ASL", ZeroPageX, 6),
+        0x0e => ("ASL", Absolute, 6),
+        0x1e => ("ASL", AbsoluteX, 7),
+        0x90 => ("BCC", Relative, 2),
+        0xb0 => ("BCS", Relative, 2),
+        0xf0 => ("BEQ", Relative, 2),
+        0x24 => ("BIT", ZeroPage, 3),
+        0x2c => ("BIT", Absolute, 4),
+        0x30 => ("BMI", Relative, 2),
+        0xd0 => ("BNE", Relative, 2),
+        0x10 => ("BPL", Relative, 2),
+        0x00 => ("BRK", Implied, 7),
+        0x50 => ("BVC", Relative, 2),
+        0x70 => ("BVS", Relative, 2),
+        0x18 => ("CLC", Implied, 2),
+        0xd8 => ("CLD", Implied, 2),
+        0x58 => ("CLI", Implied, 2),
+        0xb8 => ("CLV", Implied, 2),
+        0xc9 => ("CMP", Immediate, 2),
+        0xc5 => ("CMP", ZeroPage, 3),
+        0xd5 => ("CMP", ZeroPageX, 4),
+        0xcd => ("CMP", Absolute, 4),
+        0xdd => ("CMP", AbsoluteX, 4),
+        0xd9 => ("CMP", AbsoluteY, 4),
+        0xc1 => ("CMP", IndirectX, 6),
+        0xd1 => ("CMP", IndirectY, 5),
+        0xe0 => ("CPX", Immediate, 2),
+        0xe4 => ("CPX", ZeroPage, 3),
+        0xec => ("CPX", Absolute, 4),
+        0xc0 => ("CPY", Immediate, 2),
+        0xc4 => ("CPY", ZeroPage, 3),
+        0xcc => ("CPY", Absolute, 4),
+        0xc6 => ("DEC", ZeroPage, 5),
+        0xd6 => ("DEC", ZeroPageX, 6),
+        0xce => ("DEC", Absolute, 6),
+        0xde => ("DEC", AbsoluteX, 7),
+        0xca => ("DEX", Implied, 2),
+        0x88 => ("DEY", Implied, 2),
+        0x49 => ("EOR", Immediate, 2),
+        0x45 => ("EOR", ZeroPage, 3),
+        0x55 => ("EOR", ZeroPageX, 4),
+        0x4d => ("EOR", Absolute, 4),
+        0x5d => ("EOR", AbsoluteX, 4),
+        0x59 => ("EOR", AbsoluteY, 4),
+        0x41 => ("EOR", IndirectX, 6),
+        0x51 => ("EOR", IndirectY, 5),
+        0xe6 => ("INC", ZeroPage, 5),
+        0xf6 => ("INC", ZeroPageX, 6),
+        0xee => ("INC", Absolute, 6),
+        0xfe => ("INC", AbsoluteX, 7),
+        0xe8 => ("INX", Implied, 2),
+        0xc8 => ("INY", Implied, 2),
+        0x4c => ("JMP", Absolute, 3),
+        0x6c => ("JMP", Indirect, 5),
+        0x20 => ("JSR", Absolute, 6),
+        0xa9 => ("LDA", Immediate, 2),
+        0xa5 => ("LDA", ZeroPage, 3),
+        0xb5 => ("LDA", ZeroPageX, 4),
+        0xad => ("LDA", Absolute, 4),
+        0xbd => ("LDA", AbsoluteX, 4),
+        0xb9 => ("LDA", AbsoluteY, 4),
+        0xa1 => ("LDA", IndirectX, 6),
+        0xb1 => ("LDA", IndirectY, 5),
+        0xa2 => ("LDX", Immediate, 2),
+        0xa6 => ("LDX", ZeroPage, 3),
+        0xb6 => ("LDX", ZeroPageY, 4),
+        0xae => ("LDX", Absolute, 4),
+        0xbe => ("LDX", AbsoluteY, 4),
+        0xa0 => ("LDY", Immediate, 2),
+        0xa4 => ("LDY", ZeroPage, 3),
+        0xb4 => ("LDY", ZeroPageX, 4),
+        0xac => ("LDY", Absolute, 4),
+        0xbc => ("LDY", AbsoluteX, 4),
+        0x4a => ("LSR", Accumulator, 2),
+        0x46 => ("LSR", ZeroPage, 5),
+        0x56 => ("LSR", ZeroPageX, 6),
+        0x4e => ("LSR", Absolute, 6),
+        0x5e => ("LSR", AbsoluteX, 7),
+        0xea => ("NOP", Implied, 2),
+        0x09 => ("ORA", Immediate, 2),
+        0x05 => ("ORA", ZeroPage, 3),
+        0x15 => ("ORA", ZeroPageX, 4),
+        0x0d => ("ORA", Absolute, 4),
+        0x1d => ("ORA", AbsoluteX, 4),
+        0x19 => ("ORA", AbsoluteY, 4),
+        0x01 => ("ORA", IndirectX, 6),
+        0x11 => ("ORA", IndirectY, 5),
+        0x48 => ("PHA", Implied, 3),
+        0x08 => ("PHP", Implied, 3),
+        0x68 => ("PLA", Implied, 4),
+        0x28 => ("PLP", Implied, 4),
+        0x2a => ("ROL", Accumulator, 2),
+        0x26 => ("ROL", ZeroPage, 5),
+        0x36 => ("ROL", ZeroPageX, 6),
+        0x2e => ("ROL", Absolute, 6),
+        0x3e => ("ROL", AbsoluteX, 7),
+        0x6a => ("ROR", Accumulator, 2),
+        0x66 => ("ROR", ZeroPage, 5),
+        0x76 => ("ROR", ZeroPageX, 6),
+        0x6e => ("ROR", Absolute, 6),
+        0x7e => ("ROR", AbsoluteX, 7),
+        0x40 => ("RTI", Implied, 6),
+        0x60 => ("RTS", Implied, 6),
+        0xe9 => ("SBC", Immediate, 2),
+        0xe5 => ("SBC", ZeroPage, 3),
+        0xf5 => ("SBC", ZeroPageX, 4),
+        0xed => ("SBC", Absolute, 4),
+        0xfd => ("SBC", AbsoluteX, 4),
+        0xf9 => ("SBC", AbsoluteY, 4),
+        0xe1 => ("SBC", IndirectX, 6),
+        0xf1 => ("SBC", IndirectY, 5),
+        0x38 => ("SEC", Implied, 2),
+        0xf8 => ("SED", Implied, 2),
+        0x78 => ("SEI", Implied, 2),
+        0x85 => ("STA", ZeroPage, 3),
+        0x95 => ("STA", ZeroPageX, 4),
+        0x8d => ("STA", Absolute, 4),
+        0x9d => ("STA", AbsoluteX, 5),
+        0x99 => ("STA", AbsoluteY, 5),
+        0x81 => ("STA", IndirectX, 6),
+        0x91 => ("STA", IndirectY, 6),
+        0x86 => ("STX", ZeroPage, 3),
+        0x96 => ("STX", ZeroPageY, 4),
+        0x8e => ("STX", Absolute, 4),
+        0x84 => ("STY", ZeroPage, 3),
+        0x94 => ("STY", ZeroPageX, 4),
+        0x8c => ("STY", Absolute, 4),
+        0xaa => ("TAX", Implied, 2),
+        0xa8 => ("TAY", Implied, 2),
+        0xba => ("TSX", Implied, 2),
+        0x8a => ("TXA", Implied, 2),
+        0x9a => ("TXS", Implied, 2),
+        0x98 => ("TYA", Implied, 2),
+        _ => return None,
+    };
+    Some(Opcode {
+        mnemonic,
+        mode,
+        cycles,
+    })
+}
+
+fn zp_pointer(mem: &Memory, zp: u8) -> u16 {
+    mem.load(zp as u16) as u16 | ((mem.load(zp.wrapping_add(1) as u16) as u16) << 8)
+}
+
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub opcode: Option<Opcode>,
+}
+
+impl Instruction {
+    pub fn size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.size())
+    }
+    pub fn operand(&self) -> u16 {
+        match self.bytes.len() {
+            2 => self.bytes[1] as u16,
+            3 => self.bytes[1] as u16 | ((self.bytes[2] as u16) << 8),
+            _ => 0,
+        }
+    }
+    // absolute target of branches, jumps and absolute/zeropage operands
+    pub fn target(&self) -> Option<u16> {
+        let opcode = self.opcode?;
+        match opcode.mode {
+            Mode::Relative => Some(
+                self.next_addr()
+                    .wrapping_add_signed((self.bytes[1] as i8).into()),
+            ),
+            Mode::Implied | Mode::Accumulator | Mode::Immediate => None,
+            _ => Some(self.operand()),
+        }
+    }
+    pub fn bytes_string(&self) -> String {
+        self.bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    pub fn operand_string(&self, symbols: Option<&SymbolTable>) -> String {
+        let Some(opcode) = self.opcode else {
+            return String::new();
+        };
+        let op = self.operand();
+        let zp = |addr: u16| match symbols.and_then(|s| s.lookup(addr)) {
+            Some(name) => name.to_string(),
+            None => format!("${:02x}", addr),
+        };
+        let abs = |addr: u16| match symbols {
+            Some(symbols) => symbols.describe(addr, 3),
+            None => format!("${:04x}", addr),
+        };
+        match opcode.mode {
+            Mode::Implied => String::new(),
+            Mode::Accumulator => "A".into(),
+            Mode::Immediate => format!("#${:02x}", op),
+            Mode::ZeroPage => zp(op),
+            Mode::ZeroPageX => format!("{},X", zp(op)),
+            Mode::ZeroPageY => format!("{},Y", zp(op)),
+            Mode::Absolute => abs(op),
+            Mode::AbsoluteX => format!("{},X", abs(op)),
+            Mode::AbsoluteY => format!("{},Y", abs(op)),
+            Mode::Indirect => format!("({})", abs(op)),
+            Mode::IndirectX => format!("({},X)", zp(op)),
+            Mode::IndirectY => format!("({}),Y", zp(op)),
+            Mode::Relative => abs(self.target().unwrap_or(0)),
+        }
+    }
+    // cycles for executing this instruction with the given registers, not counting the
+    // branch penalty (see branch_cycles)
+    pub fn cycles(&self, reg: &Registers, mem: &Memory) -> u32 {
+        self.opcode
+            .map_or(0, |opcode| opcode.cycles_at(self.addr, reg, mem))
+    }
+    // address of the memory operand, None for immediate/implied modes and branches
+    pub fn effective_addr(&self, reg: &Registers, mem: &Memory) -> Option<u16> {
+        let op = self.operand();
+        match self.opcode?.mode {
+            Mode::ZeroPage | Mode::Absolute | Mode::Indirect => Some(op),
+            Mode::ZeroPageX => Some((op as u8).wrapping_add(reg.x) as u16),
+            Mode::ZeroPageY => Some((op as u8).wrapping_add(reg.y) as u16),
+            Mode::AbsoluteX => Some(op.wrapping_add(reg.x as u16)),
+            Mode::AbsoluteY => Some(op.wrapping_add(reg.y as u16)),
+            Mode::IndirectX => Some(zp_pointer(mem, (op as u8).wrapping_add(reg.x))),
+            Mode::IndirectY => Some(zp_pointer(mem, op as u8).wrapping_add(reg.y as u16)),
+            Mode::Implied | Mode::Accumulator | Mode::Immediate | Mode::Relative => None,
+        }
+    }
+    // taken branches need one extra cycle, two if the target is on another page
+    pub fn branch_cycles(&self, next_pc: u16) -> u32 {
+        self.opcode
+            .map_or(0, |opcode| opcode.branch_cycles(self.addr, next_pc))
+    }
+    pub fn mnemonic(&self) -> &'static str {
+        self.opcode.map(|o| o.mnemonic).unwrap_or("???")
+    }
+    pub fn text(&self) -> String {
+        self.text_with(None)
+    }
+    pub fn text_with(&self, symbols: Option<&SymbolTable>) -> String {
+        let operand = self.operand_string(symbols);
+        if operand.is_empty() {
+            self.mnemonic().to_string()
+        } else {
+            format!("{} {}", self.mnemonic(), operand)
+        }
+    }
+    // listing line, with a label column if symbols are available
+    pub fn listing(&self, symbols: Option<&SymbolTable>) -> String {
+        let label = symbols
+            .and_then(|s| s.lookup(self.addr))
+            .map(|l| format!("{}:", l))
+            .unwrap_or_default();
+        format!(
+            "{:04x}: {:<8}  {:<12} {}",
+            self.addr,
+            self.bytes_string(),
+            label,
+            self.text_with(symbols)
+        )
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04x}: {:<8}  {}",
+            self.addr,
+            self.bytes_string(),
+            self.text()
+        )
+    }
+}
+
+pub fn disassemble(mem: &Memory, addr: u16) -> Instruction {
+    let opc = mem.load(addr);
+    let opcode = lookup(opc);
+    let len = opcode.map(|o| o.size()).unwrap_or(1);
+    let bytes = (0..len).map(|i| mem.load(addr.wrapping_add(i))).collect();
+    Instruction {
+        addr,
+        bytes,
+        opcode,
+    }
+}
+
+pub fn disassemble_range(mem: &Memory, mut addr: u16, count: usize) -> Vec<Instruction> {
+    let mut out = Vec::with_capacity(count);
+    for _ in 0..count {
+        let inst = disassemble(mem, addr);
+        addr = inst.next_addr();
+        out.push(inst);
+    }
+    out
+}
+
+// how many instructions disassemble_before() decodes, earlier ones are single bytes
+pub const MAX_BEFORE: usize = 256;
+
+// bytes from 'cur' to 'addr' decoded as instructions, None if they don't end on 'addr'
+fn count_until(mem: &Memory, mut cur: u16, addr: u16) -> Option<usize> {
+    let mut n = 0;
+    while cur != addr {
+        let size = lookup(mem.load(cur)).map_or(1, |o| o.size());
+        if addr.wrapping_sub(cur) < size {
+            return None;
+        }
+        cur = cur.wrapping_add(size);
+        n += 1;
+    }
+    Some(n)
+}
+
+// single byte entries for the 'count' bytes before 'addr'
+fn bytes_before(mem: &Memory, addr: u16, count: usize) -> Vec<Instruction> {
+    (1..=count)
+        .rev()
+        .map(|i| {
+            let a = addr.wrapping_sub(i as u16);
+            Instruction {
+                addr: a,
+                bytes: vec![mem.load(a)],
+                opcode: None,
+            }
+        })
+        .collect()
+}
+
+// walking backwards is ambiguous on the 6502: try start addresses further and further
+// back until one of them decodes into a sequence that lands exactly on 'addr'. Only
+// the last MAX_BEFORE instructions are searched for.
+pub fn disassemble_before(mem: &Memory, addr: u16, count: usize) -> Vec<Instruction> {
+    let decoded = count.min(MAX_BEFORE);
+    for back in (decoded..=decoded * 3).rev() {
+        let start = addr.wrapping_sub(back as u16);
+        let Some(n) = count_until(mem, start, addr) else {
+            continue;
+        };
+        if n >= decoded {
+            let mut first = start;
+            for _ in 0..n - decoded {
+                first = disassemble(mem, first).next_addr();
+            }
+            let mut out = bytes_before(mem, first, count - decoded);
+            out.extend(disassemble_range(mem, first, decoded));
+            return out;
+        }
+    }
+    // no clean decode found: fall back to single bytes
+    bytes_before(mem, addr, count)
+}
@@ -0,0 +1,283 @@
+// Memory written out as a program file, e.g. to burn a patched ROM to an EEPROM or keep
+// what a program assembled. The formats are the ones loader reads, except o65:
+//
+//   bin    the bytes from the first to the last address, gaps are 0
+//   prg    the same after a 2 byte load address
+//   txt    easy6502 hexdump, 16 bytes a line
+//   ihex   Intel HEX, 16 bytes a record, the entry as start linear address
+//   srec   Motorola S1 records, S5 count and S9 with the entry
+//   woz    Wozmon hex entry, like txt in upper case, with an R command for the entry
+//
+// Without a range the whole 64K are taken. For all formats but bin and prg, 16 byte
+// chunks that are all 0 are left out then, like hexdump::dump does.
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use log::{error, info};
+
+use crate::{
+    cpu::StepInfo,
+    dbg::Dbg,
+    loader::{Format, Image, Segment},
+    mem::Memory,
+    reg::Registers,
+    screenshot::{numbered_path, Trigger, Triggers},
+};
+
+const CHUNK: usize = 16;
+
+fn unsupported(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// format by extension
+pub fn format_of(path: &str) -> Option<Format> {
+    let ext = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "rom" | "img" => Some(Format::Bin),
+        "mot" => Some(Format::SRecord),
+        ext => Format::parse(ext),
+    }
+}
+
+// start..=end of memory, without side effects on devices. 'sparse' leaves out the
+// chunks that are all 0.
+pub fn capture(mem: &Memory, start: u16, end: u16, sparse: bool, entry: Option<u16>) -> Image {
+    let mut image = Image {
+        entry,
+        ..Image::default()
+    };
+    let mut addr = start as usize;
+    while addr <= end as usize {
+        let len = CHUNK.min(end as usize + 1 - addr);
+        let chunk = (addr..addr + len)
+            .map(|a| mem.load(a as u16))
+            .collect::<Vec<_>>();
+        if !sparse || chunk.iter().any(|b| *b != 0) {
+            match image.segments.last_mut() {
+                Some(last) if last.addr as usize + last.data.len() == addr => {
+                    last.data.extend(chunk)
+                }
+                _ => image.segments.push(Segment {
+                    addr: addr as u16,
+                    data: chunk,
+                }),
+            }
+        }
+        addr += len;
+    }
+    image
+}
+
+// the segments as one block from the first to the last address
+fn flatten(image: &Image) -> (u16, Vec<u8>) {
+    let Some(start) = image.segments.iter().map(|s| s.addr).min() else {
+        return (0, Vec::new());
+    };
+    let end = image
+        .segments
+        .iter()
+        .map(|s| s.addr as usize + s.data.len())
+        .max()
+        .unwrap();
+    let mut out = vec![0; end - start as usize];
+    for s in &image.segments {
+        let at = (s.addr - start) as usize;
+        out[at..at + s.data.len()].copy_from_slice(&s.data);
+    }
+    (start, out)
+}
+
+// address and data in the chunks the text formats put on a line
+fn lines(image: &Image) -> impl Iterator<Item = (u16, &[u8])> {
+    image.segments.iter().flat_map(|s| {
+        s.data
+            .chunks(CHUNK)
+            .enumerate()
+            .map(move |(i, chunk)| (s.addr + (i * CHUNK) as u16, chunk))
+    })
+}
+
+fn write_ihex<W: Write>(out: &mut W, image: &Image) -> io::Result<()> {
+    let record = |out: &mut W, kind: u8, addr: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend(addr.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend(data);
+        let sum = bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        let hex = bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        writeln!(out, ":{}", hex)
+    };
+    for (addr, data) in lines(image) {
+        record(out, 0x00, addr, data)?;
+    }
+    if let Some(entry) = image.entry {
+        record(out, 0x05, 0, &(entry as u32).to_be_bytes())?;
+    }
+    record(out, 0x01, 0, &[])
+}
+
+fn write_srec<W: Write>(out: &mut W, image: &Image) -> io::Result<()> {
+    let record = |out: &mut W, kind: u8, addr: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8 + 3];
+        bytes.extend(addr.to_be_bytes());
+        bytes.extend(data);
+        let sum = bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        bytes.push(!sum);
+        let hex = bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        writeln!(out, "S{}{}", kind, hex)
+    };
+    record(out, 0, 0, &[])?;
+    let mut count = 0usize;
+    for (addr, data) in lines(image) {
+        record(out, 1, addr, data)?;
+        count += 1;
+    }
+    if count <= 0xffff {
+        record(out, 5, count as u16, &[])?;
+    }
+    record(out, 9, image.entry.unwrap_or(0), &[])
+}
+
+pub fn write<W: Write>(out: &mut W, format: Format, image: &Image) -> io::Result<()> {
+    match format {
+        Format::Bin => out.write_all(&flatten(image).1),
+        Format::Prg => {
+            let (start, data) = flatten(image);
+            out.write_all(&start.to_le_bytes())?;
+            out.write_all(&data)
+        }
+        Format::Txt => {
+            for (addr, data) in lines(image) {
+                let bytes = data
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>();
+                writeln!(out, "{:04x}: {}", addr, bytes.join(" "))?;
+            }
+            Ok(())
+        }
+        Format::Wozmon => {
+            for (addr, data) in lines(image) {
+                let bytes = data
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<Vec<_>>();
+                writeln!(out, "{:04X}: {}", addr, bytes.join(" "))?;
+            }
+            if let Some(entry) = image.entry {
+                writeln!(out, "{:04X}R", entry)?;
+            }
+            Ok(())
+        }
+        Format::IntelHex => write_ihex(out, image),
+        Format::SRecord => write_srec(out, image),
+        Format::O65 => Err(unsupported("o65 can't be written".to_string())),
+    }
+}
+
+// 'format' or the one the extension says
+pub fn save(path: &str, format: Option<Format>, image: &Image) -> io::Result<()> {
+    let format = format.or_else(|| format_of(path)).ok_or_else(|| {
+        unsupported(format!(
+            "{}: unknown format, use .bin, .prg, .txt, .hex, .s19 or .woz",
+            path
+        ))
+    })?;
+    let mut out = BufWriter::new(File::create(path)?);
+    write(&mut out, format, image)?;
+    out.flush()
+}
+
+// Writes memory whenever one of the triggers fires
+pub struct Export {
+    path: String,
+    format: Option<Format>,
+    range: Option<(u16, u16)>,
+    entry: Option<u16>,
+    triggers: Triggers,
+    saved: usize,
+}
+
+impl Export {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            format: None,
+            range: None,
+            entry: None,
+            triggers: Triggers::default(),
+            saved: 0,
+        }
+    }
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+    // start..=end instead of all memory
+    pub fn with_range(mut self, start: u16, end: u16) -> Self {
+        self.range = Some((start, end));
+        self
+    }
+    // the entry point written to the file, e.g. of the loaded program. Without it the
+    // pc at the time of the export is taken.
+    pub fn with_entry(mut self, entry: u16) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+    // a Pc trigger writes one file, add it again for each further visit to export
+    pub fn at(mut self, trigger: Trigger) -> Self {
+        self.triggers.push(trigger);
+        self
+    }
+    // write now, returns the file written
+    pub fn save(&mut self, mem: &Memory, pc: u16) -> io::Result<String> {
+        let path = numbered_path(&self.path, self.saved);
+        self.saved += 1;
+        let format = self.format.or_else(|| format_of(&path));
+        let entry = Some(self.entry.unwrap_or(pc));
+        let image = match self.range {
+            Some((start, end)) => capture(mem, start, end, false, entry),
+            None => capture(
+                mem,
+                0,
+                0xffff,
+                !matches!(format, Some(Format::Bin) | Some(Format::Prg)),
+                entry,
+            ),
+        };
+        save(&path, format, &image)?;
+        info!("memory exported to {}", path);
+        Ok(path)
+    }
+    fn save_or_log(&mut self, mem: &Memory, pc: u16) {
+        if let Err(e) = self.save(mem, pc) {
+            error!("export failed: {}", e);
+        }
+    }
+}
+
+impl Dbg for Export {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.triggers.at_pc(reg.pc) {
+            self.save_or_log(mem, reg.pc);
+        }
+        false
+    }
+    fn post_step(&mut self, info: &StepInfo, reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.triggers.after_step(info.cycles) {
+            self.save_or_log(mem, reg.pc);
+        }
+        false
+    }
+}
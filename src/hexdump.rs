@@ -1,82 +1,57 @@
-use std::io::{BufRead, BufReader};
+use crate::{
+    loader::{self, AddressSpace, Format},
+    symbols::SymbolTable,
+};
 
+// the file at 'base_addr' in a 64K image
 pub fn read_bin(name: &str, base_addr: usize) -> Vec<u8> {
-    let mut b: Vec<u8> = std::fs::read(name).unwrap();
-
-    let mut out = Vec::new();
-    out.resize(base_addr, 0u8);
-    out.append(&mut b);
-
-    // b.insertk
-    out
+    let mut space = AddressSpace::new();
+    space
+        .load(name, Some(Format::Bin), Some(base_addr as u16))
+        .unwrap();
+    space.to_ram()
 }
 pub fn read_txt(name: &str) -> Vec<u8> {
-    let mut out = Vec::new();
-    let f = BufReader::new(std::fs::File::open(name).unwrap());
-
-    for line in f.lines() {
-        let line = line.unwrap();
-
-        if line.chars().nth(4).unwrap() != ':' {
-            panic!("missing address in hexdump: {}", line);
-        }
-        let mut address =
-            usize::from_str_radix(&line.chars().take(4).collect::<String>(), 16).unwrap();
-
-        for b in line.chars().skip(6).collect::<String>().split(' ') {
-            // println!("b: {}", b);
-            if b.is_empty() {
-                continue;
-            }
-
-            if out.len() <= address {
-                out.resize(address + 1, 0u8);
-            }
-            out[address] = u8::from_str_radix(b, 16).unwrap();
-            address += 1;
-        }
-    }
-    out
+    let mut space = AddressSpace::new();
+    space.load(name, Some(Format::Txt), None).unwrap();
+    space.to_ram()
 }
 pub fn read() -> Vec<u8> {
-    let mut out = Vec::new();
-    for line in std::io::stdin().lock().lines() {
-        let line = line.unwrap();
-
-        if line.chars().nth(4).unwrap() != ':' {
-            panic!("missing address in hexdump: {}", line);
-        }
-        let mut address =
-            usize::from_str_radix(&line.chars().take(4).collect::<String>(), 16).unwrap();
-
-        for b in line.chars().skip(6).collect::<String>().split(' ') {
-            // println!("b: {}", b);
-            if b.is_empty() {
-                continue;
-            }
-
-            if out.len() <= address {
-                out.resize(address + 1, 0u8);
-            }
-            out[address] = u8::from_str_radix(b, 16).unwrap();
-            address += 1;
-        }
-    }
-    out
+    let text = std::io::read_to_string(std::io::stdin()).unwrap();
+    let mut space = AddressSpace::new();
+    space.place("stdin", &loader::parse_txt(&text).unwrap());
+    space.to_ram()
 }
 
 pub fn dump(data: &[u8]) {
+    dump_with_symbols(data, None);
+}
+// same as dump, but lists the labels that fall into each printed chunk
+pub fn dump_with_symbols(data: &[u8], symbols: Option<&SymbolTable>) {
     let chunk_size = 16;
     for (i, chunk) in data.chunks(chunk_size).enumerate() {
         if chunk.iter().any(|c| *c != 0) {
+            let addr = i * chunk_size;
+            let labels = symbols
+                .map(|s| {
+                    s.labels_in(addr as u16, (addr + chunk.len() - 1) as u16)
+                        .map(|(a, name)| format!("{}=${:04x}", name, a))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
             println!(
-                "{:04x}: {}",
-                i * chunk_size,
+                "{:04x}: {}{}",
+                addr,
                 chunk
                     .iter()
                     .map(|b| format!("{:02x}", *b))
                     .collect::<Vec<_>>()
-                    .join(" ")
+                    .join(" "),
+                if labels.is_empty() {
+                    String::new()
+                } else {
+                    format!("  ; {}", labels.join(" "))
+                }
             );
         }
     }
@@ -0,0 +1,281 @@
+// Keyboard input for the machine front ends: the live terminal, a script of timed
+// keystrokes, a recording of an earlier session or pasted text. Time is counted in cpu
+// cycles, so scripted runs are deterministic.
+//
+// Script format, one command per line, '#' starts a comment:
+//
+//   after 200k          wait 200000 cycles after the previous command (k and M work)
+//   at 1.5M             wait until cycle 1500000
+//   type 10 PRINT 1\n   type the text, \n is Return, \e ESC, \\ a backslash
+//   key up              a single key: a character, space, return, esc, backspace, tab,
+//                       up, down, left, right or ctrl-<c>
+//   seed 1234           seed of the machine's random numbers, recordings start with it
+//
+// Recordings are written in the same format, so they are replayed as scripts.
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use termion::{
+    async_stdin,
+    event::Key,
+    input::{Keys, TermRead},
+    AsyncReader,
+};
+
+pub trait InputSource {
+    // keys that are due at 'cycles'
+    fn poll(&mut self, cycles: u64) -> Vec<Key>;
+}
+
+impl<T: InputSource + ?Sized> InputSource for Box<T> {
+    fn poll(&mut self, cycles: u64) -> Vec<Key> {
+        (**self).poll(cycles)
+    }
+}
+
+// keys typed on the host terminal
+pub struct Live {
+    keys: Keys<AsyncReader>,
+}
+
+impl Default for Live {
+    fn default() -> Self {
+        Self {
+            keys: async_stdin().keys(),
+        }
+    }
+}
+
+impl InputSource for Live {
+    fn poll(&mut self, _cycles: u64) -> Vec<Key> {
+        self.keys.by_ref().filter_map(|k| k.ok()).collect()
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// "200k", "1.5M", "1000"
+pub fn parse_cycles(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, scale) = match s.char_indices().last()? {
+        (i, 'k') | (i, 'K') => (&s[..i], 1e3),
+        (i, 'M') => (&s[..i], 1e6),
+        _ => (s, 1.0),
+    };
+    let v = num.parse::<f64>().ok()?;
+    if v < 0.0 {
+        return None;
+    }
+    Some((v * scale) as u64)
+}
+
+pub fn parse_key(name: &str) -> Option<Key> {
+    let key = match name {
+        "space" => Key::Char(' '),
+        "return" | "enter" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "esc" => Key::Esc,
+        "backspace" => Key::Backspace,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        _ => {
+            let mut chars = name.chars();
+            match (name.strip_prefix("ctrl-"), chars.next(), chars.next()) {
+                (Some(c), _, _) if c.chars().count() == 1 => Key::Ctrl(c.chars().next()?),
+                (None, Some(c), None) => Key::Char(c),
+                _ => return None,
+            }
+        }
+    };
+    Some(key)
+}
+
+// inverse of parse_key, None for keys scripts can't express
+pub fn key_name(key: Key) -> Option<String> {
+    let name = match key {
+        Key::Char(' ') => "space".into(),
+        Key::Char('\n') => "return".into(),
+        Key::Char('\t') => "tab".into(),
+        Key::Char(c) => c.to_string(),
+        Key::Ctrl(c) => format!("ctrl-{}", c),
+        Key::Esc => "esc".into(),
+        Key::Backspace => "backspace".into(),
+        Key::Up => "up".into(),
+        Key::Down => "down".into(),
+        Key::Left => "left".into(),
+        Key::Right => "right".into(),
+        _ => return None,
+    };
+    Some(name)
+}
+
+fn unescape(text: &str) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\\' => match chars.next() {
+                Some('n') => Key::Char('\n'),
+                Some('e') => Key::Esc,
+                Some(c) => Key::Char(c),
+                None => Key::Char('\\'),
+            },
+            c => Key::Char(c),
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+// timed keystrokes from a script or recording
+#[derive(Default)]
+pub struct Script {
+    // (cycle, key), sorted by cycle
+    events: Vec<(u64, Key)>,
+    next: usize,
+    seed: Option<u64>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut events = Vec::new();
+        let mut time = 0u64;
+        let mut seed = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+            let err = || invalid(format!("line {}: bad command '{}'", i + 1, line));
+            match cmd {
+                "after" => time += parse_cycles(arg).ok_or_else(err)?,
+                "at" => time = time.max(parse_cycles(arg).ok_or_else(err)?),
+                "type" => events.extend(unescape(arg).into_iter().map(|k| (time, k))),
+                "key" => events.push((time, parse_key(arg.trim()).ok_or_else(err)?)),
+                "seed" => seed = Some(arg.trim().parse().map_err(|_| err())?),
+                _ => return Err(err()),
+            }
+        }
+        Ok(Self {
+            events,
+            next: 0,
+            seed,
+        })
+    }
+    pub fn load(path: &str) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        Self::parse(&text).map_err(|e| invalid(format!("{}: {}", path, e)))
+    }
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+    // true once all keys were delivered
+    pub fn finished(&self) -> bool {
+        self.next >= self.events.len()
+    }
+}
+
+impl InputSource for Script {
+    fn poll(&mut self, cycles: u64) -> Vec<Key> {
+        let due = self.events[self.next..]
+            .iter()
+            .take_while(|(t, _)| *t <= cycles)
+            .map(|(_, k)| *k)
+            .collect::<Vec<_>>();
+        self.next += due.len();
+        due
+    }
+}
+
+// Types a text, e.g. a Wozmon transcript, on top of another source: one character every
+// 'cycles_per_char', so it's no faster than someone typing it. Line ends are Return.
+pub struct Paste<S> {
+    source: S,
+    keys: VecDeque<Key>,
+    cycles_per_char: u64,
+    next: u64,
+}
+
+impl<S: InputSource> Paste<S> {
+    pub fn new(source: S, text: &str, cycles_per_char: u64) -> Self {
+        Self {
+            source,
+            keys: text.chars().filter(|c| *c != '\r').map(Key::Char).collect(),
+            cycles_per_char,
+            next: 0,
+        }
+    }
+    // true once all of the text was typed
+    pub fn finished(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl<S: InputSource> InputSource for Paste<S> {
+    fn poll(&mut self, cycles: u64) -> Vec<Key> {
+        let mut keys = Vec::new();
+        while self.next <= cycles {
+            let Some(key) = self.keys.pop_front() else {
+                break;
+            };
+            keys.push(key);
+            self.next += self.cycles_per_char.max(1);
+        }
+        keys.extend(self.source.poll(cycles));
+        keys
+    }
+}
+
+// passes keys through from another source and writes them to a script
+pub struct Record<S> {
+    source: S,
+    out: BufWriter<File>,
+    last: u64,
+}
+
+impl<S: InputSource> Record<S> {
+    pub fn new(source: S, path: &str) -> io::Result<Self> {
+        let mut out = BufWriter::new(
+            File::create(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?,
+        );
+        writeln!(out, "# recorded session, replay with --replay")?;
+        Ok(Self {
+            source,
+            out,
+            last: 0,
+        })
+    }
+    // needed to replay sessions of machines with random numbers
+    pub fn with_seed(mut self, seed: u64) -> io::Result<Self> {
+        writeln!(self.out, "seed {}", seed)?;
+        Ok(self)
+    }
+}
+
+impl<S: InputSource> InputSource for Record<S> {
+    fn poll(&mut self, cycles: u64) -> Vec<Key> {
+        let keys = self.source.poll(cycles);
+        if keys.is_empty() {
+            return keys;
+        }
+        for key in keys.iter().filter_map(|k| key_name(*k)) {
+            if cycles != self.last {
+                writeln!(self.out, "at {}", cycles).unwrap();
+                self.last = cycles;
+            }
+            writeln!(self.out, "key {}", key).unwrap();
+        }
+        // a crash should not lose the recording
+        self.out.flush().unwrap();
+        keys
+    }
+}
@@ -1,5 +1,17 @@
+pub mod callstack;
+pub mod coverage;
 pub mod cpu;
+pub mod dap;
 pub mod dbg;
+pub mod devices;
+pub mod disasm;
+pub mod export;
 pub mod hexdump;
+pub mod input;
+pub mod loader;
+pub mod machines;
 pub mod mem;
+pub mod profiler;
 pub mod reg;
+pub mod screenshot;
+pub mod symbols;
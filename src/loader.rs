@@ -0,0 +1,1003 @@
+// Program files as segments of memory: the bytes, where they go and where the program
+// starts, if the format says.
+//
+//   bin    raw bytes at a base address
+//   txt    easy6502 hexdump, "0600: a9 01 8d 00 02"
+//   prg    Commodore, a 2 byte load address and the bytes. Loaded at $0801 it's a BASIC
+//          program and the entry is the address of its SYS.
+//   ihex   Intel HEX, data, EOF and the start address records. Extended addresses have
+//          to stay below $10000.
+//   srec   Motorola S-records, S1/S9 and S2/S8 (and S3/S7) files
+//   o65    André Fachat's relocatable format. Text and data are relocated to the base
+//          address, bss and zero page stay where the file put them. There is no linker,
+//          undefined references are an error. The entry is the start of text.
+//   woz    Wozmon hex entry transcripts, "0300: A9 00 85 ...", read the way Wozmon
+//          reads typed lines. The entry is the address of the first R command, what
+//          comes after it is meant for the program that runs and isn't loaded.
+//
+// Without a format it is detected: o65 by its marker, Intel HEX and S-records by their
+// first line, hexdumps by the address (Wozmon transcripts if they aren't plain
+// hexdumps), PRG and Wozmon transcripts by the file name, anything else is raw.
+//
+// AddressSpace puts the segments of several files into one 64K space, e.g. a ROM at
+// $E000, a program at $0300 and the vectors at $FFFA. Overlaps are warned about, the
+// file placed later wins.
+use std::{convert::TryFrom, fs, io, path::Path};
+
+use log::{info, warn};
+
+use crate::mem::Memory;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u16,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    // the last address, inclusive
+    pub fn end(&self) -> u16 {
+        self.addr
+            .wrapping_add(self.data.len().saturating_sub(1) as u16)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u16>,
+}
+
+impl Image {
+    // appends at 'addr', growing the last segment if it ends right there
+    fn push(&mut self, addr: u16, bytes: &[u8]) {
+        match self.segments.last_mut() {
+            Some(last) if last.addr as usize + last.data.len() == addr as usize => {
+                last.data.extend_from_slice(bytes)
+            }
+            _ => self.segments.push(Segment {
+                addr,
+                data: bytes.to_vec(),
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Overlap {
+    pub start: u16,
+    pub end: u16,
+    // the file that was there before
+    pub with: String,
+}
+
+#[derive(Default)]
+pub struct AddressSpace {
+    // in the order they were placed, with the file they came from
+    segments: Vec<(String, Segment)>,
+    entry: Option<u16>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // places the segments of 'image', 'name' is what overlaps are reported with.
+    // The first entry point placed is the one of the whole space.
+    pub fn place(&mut self, name: &str, image: &Image) -> Vec<Overlap> {
+        let mut overlaps = Vec::new();
+        for segment in image.segments.iter().filter(|s| !s.data.is_empty()) {
+            info!("{}: ${:04x}-${:04x}", name, segment.addr, segment.end());
+            for (other, placed) in &self.segments {
+                let start = segment.addr.max(placed.addr);
+                let end = segment.end().min(placed.end());
+                if start <= end {
+                    warn!("{}: ${:04x}-${:04x} overlaps {}", name, start, end, other);
+                    overlaps.push(Overlap {
+                        start,
+                        end,
+                        with: other.clone(),
+                    });
+                }
+            }
+            self.segments.push((name.to_string(), segment.clone()));
+        }
+        self.entry = self.entry.or(image.entry);
+        overlaps
+    }
+    // loads and places a file, see load
+    pub fn load(
+        &mut self,
+        name: &str,
+        format: Option<Format>,
+        base: Option<u16>,
+    ) -> io::Result<Vec<Overlap>> {
+        let image = load(name, format, base)?;
+        Ok(self.place(name, &image))
+    }
+    pub fn entry(&self) -> Option<u16> {
+        self.entry
+    }
+    pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.iter().map(|(_, s)| s)
+    }
+    // all of the 64K, 0 where nothing was placed
+    pub fn to_ram(&self) -> Vec<u8> {
+        let mut ram = vec![0; 0x10000];
+        for s in self.segments() {
+            ram[s.addr as usize..][..s.data.len()].copy_from_slice(&s.data);
+        }
+        ram
+    }
+    // into a machine's memory, like the cpu would write it. Bytes that land in ROM or
+    // where nothing is mapped are dropped with a warning.
+    pub fn store(&self, mem: &mut Memory) {
+        for (name, s) in &self.segments {
+            let mut dropped = Vec::new();
+            for (i, b) in s.data.iter().enumerate() {
+                let addr = s.addr.wrapping_add(i as u16);
+                if mem.is_writable(addr) {
+                    mem.store(addr, *b);
+                } else {
+                    dropped.push(addr);
+                }
+            }
+            if let (Some(first), Some(last)) = (dropped.first(), dropped.last()) {
+                warn!(
+                    "{}: ${:04x}-${:04x} is ROM or unmapped, {} bytes not stored",
+                    name,
+                    first,
+                    last,
+                    dropped.len()
+                );
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Bin,
+    Txt,
+    Prg,
+    IntelHex,
+    SRecord,
+    O65,
+    Wozmon,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        Some(match name {
+            "bin" => Format::Bin,
+            "txt" => Format::Txt,
+            "prg" => Format::Prg,
+            "ihex" | "hex" => Format::IntelHex,
+            "srec" | "s19" | "s28" | "s37" => Format::SRecord,
+            "o65" => Format::O65,
+            "woz" => Format::Wozmon,
+            _ => return None,
+        })
+    }
+}
+
+const O65_MARKER: [u8; 5] = [0x01, 0x00, b'o', b'6', b'5'];
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn detect(name: &str, data: &[u8]) -> Format {
+    if data.starts_with(&O65_MARKER) {
+        return Format::O65;
+    }
+    let first_line = data
+        .split(|b| *b == b'\n')
+        .map(|l| l.trim_ascii())
+        .find(|l| !l.is_empty())
+        .unwrap_or_default();
+    match first_line {
+        [b':', rest @ ..] if !rest.is_empty() && rest.iter().all(u8::is_ascii_hexdigit) => {
+            return Format::IntelHex
+        }
+        [b'S', b'0'..=b'9', rest @ ..] if rest.iter().all(u8::is_ascii_hexdigit) => {
+            return Format::SRecord
+        }
+        line if monitor_command(line) => {
+            let text = std::str::from_utf8(data).unwrap_or_default();
+            return match parse_txt(text) {
+                Ok(_) if !text.is_empty() => Format::Txt,
+                _ => Format::Wozmon,
+            };
+        }
+        _ => (),
+    }
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("prg") => Format::Prg,
+        Some("woz") => Format::Wozmon,
+        _ => Format::Bin,
+    }
+}
+
+// starts like "0600: ", "300R" or "FF00.FFFF"
+fn monitor_command(line: &[u8]) -> bool {
+    let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    (1..=4).contains(&digits) && matches!(line.get(digits), Some(b':' | b'.' | b'R' | b'r'))
+}
+
+// 'base' is where raw binaries go and where o65 files are relocated to, o65 files keep
+// their own text address without it
+pub fn load(name: &str, format: Option<Format>, base: Option<u16>) -> io::Result<Image> {
+    let data = fs::read(name).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+    let format = format.unwrap_or_else(|| detect(name, &data));
+    parse(&data, Some(format), base).map_err(|e| invalid(format!("{}: {}", name, e)))
+}
+
+pub fn parse(data: &[u8], format: Option<Format>, base: Option<u16>) -> io::Result<Image> {
+    let text = || std::str::from_utf8(data).map_err(|e| invalid(e.to_string()));
+    match format.unwrap_or_else(|| detect("", data)) {
+        Format::Bin => parse_bin(data, base.unwrap_or(0)),
+        Format::Txt => parse_txt(text()?),
+        Format::Prg => parse_prg(data),
+        Format::IntelHex => parse_ihex(text()?),
+        Format::SRecord => parse_srec(text()?),
+        Format::O65 => parse_o65(data, base),
+        Format::Wozmon => parse_wozmon(text()?),
+    }
+}
+
+pub fn parse_bin(data: &[u8], base: u16) -> io::Result<Image> {
+    if base as usize + data.len() > 0x10000 {
+        return Err(invalid(format!(
+            "{} bytes don't fit at ${:04x}",
+            data.len(),
+            base
+        )));
+    }
+    let mut image = Image::default();
+    if !data.is_empty() {
+        image.push(base, data);
+    }
+    Ok(image)
+}
+
+pub fn parse_txt(text: &str) -> io::Result<Image> {
+    let mut image = Image::default();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |msg: &str| invalid(format!("line {}: {}", i + 1, msg));
+        let (addr, bytes) = line
+            .split_once(':')
+            .ok_or_else(|| error("missing address"))?;
+        let addr = u16::from_str_radix(addr.trim(), 16).map_err(|_| error("bad address"))?;
+        let bytes = bytes
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| error("bad byte"))?;
+        if addr as usize + bytes.len() > 0x10000 {
+            return Err(error("past $ffff"));
+        }
+        image.push(addr, &bytes);
+    }
+    Ok(image)
+}
+
+// the address a BASIC line "SYS 2064" at the start of 'program' (at $0801) jumps to
+pub fn basic_sys(program: &[u8]) -> Option<u16> {
+    // link and line number, then the tokens. $9E is SYS.
+    let mut tokens = program
+        .get(4..)?
+        .iter()
+        .copied()
+        .take_while(|b| *b != 0)
+        .skip_while(|b| *b == b' ');
+    if tokens.next()? != 0x9e {
+        return None;
+    }
+    tokens
+        .skip_while(|b| *b == b' ' || *b == b'(')
+        .take_while(u8::is_ascii_digit)
+        .map(|b| b as char)
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+pub fn parse_prg(data: &[u8]) -> io::Result<Image> {
+    if data.len() < 2 {
+        return Err(invalid("too short for a PRG".to_string()));
+    }
+    let addr = u16::from_le_bytes([data[0], data[1]]);
+    let mut image = parse_bin(&data[2..], addr)?;
+    image.entry = Some(match addr {
+        0x0801 => basic_sys(&data[2..]).unwrap_or(addr),
+        _ => addr,
+    });
+    Ok(image)
+}
+
+// what a typed character does to Wozmon
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WozMode {
+    Examine,
+    BlockExamine,
+    Store,
+}
+
+// Wozmon's input buffer holds 127 characters, typing more cancels the line
+const WOZMON_LINE: usize = 127;
+
+pub fn parse_wozmon(text: &str) -> io::Result<Image> {
+    let mut image = Image::default();
+    // the store and examine indexes, they carry over from line to line
+    let mut store = 0u16;
+    let mut examine = 0u16;
+    let mut lines = text.lines().enumerate();
+    for (i, line) in &mut lines {
+        let error = |msg: &str| invalid(format!("line {}: {}", i + 1, msg));
+        // the keyboard has no lower case, '_' is rubout
+        let mut typed = Vec::new();
+        for c in line.bytes() {
+            match c.to_ascii_uppercase() {
+                b'_' => {
+                    typed.pop();
+                }
+                b'\r' => (),
+                c => typed.push(c),
+            }
+        }
+        if typed.len() > WOZMON_LINE {
+            return Err(error("longer than Wozmon's 127 characters"));
+        }
+        let mut mode = WozMode::Examine;
+        let mut pos = 0;
+        while pos < typed.len() {
+            match typed[pos] {
+                b'.' => mode = WozMode::BlockExamine,
+                b':' => mode = WozMode::Store,
+                b'R' => {
+                    image.entry = Some(examine);
+                    let rest = lines.filter(|(_, l)| !l.trim().is_empty()).count();
+                    if rest > 0 {
+                        warn!(
+                            "{} lines after the R on line {} are input for the program, not loaded",
+                            rest,
+                            i + 1
+                        );
+                    }
+                    return Ok(image);
+                }
+                // delimiters
+                c if c < b'.' => (),
+                _ => {
+                    let digits = typed[pos..]
+                        .iter()
+                        .take_while(|c| matches!(c, b'0'..=b'9' | b'A'..=b'F'))
+                        .count();
+                    // anything else makes Wozmon drop the rest of the line
+                    if digits == 0 {
+                        break;
+                    }
+                    // only the last 4 digits count
+                    let value = typed[pos..pos + digits].iter().fold(0u16, |v, c| {
+                        v << 4 | (*c as char).to_digit(16).unwrap() as u16
+                    });
+                    pos += digits;
+                    match mode {
+                        WozMode::Store => {
+                            image.push(store, &[value as u8]);
+                            store = store.wrapping_add(1);
+                        }
+                        WozMode::Examine => {
+                            store = value;
+                            examine = value;
+                        }
+                        WozMode::BlockExamine => examine = value.wrapping_add(1),
+                    }
+                    continue;
+                }
+            }
+            pos += 1;
+        }
+    }
+    Ok(image)
+}
+
+// the bytes of a hex encoded record
+fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+// absolute addresses of the 6502, the upper bits the formats allow have to be 0
+fn address(addr: u32) -> io::Result<u16> {
+    u16::try_from(addr).map_err(|_| invalid(format!("address ${:x} past $ffff", addr)))
+}
+
+pub fn parse_ihex(text: &str) -> io::Result<Image> {
+    let mut image = Image::default();
+    // from the extended segment (type 2) and linear (type 4) address records
+    let mut upper = 0u32;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: String| invalid(format!("line {}: {}", i + 1, msg));
+        let record = line
+            .strip_prefix(':')
+            .and_then(hex_bytes)
+            .filter(|r| r.len() >= 5 && r.len() == r[0] as usize + 5)
+            .ok_or_else(|| error("bad record".to_string()))?;
+        if record.iter().fold(0u8, |s, b| s.wrapping_add(*b)) != 0 {
+            return Err(error("checksum mismatch".to_string()));
+        }
+        let addr = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        let word = || match data {
+            [hi, lo] => Ok(u16::from_be_bytes([*hi, *lo]) as u32),
+            _ => Err(error("bad address record".to_string())),
+        };
+        match record[3] {
+            0 => {
+                let start = address(upper + addr).map_err(|e| error(e.to_string()))?;
+                address(upper + addr + data.len().max(1) as u32 - 1)
+                    .map_err(|e| error(e.to_string()))?;
+                image.push(start, data);
+            }
+            1 => return Ok(image),
+            2 => upper = word()? << 4,
+            4 => upper = word()? << 16,
+            // CS:IP and EIP
+            3 | 5 => {
+                let [a, b, c, d] = data else {
+                    return Err(error("bad start address record".to_string()));
+                };
+                let start = if record[3] == 3 {
+                    (u16::from_be_bytes([*a, *b]) as u32) * 16 + u16::from_be_bytes([*c, *d]) as u32
+                } else {
+                    u32::from_be_bytes([*a, *b, *c, *d])
+                };
+                image.entry = Some(address(start).map_err(|e| error(e.to_string()))?);
+            }
+            t => return Err(error(format!("unknown record type {:02x}", t))),
+        }
+    }
+    Err(invalid("no end of file record".to_string()))
+}
+
+pub fn parse_srec(text: &str) -> io::Result<Image> {
+    let mut image = Image::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: String| invalid(format!("line {}: {}", i + 1, msg));
+        let (kind, record) = line
+            .strip_prefix('S')
+            .and_then(|l| l.split_at_checked(1))
+            .and_then(|(kind, rest)| Some((kind, hex_bytes(rest)?)))
+            .filter(|(_, r)| !r.is_empty() && r.len() == r[0] as usize + 1)
+            .ok_or_else(|| error("bad record".to_string()))?;
+        if record.iter().fold(0u8, |s, b| s.wrapping_add(*b)) != 0xff {
+            return Err(error("checksum mismatch".to_string()));
+        }
+        let address_size = match kind {
+            "0" | "1" | "5" | "9" => 2,
+            "2" | "6" | "8" => 3,
+            "3" | "7" => 4,
+            _ => return Err(error(format!("unknown record type S{}", kind))),
+        };
+        if record.len() < address_size + 2 {
+            return Err(error("record too short".to_string()));
+        }
+        let addr = record[1..=address_size]
+            .iter()
+            .fold(0u32, |a, b| a << 8 | *b as u32);
+        let data = &record[address_size + 1..record.len() - 1];
+        match kind {
+            "1" | "2" | "3" => {
+                let start = address(addr).map_err(|e| error(e.to_string()))?;
+                address(addr + data.len().max(1) as u32 - 1).map_err(|e| error(e.to_string()))?;
+                image.push(start, data);
+            }
+            // 0 is what tools write when there is no entry
+            "7" | "8" | "9" => {
+                if addr != 0 {
+                    image.entry = Some(address(addr).map_err(|e| error(e.to_string()))?);
+                }
+                return Ok(image);
+            }
+            // header and record counts
+            _ => (),
+        }
+    }
+    Ok(image)
+}
+
+// o65 segment ids, 1 absolute, 4 bss and 5 zero page aren't moved
+const SEG_UNDEFINED: u8 = 0;
+const SEG_TEXT: u8 = 2;
+const SEG_DATA: u8 = 3;
+
+// relocation entry types
+const RELOC_WORD: u8 = 0x80;
+const RELOC_HIGH: u8 = 0x40;
+const RELOC_LOW: u8 = 0x20;
+
+// o65 mode bits
+const MODE_65816: u16 = 0x8000;
+const MODE_PAGED: u16 = 0x4000;
+const MODE_SIZE32: u16 = 0x2000;
+const MODE_BSSZERO: u16 = 0x0200;
+
+struct O65Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl O65Reader<'_> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let b = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| invalid(format!("o65: truncated at {}", self.pos)))?;
+        self.pos += 1;
+        Ok(b)
+    }
+    fn word(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes([self.byte()?, self.byte()?]))
+    }
+    fn bytes(&mut self, n: usize) -> io::Result<&[u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid(format!("o65: truncated at {}", self.pos)))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+    fn name(&mut self) -> io::Result<String> {
+        let mut name = Vec::new();
+        loop {
+            match self.byte()? {
+                0 => return Ok(String::from_utf8_lossy(&name).to_string()),
+                b => name.push(b),
+            }
+        }
+    }
+}
+
+// applies a relocation table to 'segment', 'delta' is by how much each segment id moved
+fn relocate(
+    reader: &mut O65Reader,
+    segment: &mut [u8],
+    delta: &[i32; 6],
+    paged: bool,
+    undefined: &[String],
+) -> io::Result<()> {
+    let mut offset: isize = -1;
+    loop {
+        match reader.byte()? {
+            0 => return Ok(()),
+            255 => {
+                offset += 254;
+                continue;
+            }
+            b => offset += b as isize,
+        }
+        let typebyte = reader.byte()?;
+        let (kind, id) = (typebyte & 0xe0, typebyte & 0x1f);
+        if id == SEG_UNDEFINED {
+            let index = reader.word()? as usize;
+            let name = undefined.get(index).map(|n| n.as_str()).unwrap_or("?");
+            return Err(invalid(format!("o65: undefined reference to {}", name)));
+        }
+        let delta = *delta
+            .get(id as usize)
+            .ok_or_else(|| invalid(format!("o65: bad segment id {}", id)))?;
+        let at = offset as usize;
+        let out_of_range = || invalid(format!("o65: relocation at {} outside the segment", at));
+        match kind {
+            RELOC_WORD => {
+                let bytes = segment.get_mut(at..at + 2).ok_or_else(out_of_range)?;
+                let v = u16::from_le_bytes([bytes[0], bytes[1]]) as i32 + delta;
+                bytes.copy_from_slice(&(v as u16).to_le_bytes());
+            }
+            RELOC_HIGH => {
+                let low = if paged { 0 } else { reader.byte()? };
+                let byte = segment.get_mut(at).ok_or_else(out_of_range)?;
+                let v = (u16::from_le_bytes([low, *byte]) as i32 + delta) as u16;
+                *byte = (v >> 8) as u8;
+            }
+            RELOC_LOW => {
+                let byte = segment.get_mut(at).ok_or_else(out_of_range)?;
+                *byte = (*byte as i32 + delta) as u8;
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "o65: relocation type {:02x} is 65816 only",
+                    kind
+                )))
+            }
+        }
+    }
+}
+
+pub fn parse_o65(data: &[u8], base: Option<u16>) -> io::Result<Image> {
+    let mut reader = O65Reader { data, pos: 0 };
+    if reader.bytes(5)? != O65_MARKER {
+        return Err(invalid("not an o65 file".to_string()));
+    }
+    let version = reader.byte()?;
+    if version != 0 {
+        return Err(invalid(format!("o65: unknown version {}", version)));
+    }
+    let mode = reader.word()?;
+    if mode & (MODE_65816 | MODE_SIZE32) != 0 {
+        return Err(invalid(
+            "o65: 65816 or 32 bit files aren't supported".to_string(),
+        ));
+    }
+    let tbase = reader.word()?;
+    let tlen = reader.word()?;
+    let dbase = reader.word()?;
+    let dlen = reader.word()?;
+    let bbase = reader.word()?;
+    let blen = reader.word()?;
+    let _zbase = reader.word()?;
+    let _zlen = reader.word()?;
+    let _stack = reader.word()?;
+    // header options: length (including itself), type, bytes
+    loop {
+        match reader.byte()? {
+            0 => break,
+            len => {
+                reader.bytes(len as usize - 1)?;
+            }
+        }
+    }
+    let mut text = reader.bytes(tlen as usize)?.to_vec();
+    let mut data_segment = reader.bytes(dlen as usize)?.to_vec();
+    let undefined = (0..reader.word()?)
+        .map(|_| reader.name())
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // text at 'base', data right after it, bss and zero page where they were
+    let new_tbase = base.unwrap_or(tbase);
+    let new_dbase = new_tbase.wrapping_add(tlen);
+    let mut delta = [0i32; 6];
+    delta[SEG_TEXT as usize] = new_tbase as i32 - tbase as i32;
+    delta[SEG_DATA as usize] = new_dbase as i32 - dbase as i32;
+    let paged = mode & MODE_PAGED != 0;
+    relocate(&mut reader, &mut text, &delta, paged, &undefined)?;
+    relocate(&mut reader, &mut data_segment, &delta, paged, &undefined)?;
+
+    let mut image = Image::default();
+    for (addr, bytes) in [(new_tbase, &text), (new_dbase, &data_segment)] {
+        if addr as usize + bytes.len() > 0x10000 {
+            return Err(invalid(format!("o65: segment at ${:04x} past $ffff", addr)));
+        }
+        if !bytes.is_empty() {
+            image.push(addr, bytes);
+        }
+    }
+    if mode & MODE_BSSZERO != 0 && blen > 0 {
+        if bbase as usize + blen as usize > 0x10000 {
+            return Err(invalid(format!("o65: bss at ${:04x} past $ffff", bbase)));
+        }
+        image.segments.push(Segment {
+            addr: bbase,
+            data: vec![0; blen as usize],
+        });
+    }
+    image.entry = Some(new_tbase);
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(addr: u16, data: &[u8]) -> Segment {
+        Segment {
+            addr,
+            data: data.to_vec(),
+        }
+    }
+
+    fn error(result: io::Result<Image>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    // a record with the checksum filled in
+    fn ihex(addr: u16, kind: u8, data: &[u8]) -> String {
+        let mut record = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+        record.extend_from_slice(data);
+        let sum = record.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        record.push(sum.wrapping_neg());
+        let hex: String = record.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(":{}\n", hex)
+    }
+
+    fn srec(kind: char, addr: u32, address_size: usize, data: &[u8]) -> String {
+        let mut record = vec![(address_size + data.len() + 1) as u8];
+        record.extend_from_slice(&addr.to_be_bytes()[4 - address_size..]);
+        record.extend_from_slice(data);
+        let sum = record.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        record.push(!sum);
+        let hex: String = record.iter().map(|b| format!("{:02X}", b)).collect();
+        format!("S{}{}\n", kind, hex)
+    }
+
+    #[test]
+    fn ihex_data_and_start_address() {
+        let text = ihex(0x1000, 0, &[0xa9, 0x01])
+            + &ihex(0x1002, 0, &[0x60])
+            + &ihex(0x2000, 0, &[0xff])
+            + &ihex(0, 5, &[0, 0, 0x10, 0x00])
+            + &ihex(0, 1, &[]);
+        let image = parse_ihex(&text).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![
+                segment(0x1000, &[0xa9, 0x01, 0x60]),
+                segment(0x2000, &[0xff])
+            ]
+        );
+        assert_eq!(image.entry, Some(0x1000));
+    }
+
+    #[test]
+    fn ihex_extended_address_records() {
+        // segment base $0100 is $1000, CS:IP $0100:$0010 is $1010
+        let text = ihex(0, 2, &[0x01, 0x00])
+            + &ihex(0x0010, 0, &[0xea])
+            + &ihex(0, 3, &[0x01, 0x00, 0x00, 0x10])
+            + &ihex(0, 4, &[0x00, 0x00])
+            + &ihex(0x0200, 0, &[0x42])
+            + &ihex(0, 1, &[]);
+        let image = parse_ihex(&text).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![segment(0x1010, &[0xea]), segment(0x0200, &[0x42])]
+        );
+        assert_eq!(image.entry, Some(0x1010));
+
+        let text = ihex(0, 4, &[0x00, 0x01]) + &ihex(0, 0, &[0xea]) + &ihex(0, 1, &[]);
+        assert_eq!(
+            error(parse_ihex(&text)),
+            "line 2: address $10000 past $ffff"
+        );
+        let text = ihex(0xffff, 0, &[1, 2]) + &ihex(0, 1, &[]);
+        assert_eq!(
+            error(parse_ihex(&text)),
+            "line 1: address $10000 past $ffff"
+        );
+    }
+
+    #[test]
+    fn ihex_errors() {
+        let text = ihex(0x1000, 0, &[0xa9]).replace("A9", "A8") + &ihex(0, 1, &[]);
+        assert_eq!(error(parse_ihex(&text)), "line 1: checksum mismatch");
+        assert_eq!(error(parse_ihex(":0000\n")), "line 1: bad record");
+        assert_eq!(
+            error(parse_ihex(&ihex(0x1000, 0, &[0xa9]))),
+            "no end of file record"
+        );
+        assert_eq!(
+            error(parse_ihex(&ihex(0, 6, &[]))),
+            "line 1: unknown record type 06"
+        );
+    }
+
+    #[test]
+    fn srec_address_sizes_and_entry() {
+        let text = srec('0', 0, 2, b"HDR")
+            + &srec('1', 0x1000, 2, &[0xa9, 0x01])
+            + &srec('2', 0x1002, 3, &[0x60])
+            + &srec('3', 0x3000, 4, &[0xff])
+            + &srec('5', 3, 2, &[])
+            + &srec('9', 0x1000, 2, &[]);
+        let image = parse_srec(&text).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![
+                segment(0x1000, &[0xa9, 0x01, 0x60]),
+                segment(0x3000, &[0xff])
+            ]
+        );
+        assert_eq!(image.entry, Some(0x1000));
+
+        // an entry of 0 means there is none
+        let image = parse_srec(&(srec('1', 0x1000, 2, &[0xea]) + &srec('9', 0, 2, &[]))).unwrap();
+        assert_eq!(image.entry, None);
+    }
+
+    #[test]
+    fn srec_errors() {
+        let text = srec('1', 0x1000, 2, &[0xa9]).replace("A9", "A8");
+        assert_eq!(error(parse_srec(&text)), "line 1: checksum mismatch");
+        assert_eq!(
+            error(parse_srec(&srec('2', 0x10000, 3, &[0xea]))),
+            "line 1: address $10000 past $ffff"
+        );
+        assert_eq!(
+            error(parse_srec(&srec('1', 0xffff, 2, &[1, 2]))),
+            "line 1: address $10000 past $ffff"
+        );
+        assert_eq!(
+            error(parse_srec(&srec('4', 0, 2, &[]))),
+            "line 1: unknown record type S4"
+        );
+    }
+
+    // a non-paged o65 file with text at $1000 and data at $2000
+    fn o65(text: &[u8], data: &[u8], undefined: &[&str], relocs: &[u8]) -> Vec<u8> {
+        let mut out = O65_MARKER.to_vec();
+        out.push(0);
+        for word in [
+            0,
+            0x1000,
+            text.len() as u16,
+            0x2000,
+            data.len() as u16,
+            0x3000,
+            0,
+            0,
+            0,
+            0,
+        ] {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        // no header options
+        out.push(0);
+        out.extend_from_slice(text);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&(undefined.len() as u16).to_le_bytes());
+        for name in undefined {
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+        }
+        out.extend_from_slice(relocs);
+        out
+    }
+
+    #[test]
+    fn o65_relocates_text_and_data() {
+        // JMP $1000, LDA $2000, then a pointer to the text in the data segment
+        let text = [0x4c, 0x00, 0x10, 0xad, 0x00, 0x20];
+        let data = [0x03, 0x10];
+        let relocs = [
+            // text: words at 1 and 4
+            2,
+            RELOC_WORD | SEG_TEXT,
+            3,
+            RELOC_WORD | SEG_DATA,
+            0,
+            // data: word at 0
+            1,
+            RELOC_WORD | SEG_TEXT,
+            0,
+        ];
+        let file = o65(&text, &data, &[], &relocs);
+        let image = parse_o65(&file, Some(0x4000)).unwrap();
+        // the data segment follows the text
+        assert_eq!(
+            image.segments,
+            vec![segment(
+                0x4000,
+                &[0x4c, 0x00, 0x40, 0xad, 0x06, 0x40, 0x03, 0x40]
+            )]
+        );
+        assert_eq!(image.entry, Some(0x4000));
+
+        // without a base the text stays, the data still moves up to it
+        let image = parse_o65(&file, None).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![segment(
+                0x1000,
+                &[0x4c, 0x00, 0x10, 0xad, 0x06, 0x10, 0x03, 0x10]
+            )]
+        );
+        assert_eq!(image.entry, Some(0x1000));
+    }
+
+    #[test]
+    fn o65_offset_skip_and_byte_relocations() {
+        let mut text = vec![0xea; 300];
+        // high byte of $10f0 at 0, low byte of $1010 at 299
+        text[0] = 0x10;
+        text[299] = 0x10;
+        let relocs = [
+            1,
+            RELOC_HIGH | SEG_TEXT,
+            0xf0,
+            // 255 only moves on by 254
+            255,
+            45,
+            RELOC_LOW | SEG_TEXT,
+            0,
+            // nothing in the data segment
+            0,
+        ];
+        let image = parse_o65(&o65(&text, &[], &[], &relocs), Some(0x1020)).unwrap();
+        let mut expected = text.clone();
+        // $10f0 + $20 carries into the high byte, which needs the low byte from the table
+        expected[0] = 0x11;
+        expected[299] = 0x30;
+        assert_eq!(image.segments, vec![segment(0x1020, &expected)]);
+    }
+
+    #[test]
+    fn o65_errors() {
+        let relocs = [2, RELOC_WORD | SEG_UNDEFINED, 0, 0, 0];
+        let file = o65(&[0x20, 0x00, 0x00], &[], &["chrout"], &relocs);
+        assert_eq!(
+            error(parse_o65(&file, None)),
+            "o65: undefined reference to chrout"
+        );
+
+        let relocs = [3, RELOC_WORD | SEG_TEXT, 0, 0];
+        let file = o65(&[0x20, 0x00, 0x10], &[], &[], &relocs);
+        assert_eq!(
+            error(parse_o65(&file, None)),
+            "o65: relocation at 2 outside the segment"
+        );
+
+        let file = o65(&[0xea; 2], &[], &[], &[0, 0]);
+        assert_eq!(
+            error(parse_o65(&file, Some(0xffff))),
+            "o65: segment at $ffff past $ffff"
+        );
+        assert_eq!(error(parse_o65(&file[..20], None)), "o65: truncated at 20");
+    }
+
+    #[test]
+    fn wozmon_stores_and_runs() {
+        let text = "300: a9 01\n: 85 2_02\n\n300R\nHELLO\n";
+        let image = parse_wozmon(text).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![segment(0x300, &[0xa9, 0x01, 0x85, 0x02])]
+        );
+        assert_eq!(image.entry, Some(0x300));
+
+        // only the last 4 digits count, and examining moves the store index
+        let image = parse_wozmon("12345: 01 02\n0400\n: 03\n").unwrap();
+        assert_eq!(
+            image.segments,
+            vec![segment(0x2345, &[0x01, 0x02]), segment(0x400, &[0x03])]
+        );
+        assert_eq!(image.entry, None);
+
+        // anything that isn't hex ends the line
+        let image = parse_wozmon("300: 01 XX 02\n").unwrap();
+        assert_eq!(image.segments, vec![segment(0x300, &[0x01])]);
+    }
+
+    #[test]
+    fn wozmon_line_length() {
+        let line = format!("300:{}\n", " 01".repeat(42));
+        assert_eq!(
+            error(parse_wozmon(&line)),
+            "line 1: longer than Wozmon's 127 characters"
+        );
+        // rubouts don't count
+        let line = format!("300:{}{}\n", " 01".repeat(42), "_".repeat(3));
+        assert_eq!(parse_wozmon(&line).unwrap().segments[0].data.len(), 41);
+    }
+}
@@ -0,0 +1,258 @@
+// Apple Cassette Interface: 256 byte ROM at $C100, I/O at $C000-$C0FF.
+//
+// Every access to $C000-$C0FF toggles the tape output flip-flop. The ROM also appears
+// in that page, and for addresses with A7 set the tape input replaces A0, so the ROM
+// code sees the input level as a change between the bytes at $C080/$C081.
+//
+// Instead of audio the tape is a WAV file: recordings start with the first toggle and
+// are written out after half a second without one. A loaded tape starts playing on the
+// first read of the input and is then sampled at the cpu clock.
+use std::{io, path::Path};
+
+use log::{error, info};
+
+use crate::mem::Device;
+
+use super::CLOCK_HZ;
+
+const SAMPLE_RATE: u32 = 44100;
+const AMPLITUDE: i16 = 0x3000;
+
+struct Recording {
+    start: u64,
+    level: bool,
+    toggles: Vec<u64>,
+}
+
+struct Tape {
+    levels: Vec<bool>,
+    rate: u64,
+    start: Option<u64>,
+}
+
+pub struct Aci {
+    rom: Vec<u8>,
+    clock: u64,
+    output: bool,
+    recording: Option<Recording>,
+    save_path: Option<String>,
+    saved: usize,
+    tape: Option<Tape>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Aci {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Self {
+            rom,
+            clock: 0,
+            output: false,
+            recording: None,
+            save_path: None,
+            saved: 0,
+            tape: None,
+        }
+    }
+
+    // where recordings go. The first one is written to 'path', later ones get a
+    // number appended: 'tape.wav', 'tape-1.wav', ...
+    pub fn set_save_path(&mut self, path: &str) {
+        self.save_path = Some(path.to_string());
+    }
+
+    // insert a tape, it starts playing when the ROM first reads the input
+    pub fn load_tape(&mut self, path: &str) -> io::Result<()> {
+        let reader =
+            hound::WavReader::open(path).map_err(|e| invalid(format!("{}: {}", path, e)))?;
+        let spec = reader.spec();
+        let channels = spec.channels as usize;
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .into_samples::<f32>()
+                .step_by(channels)
+                .collect::<Result<Vec<_>, _>>(),
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u32 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .into_samples::<i32>()
+                    .step_by(channels)
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect::<Result<Vec<_>, _>>()
+            }
+        }
+        .map_err(|e| invalid(format!("{}: {}", path, e)))?;
+        self.tape = Some(Tape {
+            levels: levels(&samples),
+            rate: spec.sample_rate as u64,
+            start: None,
+        });
+        info!(
+            "tape {}: {} samples at {} Hz",
+            path,
+            samples.len(),
+            spec.sample_rate
+        );
+        Ok(())
+    }
+
+    pub fn rewind(&mut self) {
+        if let Some(tape) = &mut self.tape {
+            tape.start = None;
+        }
+    }
+
+    fn input(&mut self) -> bool {
+        let clock = self.clock;
+        let Some(tape) = &mut self.tape else {
+            return false;
+        };
+        let start = *tape.start.get_or_insert(clock);
+        let pos = ((clock - start) * tape.rate / CLOCK_HZ) as usize;
+        // at the end of the tape the level stays where it was
+        tape.levels
+            .get(pos)
+            .or(tape.levels.last())
+            .copied()
+            .unwrap_or(false)
+    }
+
+    // only accesses below $C080 are recorded: the ROM reads the input at $C081, which
+    // toggles the output as well
+    fn toggle(&mut self, addr: u16) {
+        let (clock, level) = (self.clock, self.output);
+        self.output = !self.output;
+        if addr & 0x80 != 0 || self.save_path.is_none() {
+            return;
+        }
+        let recording = self.recording.get_or_insert_with(|| Recording {
+            start: clock,
+            level,
+            toggles: Vec::new(),
+        });
+        recording.toggles.push(clock);
+    }
+
+    fn next_save_path(&mut self) -> Option<String> {
+        let path = self.save_path.as_ref()?;
+        let name = if self.saved == 0 {
+            path.clone()
+        } else {
+            let p = Path::new(path);
+            let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or("tape");
+            let name = format!("{}-{}.wav", stem, self.saved);
+            p.with_file_name(name).to_string_lossy().to_string()
+        };
+        self.saved += 1;
+        Some(name)
+    }
+
+    // write the current recording, if any
+    pub fn finish_recording(&mut self) -> io::Result<()> {
+        let Some(recording) = self.recording.take() else {
+            return Ok(());
+        };
+        let Some(path) = self.next_save_path() else {
+            return Ok(());
+        };
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec)
+            .map_err(|e| invalid(format!("{}: {}", path, e)))?;
+        // a bit of silence after the last edge
+        let end = recording.toggles.last().copied().unwrap_or(recording.start) + CLOCK_HZ / 10;
+        let mut level = recording.level;
+        let mut toggles = recording.toggles.iter().peekable();
+        let mut i = 0u64;
+        loop {
+            let t = recording.start + i * CLOCK_HZ / SAMPLE_RATE as u64;
+            if t > end {
+                break;
+            }
+            while toggles.next_if(|toggle| **toggle <= t).is_some() {
+                level = !level;
+            }
+            let sample = if level { AMPLITUDE } else { -AMPLITUDE };
+            writer
+                .write_sample(sample)
+                .map_err(|e| invalid(format!("{}: {}", path, e)))?;
+            i += 1;
+        }
+        writer
+            .finalize()
+            .map_err(|e| invalid(format!("{}: {}", path, e)))?;
+        info!("tape saved to {} ({} samples)", path, i);
+        Ok(())
+    }
+}
+
+// square wave from the (possibly noisy, DC shifted) samples of a digitized tape
+fn levels(samples: &[f32]) -> Vec<bool> {
+    if samples.is_empty() {
+        return Vec::new();
+    }
+    let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+    let peak = samples.iter().map(|s| (s - mean).abs()).fold(0.0, f32::max);
+    let hysteresis = peak * 0.1;
+    let mut level = false;
+    samples
+        .iter()
+        .map(|s| {
+            if s - mean > hysteresis {
+                level = true;
+            } else if s - mean < -hysteresis {
+                level = false;
+            }
+            level
+        })
+        .collect()
+}
+
+impl Device for Aci {
+    fn read(&mut self, addr: u16) -> u8 {
+        if addr & 0xff00 != 0xc000 {
+            return self.peek(addr);
+        }
+        self.toggle(addr);
+        let addr = if addr & 0x80 != 0 {
+            (addr & !1) | self.input() as u16
+        } else {
+            addr
+        };
+        self.peek(addr)
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        self.rom.get(addr as usize & 0xff).copied().unwrap_or(0)
+    }
+    fn write(&mut self, addr: u16, _value: u8) {
+        if addr & 0xff00 == 0xc000 {
+            self.toggle(addr);
+        }
+    }
+    fn tick(&mut self, cycles: u32) {
+        self.clock += cycles as u64;
+        let idle = match &self.recording {
+            Some(recording) => recording.toggles.last().copied().unwrap_or(0) + CLOCK_HZ / 2,
+            None => return,
+        };
+        if self.clock > idle {
+            if let Err(e) = self.finish_recording() {
+                error!("saving tape failed: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Aci {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish_recording() {
+            error!("saving tape failed: {}", e);
+        }
+    }
+}
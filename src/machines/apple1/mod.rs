@@ -0,0 +1,535 @@
+// Apple-1: Wozmon ROM at $FF00, Integer BASIC at $E000, RAM from $0000 and the PIA
+// connecting keyboard and terminal at $D010-$D013. Optionally the cassette interface
+// card at $C000.
+//
+// The terminal is emulated as on the real machine: 40x24 uppercase characters,
+// scrolling at the bottom, accepting about 60 characters per second. While a
+// character is being displayed bit 7 of DSP reads as 1 (busy).
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Stdout, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use termion::{
+    event::Key,
+    raw::{IntoRawMode, RawTerminal},
+};
+
+pub mod aci;
+
+use crate::{
+    cpu::StepInfo,
+    dbg::Dbg,
+    input::InputSource,
+    mem::{Device, Memory},
+    reg::Registers,
+    screenshot::{self, Image, Video},
+};
+
+use self::aci::Aci;
+
+pub const CLOCK_HZ: u64 = 1_022_727;
+pub const COLUMNS: usize = 40;
+pub const ROWS: usize = 24;
+
+pub struct Config {
+    // RAM from $0000 in bytes, 4K on the original board, up to 48K. $E000-$EFFF is
+    // always RAM, that's where BASIC lives.
+    pub ram_size: usize,
+    pub wozmon: String,
+    pub basic: Option<String>,
+    // display output rate, 0 for no limit
+    pub chars_per_second: u64,
+    // ACI ROM, no cassette interface without it
+    pub aci_rom: Option<String>,
+    // WAV files to load from / save to
+    pub tape_in: Option<String>,
+    pub tape_out: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ram_size: 0x1000,
+            wozmon: "6502-test-code/wozmon.bin".into(),
+            basic: Some("6502-test-code/apple1basic.bin".into()),
+            chars_per_second: 60,
+            aci_rom: None,
+            tape_in: None,
+            tape_out: None,
+        }
+    }
+}
+
+pub struct Screen {
+    rows: [[u8; COLUMNS]; ROWS],
+    row: usize,
+    col: usize,
+    dirty: bool,
+    // everything displayed since the last take_output(), lines end with '\n'
+    output: Vec<u8>,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self {
+            rows: [[b' '; COLUMNS]; ROWS],
+            row: 0,
+            col: 0,
+            dirty: true,
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Screen {
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < ROWS {
+            self.row += 1;
+        } else {
+            self.rows.copy_within(1.., 0);
+            self.rows[ROWS - 1] = [b' '; COLUMNS];
+        }
+    }
+    pub fn putc(&mut self, c: u8) {
+        let c = match c & 0x7f {
+            0x0d => {
+                self.newline();
+                self.output.push(b'\n');
+                self.dirty = true;
+                return;
+            }
+            // no lower case in the character generator
+            c @ 0x60..=0x7e => c - 0x20,
+            c @ 0x20..=0x5f => c,
+            // other control characters are ignored
+            _ => return,
+        };
+        self.rows[self.row][self.col] = c;
+        self.output.push(c);
+        self.col += 1;
+        if self.col == COLUMNS {
+            self.newline();
+        }
+        self.dirty = true;
+    }
+    pub fn rows(&self) -> &[[u8; COLUMNS]; ROWS] {
+        &self.rows
+    }
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.col, self.row)
+    }
+    pub fn text(&self) -> String {
+        self.rows
+            .iter()
+            .map(|row| String::from_utf8_lossy(row).trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+    // true if the screen changed since the last call
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+}
+
+// MC6821 as wired on the Apple-1: port A is the keyboard (CA1 strobe), port B the
+// display (PB7 busy input, CB2 strobe). Selected by any $Dxxx address with A4 set,
+// A0/A1 select the register.
+pub struct Pia {
+    kbd: u8,
+    cra: u8,
+    ddrb: u8,
+    dsp: u8,
+    crb: u8,
+    // character waiting for the display
+    pending: Option<u8>,
+    clock: u64,
+    ready_at: u64,
+    cycles_per_char: u64,
+    screen: Screen,
+}
+
+impl Pia {
+    pub fn new(chars_per_second: u64) -> Self {
+        Self {
+            kbd: 0,
+            cra: 0,
+            ddrb: 0,
+            dsp: 0,
+            crb: 0,
+            pending: None,
+            clock: 0,
+            ready_at: 0,
+            cycles_per_char: CLOCK_HZ.checked_div(chars_per_second).unwrap_or(0),
+            screen: Screen::default(),
+        }
+    }
+    // a key press. Returns false if the previous key was not read yet.
+    pub fn key(&mut self, c: u8) -> bool {
+        if self.key_pending() {
+            return false;
+        }
+        let c = match c {
+            b'\n' => b'\r',
+            // rubout
+            0x08 | 0x7f => b'_',
+            c => c.to_ascii_uppercase(),
+        };
+        self.kbd = c | 0x80;
+        self.cra |= 0x80;
+        true
+    }
+    pub fn key_pending(&self) -> bool {
+        self.cra & 0x80 != 0
+    }
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+    pub fn screen_mut(&mut self) -> &mut Screen {
+        &mut self.screen
+    }
+    // the character ROM and terminal are not emulated beyond the screen contents
+    fn display(&mut self) {
+        if let Some(c) = self.pending {
+            if self.clock >= self.ready_at {
+                self.screen.putc(c);
+                self.pending = None;
+                self.ready_at = self.clock + self.cycles_per_char;
+            }
+        }
+    }
+}
+
+impl Device for Pia {
+    fn read(&mut self, addr: u16) -> u8 {
+        let v = self.peek(addr);
+        if addr & 0x13 == 0x10 {
+            // reading the port clears the strobe flag
+            self.cra &= 0x7f;
+        }
+        v
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        if addr & 0x10 == 0 {
+            return 0;
+        }
+        match addr & 3 {
+            0 => self.kbd,
+            1 => self.cra,
+            2 if self.crb & 0x04 != 0 => {
+                (self.dsp & 0x7f) | if self.pending.is_some() { 0x80 } else { 0 }
+            }
+            2 => self.ddrb,
+            _ => self.crb,
+        }
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr & 0x10 == 0 {
+            return;
+        }
+        match addr & 3 {
+            // the keyboard port is input only
+            0 => (),
+            // flag bits are read only
+            1 => self.cra = (self.cra & 0xc0) | (value & 0x3f),
+            2 if self.crb & 0x04 != 0 => {
+                self.dsp = value;
+                self.pending = Some(value & 0x7f);
+                self.display();
+            }
+            2 => self.ddrb = value,
+            _ => self.crb = (self.crb & 0xc0) | (value & 0x3f),
+        }
+    }
+    fn tick(&mut self, cycles: u32) {
+        self.clock += cycles as u64;
+        self.display();
+    }
+}
+
+pub struct Apple1 {
+    pia: Rc<RefCell<Pia>>,
+    aci: Option<Rc<RefCell<Aci>>>,
+}
+
+fn load_rom(name: &str, max_len: usize) -> io::Result<Vec<u8>> {
+    let data =
+        std::fs::read(name).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+    if data.len() > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {} bytes, at most {} fit", name, data.len(), max_len),
+        ));
+    }
+    Ok(data)
+}
+
+impl Apple1 {
+    // the machine and its memory map with the ROMs loaded
+    pub fn new(config: &Config) -> io::Result<(Apple1, Memory)> {
+        let mut ram = vec![0u8; 0x10000];
+        let wozmon = load_rom(&config.wozmon, 0x100)?;
+        ram[0x10000 - wozmon.len()..].copy_from_slice(&wozmon);
+        if let Some(basic) = &config.basic {
+            let basic = load_rom(basic, 0x1000)?;
+            ram[0xe000..0xe000 + basic.len()].copy_from_slice(&basic);
+        }
+        let pia = Rc::new(RefCell::new(Pia::new(config.chars_per_second)));
+        let mut mem = Memory::new(ram);
+        let ram_size = config.ram_size.min(0xd000);
+        if ram_size < 0xd000 {
+            mem.unmap(ram_size as u16, 0xcfff);
+        }
+        mem.map_device(0xd000, 0xdfff, pia.clone());
+        mem.unmap(0xf000, 0xfeff);
+        mem.map_rom(0xff00, 0xffff);
+        let aci = match &config.aci_rom {
+            Some(rom) => {
+                let mut aci = Aci::new(load_rom(rom, 0x100)?);
+                if let Some(tape) = &config.tape_in {
+                    aci.load_tape(tape)?;
+                }
+                if let Some(tape) = &config.tape_out {
+                    aci.set_save_path(tape);
+                }
+                let aci = Rc::new(RefCell::new(aci));
+                mem.map_device(0xc000, 0xc1ff, aci.clone());
+                Some(aci)
+            }
+            None => None,
+        };
+        Ok((Apple1 { pia, aci }, mem))
+    }
+    pub fn pia(&self) -> Rc<RefCell<Pia>> {
+        self.pia.clone()
+    }
+    pub fn aci(&self) -> Option<Rc<RefCell<Aci>>> {
+        self.aci.clone()
+    }
+    // screen on the host terminal, keys from 'input'
+    pub fn terminal(&self, input: Box<dyn InputSource>) -> Terminal {
+        Terminal::new(self.pia.clone(), input)
+    }
+    // no terminal, keys from 'input'
+    pub fn headless(&self, input: Box<dyn InputSource>) -> Headless {
+        Headless::new(self.pia.clone(), input)
+    }
+}
+
+// the 40x24 text screen for screenshots, white on black
+pub struct Display {
+    pia: Rc<RefCell<Pia>>,
+}
+
+impl Display {
+    pub fn new(pia: Rc<RefCell<Pia>>) -> Self {
+        Self { pia }
+    }
+}
+
+impl Video for Display {
+    fn capture(&self, _mem: &Memory) -> Image {
+        let pia = self.pia.borrow();
+        let rows = pia
+            .screen()
+            .rows()
+            .iter()
+            .map(|row| row.iter().map(|c| (*c, false)).collect())
+            .collect::<Vec<_>>();
+        screenshot::text_image(&rows, (0xff, 0xff, 0xff), (0, 0, 0))
+    }
+}
+
+// Polls 'input' and passes the oldest queued key to the PIA once it took the previous
+// one. Returns true on Ctrl-C.
+fn feed_keys(
+    input: &mut dyn InputSource,
+    cycles: u64,
+    keys: &mut VecDeque<u8>,
+    pia: &RefCell<Pia>,
+) -> bool {
+    let mut quit = false;
+    for key in input.poll(cycles) {
+        match key {
+            Key::Ctrl('c') => quit = true,
+            Key::Ctrl(c) if c.is_ascii_lowercase() => keys.push_back(c as u8 & 0x1f),
+            Key::Char(c) if c.is_ascii() => keys.push_back(c as u8),
+            Key::Esc => keys.push_back(0x1b),
+            Key::Backspace => keys.push_back(0x08),
+            _ => (),
+        }
+    }
+    if let Some(c) = keys.front() {
+        if pia.borrow_mut().key(*c) {
+            keys.pop_front();
+        }
+    }
+    quit
+}
+
+// Runs the machine in real time on the host terminal: keys go to the PIA (lower case
+// is converted, Ctrl-C quits since ESC is a key Wozmon uses) and the screen is
+// redrawn up to 60 times per second.
+pub struct Terminal {
+    pia: Rc<RefCell<Pia>>,
+    stdout: RawTerminal<Stdout>,
+    input: Box<dyn InputSource>,
+    keys: VecDeque<u8>,
+    start: Instant,
+    cycles: u64,
+    next_frame: u64,
+    quit: bool,
+}
+
+impl Terminal {
+    pub fn new(pia: Rc<RefCell<Pia>>, input: Box<dyn InputSource>) -> Self {
+        let mut stdout = std::io::stdout().into_raw_mode().unwrap();
+        write!(stdout, "{}", termion::clear::All).unwrap();
+        Self {
+            pia,
+            stdout,
+            input,
+            keys: VecDeque::new(),
+            start: Instant::now(),
+            cycles: 0,
+            next_frame: 0,
+            quit: false,
+        }
+    }
+    fn draw(&mut self) {
+        let mut pia = self.pia.borrow_mut();
+        let screen = pia.screen_mut();
+        if !screen.take_dirty() {
+            return;
+        }
+        for (y, row) in screen.rows().iter().enumerate() {
+            write!(
+                self.stdout,
+                "{}{}",
+                termion::cursor::Goto(1, y as u16 + 1),
+                String::from_utf8_lossy(row)
+            )
+            .unwrap();
+        }
+        let (col, row) = screen.cursor();
+        write!(
+            self.stdout,
+            "{}",
+            termion::cursor::Goto(col as u16 + 1, row as u16 + 1)
+        )
+        .unwrap();
+        self.stdout.flush().unwrap();
+    }
+    fn frame(&mut self) {
+        self.quit |= feed_keys(&mut *self.input, self.cycles, &mut self.keys, &self.pia);
+        self.draw();
+        // keep emulated time in line with the wall clock
+        let due = Duration::from_micros(self.cycles * 1_000_000 / CLOCK_HZ);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        write!(self.stdout, "{}", termion::cursor::Goto(1, ROWS as u16 + 1)).unwrap();
+    }
+}
+
+impl Dbg for Terminal {
+    fn step(&mut self, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        if self.quit {
+            return true;
+        }
+        if self.cycles >= self.next_frame {
+            self.next_frame = self.cycles + CLOCK_HZ / 60;
+            self.frame();
+        }
+        false
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        false
+    }
+}
+
+// Runs the machine as fast as possible without a terminal, for tests and pipes. The
+// output is collected (see output()) and optionally copied to a stream as it appears.
+// Stops on Ctrl-C from the input.
+pub struct Headless {
+    pia: Rc<RefCell<Pia>>,
+    input: Box<dyn InputSource>,
+    keys: VecDeque<u8>,
+    echo: Option<Box<dyn Write>>,
+    output: String,
+    cycles: u64,
+    next_frame: u64,
+    quit: bool,
+}
+
+impl Headless {
+    pub fn new(pia: Rc<RefCell<Pia>>, input: Box<dyn InputSource>) -> Self {
+        Self {
+            pia,
+            input,
+            keys: VecDeque::new(),
+            echo: None,
+            output: String::new(),
+            cycles: 0,
+            next_frame: 0,
+            quit: false,
+        }
+    }
+    pub fn with_echo(mut self, out: Box<dyn Write>) -> Self {
+        self.echo = Some(out);
+        self
+    }
+    // all output so far, lines end with '\n'
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+    fn collect_output(&mut self) {
+        let out = self.pia.borrow_mut().screen_mut().take_output();
+        if out.is_empty() {
+            return;
+        }
+        if let Some(echo) = &mut self.echo {
+            echo.write_all(&out).unwrap();
+            echo.flush().unwrap();
+        }
+        self.output.push_str(&String::from_utf8_lossy(&out));
+    }
+}
+
+impl Drop for Headless {
+    fn drop(&mut self) {
+        self.collect_output();
+    }
+}
+
+impl Dbg for Headless {
+    fn step(&mut self, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        if self.quit {
+            return true;
+        }
+        // same key timing as the terminal, so scripts behave the same in both
+        if self.cycles >= self.next_frame {
+            self.next_frame = self.cycles + CLOCK_HZ / 60;
+            self.quit |= feed_keys(&mut *self.input, self.cycles, &mut self.keys, &self.pia);
+            self.collect_output();
+        }
+        false
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        false
+    }
+}
@@ -0,0 +1,353 @@
+// KERNAL calls serviced on the host, so PRG files run without the ROMs: a JSR (or JMP)
+// to one of the entry points below is handled here and returns like the KERNAL would.
+//
+// Character I/O is the console: CHROUT prints to stdout, CHRIN reads a line from stdin,
+// GETIN takes what stdin has without waiting. LOAD and SAVE use files in a host
+// directory, names are matched ignoring case, with or without '.prg' and with a
+// trailing '*' as wildcard.
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+};
+
+use log::{info, warn};
+
+use crate::{cpu::Cpu, dbg::Dbg, devices::acia, mem::Memory, reg::Registers};
+
+pub const READST: u16 = 0xffb7;
+pub const SETLFS: u16 = 0xffba;
+pub const SETNAM: u16 = 0xffbd;
+pub const CLRCHN: u16 = 0xffcc;
+pub const CHRIN: u16 = 0xffcf;
+pub const CHROUT: u16 = 0xffd2;
+pub const LOAD: u16 = 0xffd5;
+pub const SAVE: u16 = 0xffd8;
+pub const GETIN: u16 = 0xffe4;
+pub const CLALL: u16 = 0xffe7;
+// the program returns here when it's done, it's where RTS from a SYS lands. With a BASIC
+// ROM this is NEWSTT, which also runs for every statement.
+pub const EXIT: u16 = 0xa7ae;
+
+// KERNAL variables the calls keep their arguments in
+const STATUS: u16 = 0x90;
+const FNLEN: u16 = 0xb7;
+const LA: u16 = 0xb8;
+const SA: u16 = 0xb9;
+const FA: u16 = 0xba;
+const FNADR: u16 = 0xbb;
+const EAL: u16 = 0xae;
+
+// KERNAL error codes, returned in A with carry set
+const FILE_NOT_FOUND: u8 = 4;
+const MISSING_FILE_NAME: u8 = 8;
+
+// status bits
+const EOF: u8 = 0x40;
+
+// the KERNAL jump table
+const JUMP_TABLE: std::ops::RangeInclusive<u16> = 0xff81..=0xfff3;
+
+// PETSCII to the console. 'lower' is the upper/lower case character set, where
+// $41-$5A are lower case letters.
+pub fn petscii_to_char(c: u8, lower: bool) -> Option<char> {
+    Some(match c {
+        13 => '\n',
+        0x41..=0x5a if lower => (c as char).to_ascii_lowercase(),
+        0xc1..=0xda => (c - 0x80) as char,
+        0x61..=0x7a => (c - 0x20) as char,
+        0x5c => '£',
+        0x5e => '↑',
+        0x5f => '←',
+        0x20..=0x5d => c as char,
+        _ => return None,
+    })
+}
+
+pub fn char_to_petscii(c: u8, lower: bool) -> u8 {
+    match c {
+        b'\n' => 13,
+        b'a'..=b'z' => c - 0x20,
+        b'A'..=b'Z' if lower => c + 0x80,
+        _ => c,
+    }
+}
+
+fn read_name(mem: &Memory) -> String {
+    let len = mem.load(FNLEN) as u16;
+    let addr = mem.load16(FNADR);
+    (0..len)
+        .map(|i| mem.load(addr + i))
+        .map(|c| petscii_to_char(c, false).unwrap_or('_'))
+        .collect()
+}
+
+// the file in 'dir' a KERNAL file name means
+fn find_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    let name = name.to_ascii_lowercase();
+    let matches = |file: &str| {
+        let file = file.to_ascii_lowercase();
+        let stem = file.strip_suffix(".prg").unwrap_or(&file);
+        match name.strip_suffix('*') {
+            Some(prefix) => file.starts_with(prefix),
+            None => file == name || stem == name,
+        }
+    };
+    let mut files = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|f| matches(f))
+        .collect::<Vec<_>>();
+    files.sort();
+    files.first().map(|f| dir.join(f))
+}
+
+fn store_prg(mem: &mut Memory, data: &[u8], at: Option<u16>) -> Option<(u16, u16)> {
+    if data.len() < 2 {
+        return None;
+    }
+    let start = at.unwrap_or(u16::from_le_bytes([data[0], data[1]]));
+    let mut addr = start;
+    for b in &data[2..] {
+        mem.store(addr, *b);
+        addr = addr.wrapping_add(1);
+    }
+    Some((start, addr))
+}
+
+// sets the cpu up as if BASIC had called 'entry' with SYS. Returns the stack pointer
+// after the program's final RTS, for Traps::with_exit.
+pub fn start(cpu: &mut Cpu, entry: u16) -> u16 {
+    info!("SYS {}", entry);
+    let ret = EXIT - 1;
+    let reg = cpu.get_reg_mut();
+    let sp = reg.sp;
+    reg.sp -= 1;
+    let hi = 0x100 + reg.sp;
+    reg.sp -= 1;
+    let lo = 0x100 + reg.sp;
+    cpu.get_mem_mut().store(hi, (ret >> 8) as u8);
+    cpu.get_mem_mut().store(lo, ret as u8);
+    cpu.set_pc(entry);
+    sp
+}
+
+pub struct Traps {
+    dir: PathBuf,
+    stdin: Option<Receiver<u8>>,
+    // the rest of the line CHRIN is returning
+    line: VecDeque<u8>,
+    lower: bool,
+    // the program started by start() is done when it gets to EXIT with this stack pointer
+    exit_sp: Option<u16>,
+    // without BASIC nothing else runs at EXIT
+    basic: bool,
+}
+
+impl Traps {
+    // LOAD and SAVE work in 'dir'
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+            stdin: None,
+            line: VecDeque::new(),
+            lower: false,
+            exit_sp: None,
+            basic: true,
+        }
+    }
+    // stop when the program returns, 'sp' is what start() returned
+    pub fn with_exit(mut self, sp: u16) -> Self {
+        self.exit_sp = Some(sp);
+        self
+    }
+    pub fn with_basic(mut self, basic: bool) -> Self {
+        self.basic = basic;
+        self
+    }
+    fn stdin(&mut self) -> &Receiver<u8> {
+        io::stdout().flush().unwrap();
+        self.stdin
+            .get_or_insert_with(|| acia::spawn_reader(io::stdin()))
+    }
+
+    fn chrout(&mut self, c: u8) {
+        match c {
+            14 => self.lower = true,
+            142 => self.lower = false,
+            _ => {
+                if let Some(c) = petscii_to_char(c, self.lower) {
+                    print!("{}", c);
+                    if c == '\n' {
+                        io::stdout().flush().unwrap();
+                    }
+                }
+            }
+        }
+    }
+    // a character of the input line, waits for a line if there is none
+    fn chrin(&mut self, mem: &mut Memory) -> u8 {
+        if self.line.is_empty() {
+            loop {
+                match self.stdin().recv() {
+                    Ok(b'\r') => continue,
+                    Ok(b'\n') => break,
+                    Ok(c) => self.line.push_back(c),
+                    Err(_) => {
+                        mem.store(STATUS, mem.load(STATUS) | EOF);
+                        break;
+                    }
+                }
+            }
+            self.line.push_back(b'\n');
+        }
+        let c = self.line.pop_front().unwrap();
+        char_to_petscii(c, self.lower)
+    }
+    fn getin(&mut self) -> u8 {
+        match self.stdin().try_recv() {
+            Ok(b'\r') | Err(_) => 0,
+            Ok(c) => char_to_petscii(c, self.lower),
+        }
+    }
+    // A: 0 load, 1 verify. The secondary address says whether to use the file's own
+    // address (1) or X/Y (0).
+    fn load(&mut self, reg: &Registers, mem: &mut Memory) -> Result<u16, u8> {
+        let name = read_name(mem);
+        if name.is_empty() {
+            return Err(MISSING_FILE_NAME);
+        }
+        let Some(path) = find_file(&self.dir, &name) else {
+            warn!("LOAD \"{}\": not found in {}", name, self.dir.display());
+            return Err(FILE_NOT_FOUND);
+        };
+        let data = fs::read(&path).map_err(|_| FILE_NOT_FOUND)?;
+        let at = (mem.load(SA) == 0).then_some(u16::from_le_bytes([reg.x, reg.y]));
+        if reg.a != 0 {
+            info!("VERIFY \"{}\" isn't checked", name);
+            return Ok(at.unwrap_or(0));
+        }
+        let (start, end) = store_prg(mem, &data, at).ok_or(FILE_NOT_FOUND)?;
+        info!(
+            "LOAD \"{}\" from {}: ${:04x}-${:04x}",
+            name,
+            path.display(),
+            start,
+            end
+        );
+        Ok(end)
+    }
+    // A: zero page pointer to the start, X/Y: end (exclusive)
+    fn save(&mut self, reg: &Registers, mem: &Memory) -> Result<(), u8> {
+        let name = read_name(mem);
+        if name.is_empty() {
+            return Err(MISSING_FILE_NAME);
+        }
+        // the name is a file in 'dir', never a path out of it
+        if name.contains(['/', '\\']) || name.contains("..") {
+            warn!("SAVE \"{}\": not a file name", name);
+            return Err(FILE_NOT_FOUND);
+        }
+        let start = mem.load16(reg.a as u16);
+        let end = u16::from_le_bytes([reg.x, reg.y]);
+        let mut data = start.to_le_bytes().to_vec();
+        data.extend((start..end).map(|a| mem.load(a)));
+        let mut file = name.to_ascii_lowercase();
+        if !file.contains('.') {
+            file += ".prg";
+        }
+        let path = self.dir.join(file);
+        fs::write(&path, data).map_err(|e| {
+            warn!("SAVE \"{}\": {}", name, e);
+            FILE_NOT_FOUND
+        })?;
+        info!(
+            "SAVE \"{}\" to {}: ${:04x}-${:04x}",
+            name,
+            path.display(),
+            start,
+            end
+        );
+        Ok(())
+    }
+
+    // services the call at reg.pc, false if it isn't one of ours
+    fn call(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        let mut result = Ok(());
+        match reg.pc {
+            CHROUT => self.chrout(reg.a),
+            CHRIN => reg.a = self.chrin(mem),
+            GETIN => reg.a = self.getin(),
+            READST => reg.a = mem.load(STATUS),
+            SETLFS => {
+                mem.store(LA, reg.a);
+                mem.store(FA, reg.x);
+                mem.store(SA, reg.y);
+            }
+            SETNAM => {
+                mem.store(FNLEN, reg.a);
+                mem.store(FNADR, reg.x);
+                mem.store(FNADR + 1, reg.y);
+            }
+            LOAD => {
+                result = self.load(reg, mem).map(|end| {
+                    [reg.x, reg.y] = end.to_le_bytes();
+                    mem.store16(EAL, end);
+                })
+            }
+            SAVE => result = self.save(reg, mem),
+            // only the console, nothing to reset
+            CLRCHN | CLALL => (),
+            _ => return false,
+        }
+        match result {
+            Ok(()) => reg.sr.c = false,
+            Err(code) => {
+                reg.a = code;
+                reg.sr.c = true;
+            }
+        }
+        true
+    }
+}
+
+// the RTS at the end of the call
+fn return_from(reg: &mut Registers, mem: &Memory) {
+    let lo = mem.load(0x100 + reg.sp) as u16;
+    reg.sp += 1;
+    let hi = mem.load(0x100 + reg.sp) as u16;
+    reg.sp += 1;
+    reg.pc = (lo | hi << 8).wrapping_add(1);
+}
+
+impl Drop for Traps {
+    fn drop(&mut self) {
+        io::stdout().flush().unwrap();
+    }
+}
+
+impl Dbg for Traps {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        if reg.pc == EXIT && (!self.basic || self.exit_sp == Some(reg.sp)) {
+            info!("program returned");
+            return true;
+        }
+        if !JUMP_TABLE.contains(&reg.pc) {
+            return false;
+        }
+        if self.call(reg, mem) {
+            return_from(reg, mem);
+            return false;
+        }
+        // a jump table entry of a real KERNAL starts with JMP
+        if mem.load(reg.pc) != 0x4c {
+            warn!("KERNAL call ${:04x} isn't trapped", reg.pc);
+            return true;
+        }
+        false
+    }
+}
@@ -0,0 +1,542 @@
+// Commodore 64, the parts the CPU sees: 64K RAM, the 6510's I/O port at $00/$01
+// switching BASIC ($A000), KERNAL ($E000), character ROM and I/O ($D000) in and out,
+// and the two 6526 CIAs at $DC00 (IRQ) and $DD00 (NMI). There is no graphics or sound:
+// the VIC-II is its registers and the raster counter, the SID ignores everything.
+//
+// The text screen is read from $0400 and typing goes into the KERNAL's keyboard buffer,
+// which is enough for BASIC and most machine code utilities.
+pub mod kernal;
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Stdout, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use termion::{
+    event::Key,
+    raw::{IntoRawMode, RawTerminal},
+};
+
+use crate::{
+    cpu::StepInfo,
+    dbg::Dbg,
+    devices::cia::Cia,
+    input::InputSource,
+    mem::{Device, Memory, Switch},
+    reg::Registers,
+    screenshot::{self, Image, Video},
+};
+
+// PAL
+pub const CLOCK_HZ: u64 = 985_248;
+pub const SCREEN: u16 = 0x0400;
+pub const COLUMNS: usize = 40;
+pub const ROWS: usize = 25;
+
+const CYCLES_PER_LINE: u32 = 63;
+const LINES: u16 = 312;
+
+// KERNAL keyboard buffer, its length and size
+const KEYD: u16 = 0x0277;
+const NDX: u16 = 0x00c6;
+const XMAX: u16 = 0x0289;
+// cursor column and row
+const PNTR: u16 = 0x00d3;
+const TBLX: u16 = 0x00d6;
+
+pub struct Config {
+    // without a KERNAL, programs need its calls trapped, see kernal::Traps
+    pub kernal: Option<String>,
+    pub basic: Option<String>,
+    // only needed by programs reading the character ROM
+    pub chargen: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kernal: Some("6502-test-code/kernal.bin".into()),
+            basic: Some("6502-test-code/basic.bin".into()),
+            chargen: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Banks {
+    basic: Switch,
+    kernal: Switch,
+    chargen: Switch,
+    io: Switch,
+}
+
+// the 6510 I/O port: data direction at $00, data at $01. Bits 0-2 are LORAM, HIRAM and
+// CHAREN, pins set as inputs are pulled up. Bit 4 is the cassette switch (not pressed).
+pub struct ProcessorPort {
+    ddr: u8,
+    data: u8,
+    banks: Banks,
+}
+
+impl ProcessorPort {
+    fn new(banks: Banks) -> Self {
+        let mut port = Self {
+            ddr: 0,
+            data: 0,
+            banks,
+        };
+        port.update_banks();
+        port
+    }
+    fn update_banks(&mut self) {
+        let lines = self.data | !self.ddr;
+        let loram = lines & 0x01 != 0;
+        let hiram = lines & 0x02 != 0;
+        let charen = lines & 0x04 != 0;
+        self.banks.basic.set(loram && hiram);
+        self.banks.kernal.set(hiram);
+        // with both LORAM and HIRAM low it's all RAM
+        self.banks.io.set((loram || hiram) && charen);
+        self.banks.chargen.set((loram || hiram) && !charen);
+    }
+}
+
+impl Device for ProcessorPort {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        if addr & 1 == 0 {
+            self.ddr
+        } else {
+            (self.data & self.ddr) | (0x17 & !self.ddr)
+        }
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr & 1 == 0 {
+            self.ddr = value;
+        } else {
+            self.data = value;
+        }
+        self.update_banks();
+    }
+}
+
+// VIC-II registers, mirrored every 64 bytes. Only the raster counter and the raster
+// interrupt do anything.
+pub struct Vic {
+    regs: [u8; 0x40],
+    raster: u16,
+    line_cycles: u32,
+    compare: u16,
+    flags: u8,
+    mask: u8,
+}
+
+impl Default for Vic {
+    fn default() -> Self {
+        Self {
+            regs: [0; 0x40],
+            raster: 0,
+            line_cycles: 0,
+            compare: 0,
+            flags: 0,
+            mask: 0,
+        }
+    }
+}
+
+impl Device for Vic {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        match addr & 0x3f {
+            0x11 => (self.regs[0x11] & 0x7f) | ((self.raster >> 1) & 0x80) as u8,
+            0x12 => self.raster as u8,
+            0x19 => {
+                let irq = if self.flags & self.mask != 0 { 0x80 } else { 0 };
+                self.flags | 0x70 | irq
+            }
+            0x1a => self.mask | 0xf0,
+            // unused registers
+            0x2f..=0x3f => 0xff,
+            r => self.regs[r as usize],
+        }
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        let r = (addr & 0x3f) as usize;
+        match r {
+            0x11 => self.compare = (self.compare & 0xff) | ((value as u16 & 0x80) << 1),
+            0x12 => self.compare = (self.compare & 0x100) | value as u16,
+            // writing 1 acknowledges
+            0x19 => self.flags &= !value & 0x0f,
+            0x1a => self.mask = value & 0x0f,
+            _ => (),
+        }
+        self.regs[r] = value;
+    }
+    fn tick(&mut self, cycles: u32) {
+        self.line_cycles += cycles;
+        while self.line_cycles >= CYCLES_PER_LINE {
+            self.line_cycles -= CYCLES_PER_LINE;
+            self.raster = (self.raster + 1) % LINES;
+            if self.raster == self.compare {
+                self.flags |= 0x01;
+            }
+        }
+    }
+    fn irq(&self) -> bool {
+        self.flags & self.mask != 0
+    }
+}
+
+// 1K of 4 bit color RAM, the upper bits read as noise on the real thing
+pub struct ColorRam([u8; 0x400]);
+
+impl Device for ColorRam {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        self.0[(addr & 0x3ff) as usize] | 0xf0
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        self.0[(addr & 0x3ff) as usize] = value & 0x0f;
+    }
+}
+
+// SID and the expansion port I/O areas: writes go nowhere, reads are 0
+pub struct Silent;
+
+impl Device for Silent {
+    fn read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+    fn peek(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn write(&mut self, _addr: u16, _value: u8) {}
+}
+
+fn read_rom(path: &str, size: usize) -> io::Result<Vec<u8>> {
+    let rom =
+        std::fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    if rom.len() != size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {} bytes, expected {}", path, rom.len(), size),
+        ));
+    }
+    Ok(rom)
+}
+
+pub struct C64 {
+    port: Rc<RefCell<ProcessorPort>>,
+    cia1: Rc<RefCell<Cia>>,
+    cia2: Rc<RefCell<Cia>>,
+    vic: Rc<RefCell<Vic>>,
+}
+
+impl C64 {
+    pub fn new(config: &Config) -> io::Result<(C64, Memory)> {
+        let banks = Banks::default();
+        let mut mem = Memory::new(vec![0; 0x10000]);
+        if let Some(path) = &config.basic {
+            mem.map_overlay(0xa000, read_rom(path, 0x2000)?, banks.basic.clone())?;
+        }
+        if let Some(path) = &config.kernal {
+            mem.map_overlay(0xe000, read_rom(path, 0x2000)?, banks.kernal.clone())?;
+        }
+        if let Some(path) = &config.chargen {
+            mem.map_overlay(0xd000, read_rom(path, 0x1000)?, banks.chargen.clone())?;
+        }
+        let vic = Rc::new(RefCell::new(Vic::default()));
+        let cia1 = Rc::new(RefCell::new(Cia::new(CLOCK_HZ)));
+        let cia2 = Rc::new(RefCell::new(Cia::new(CLOCK_HZ).with_nmi_output()));
+        let silent = Rc::new(RefCell::new(Silent));
+        let io = &banks.io;
+        mem.map_switched_device(0xd000, 0xd3ff, vic.clone(), io.clone());
+        mem.map_switched_device(0xd400, 0xd7ff, silent.clone(), io.clone());
+        mem.map_switched_device(
+            0xd800,
+            0xdbff,
+            Rc::new(RefCell::new(ColorRam([0; 0x400]))),
+            io.clone(),
+        );
+        mem.map_switched_device(0xdc00, 0xdcff, cia1.clone(), io.clone());
+        mem.map_switched_device(0xdd00, 0xddff, cia2.clone(), io.clone());
+        mem.map_switched_device(0xde00, 0xdfff, silent, io.clone());
+        let port = Rc::new(RefCell::new(ProcessorPort::new(banks)));
+        mem.map_device(0x0000, 0x0001, port.clone());
+        Ok((
+            C64 {
+                port,
+                cia1,
+                cia2,
+                vic,
+            },
+            mem,
+        ))
+    }
+    pub fn port(&self) -> Rc<RefCell<ProcessorPort>> {
+        self.port.clone()
+    }
+    pub fn cia1(&self) -> Rc<RefCell<Cia>> {
+        self.cia1.clone()
+    }
+    pub fn cia2(&self) -> Rc<RefCell<Cia>> {
+        self.cia2.clone()
+    }
+    pub fn vic(&self) -> Rc<RefCell<Vic>> {
+        self.vic.clone()
+    }
+    // the text screen on the host terminal, Ctrl-C from 'input' quits
+    pub fn terminal(&self, input: Box<dyn InputSource>) -> Terminal {
+        Terminal::new(input)
+    }
+    // no terminal, the screen text is printed at the end
+    pub fn headless(&self, input: Box<dyn InputSource>) -> Headless {
+        Headless {
+            keyboard: Keyboard::new(input),
+            screen: None,
+            cycles: 0,
+            next_frame: 0,
+        }
+    }
+}
+
+// the text screen at $0400 for screenshots, in the colors after power on. Graphic
+// characters are left blank, the built in font only has ASCII.
+pub struct Display;
+
+impl Video for Display {
+    fn capture(&self, mem: &Memory) -> Image {
+        let rows = (0..ROWS)
+            .map(|row| {
+                (0..COLUMNS)
+                    .map(|col| {
+                        let code = mem.load(SCREEN + (row * COLUMNS + col) as u16);
+                        let c = match code & 0x7f {
+                            c @ 0x00..=0x1f => c + 0x40,
+                            c @ 0x20..=0x3f => c,
+                            _ => b' ',
+                        };
+                        (c, code & 0x80 != 0)
+                    })
+                    .collect()
+            })
+            .collect::<Vec<_>>();
+        screenshot::text_image(&rows, (0x6c, 0x5e, 0xb5), (0x35, 0x28, 0x79))
+    }
+}
+
+// screen code (upper case character set) to the closest unicode character
+pub fn screen_char(code: u8) -> char {
+    // bit 7 is reverse video
+    match code & 0x7f {
+        0x00 => '@',
+        c @ 0x01..=0x1a => (b'A' + c - 1) as char,
+        0x1b => '[',
+        0x1c => '£',
+        0x1d => ']',
+        0x1e => '↑',
+        0x1f => '←',
+        c @ 0x20..=0x3f => c as char,
+        0x40 | 0x43 => '─',
+        0x42 | 0x5d => '│',
+        0x51 => '●',
+        0x53 => '♥',
+        0x5a => '♦',
+        0x58 => '♣',
+        0x41 => '♠',
+        0x5e => 'π',
+        0x66 => '▒',
+        0x60 => ' ',
+        0x6e => '┐',
+        0x70 => '┌',
+        0x6d => '└',
+        0x7d => '┘',
+        0x6b => '├',
+        0x73 => '┤',
+        0x72 => '┬',
+        0x71 => '┴',
+        0x5b => '┼',
+        _ => '▒',
+    }
+}
+
+pub fn screen_rows(mem: &Memory) -> Vec<String> {
+    (0..ROWS)
+        .map(|row| {
+            (0..COLUMNS)
+                .map(|col| screen_char(mem.load(SCREEN + (row * COLUMNS + col) as u16)))
+                .collect()
+        })
+        .collect()
+}
+
+// the text screen, trailing blanks and empty lines removed
+pub fn screen_text(mem: &Memory) -> String {
+    let rows = screen_rows(mem)
+        .iter()
+        .map(|row| row.trim_end().to_string())
+        .collect::<Vec<_>>();
+    rows.join("\n").trim_end().to_string()
+}
+
+// host key to PETSCII as the KERNAL keyboard routine would deliver it
+pub fn petscii(key: Key) -> Option<u8> {
+    Some(match key {
+        Key::Char('\n') => 13,
+        Key::Char(c) if c.is_ascii_lowercase() => c.to_ascii_uppercase() as u8,
+        Key::Char(c) if c.is_ascii_uppercase() => c as u8 + 0x80,
+        Key::Char(c) if (' '..='@').contains(&c) || c == '[' || c == ']' => c as u8,
+        Key::Backspace => 20,
+        Key::Insert => 148,
+        Key::Home => 19,
+        Key::Up => 145,
+        Key::Down => 17,
+        Key::Left => 157,
+        Key::Right => 29,
+        // RUN/STOP
+        Key::Esc => 3,
+        _ => return None,
+    })
+}
+
+// host keys into the KERNAL keyboard buffer, as fast as it takes them
+struct Keyboard {
+    input: Box<dyn InputSource>,
+    pending: VecDeque<u8>,
+    quit: bool,
+}
+
+impl Keyboard {
+    fn new(input: Box<dyn InputSource>) -> Self {
+        Self {
+            input,
+            pending: VecDeque::new(),
+            quit: false,
+        }
+    }
+    fn poll(&mut self, cycles: u64, mem: &mut Memory) {
+        for key in self.input.poll(cycles) {
+            if key == Key::Ctrl('c') {
+                self.quit = true;
+            }
+            self.pending.extend(petscii(key));
+        }
+        // XMAX is 0 until the KERNAL has set up the buffer
+        let size = mem.load(XMAX).min(10);
+        while !self.pending.is_empty() && mem.load(NDX) < size {
+            let n = mem.load(NDX);
+            mem.store(KEYD + n as u16, self.pending.pop_front().unwrap());
+            mem.store(NDX, n + 1);
+        }
+    }
+}
+
+// Real time on the host terminal with the text screen in a frame.
+pub struct Terminal {
+    keyboard: Keyboard,
+    stdout: RawTerminal<Stdout>,
+    screen: Vec<String>,
+    start: Instant,
+    cycles: u64,
+    next_frame: u64,
+}
+
+impl Terminal {
+    pub fn new(input: Box<dyn InputSource>) -> Self {
+        let mut stdout = std::io::stdout().into_raw_mode().unwrap();
+        write!(stdout, "{}", termion::clear::All).unwrap();
+        Self {
+            keyboard: Keyboard::new(input),
+            stdout,
+            screen: Vec::new(),
+            start: Instant::now(),
+            cycles: 0,
+            next_frame: 0,
+        }
+    }
+    fn draw(&mut self, mem: &Memory) {
+        let screen = screen_rows(mem);
+        let cursor = (mem.load(PNTR) as usize % COLUMNS, mem.load(TBLX) as usize);
+        if screen == self.screen {
+            return;
+        }
+        let border = "─".repeat(COLUMNS);
+        let mut out = format!("{}┌{}┐", termion::cursor::Goto(1, 1), border);
+        for (y, row) in screen.iter().enumerate() {
+            out += &format!("{}│{}│", termion::cursor::Goto(1, y as u16 + 2), row);
+        }
+        out += &format!("{}└{}┘", termion::cursor::Goto(1, ROWS as u16 + 2), border);
+        out += &format!(
+            "{}",
+            termion::cursor::Goto(cursor.0 as u16 + 2, cursor.1.min(ROWS - 1) as u16 + 2)
+        );
+        self.stdout.write_all(out.as_bytes()).unwrap();
+        self.stdout.flush().unwrap();
+        self.screen = screen;
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        write!(self.stdout, "{}", termion::cursor::Goto(1, ROWS as u16 + 3)).unwrap();
+    }
+}
+
+impl Dbg for Terminal {
+    fn step(&mut self, _reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.cycles >= self.next_frame {
+            self.next_frame = self.cycles + CLOCK_HZ / 50;
+            self.keyboard.poll(self.cycles, mem);
+            self.draw(mem);
+            let due = Duration::from_micros(self.cycles * 1_000_000 / CLOCK_HZ);
+            if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+                std::thread::sleep(ahead);
+            }
+        }
+        self.keyboard.quit
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        false
+    }
+}
+
+pub struct Headless {
+    keyboard: Keyboard,
+    // the screen at the last frame, printed when the run ends
+    screen: Option<String>,
+    cycles: u64,
+    next_frame: u64,
+}
+
+impl Drop for Headless {
+    fn drop(&mut self) {
+        if let Some(text) = self.screen.take() {
+            println!("{}", text);
+        }
+    }
+}
+
+impl Dbg for Headless {
+    fn step(&mut self, _reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.cycles >= self.next_frame {
+            self.next_frame = self.cycles + CLOCK_HZ / 50;
+            self.keyboard.poll(self.cycles, mem);
+            self.screen = Some(screen_text(mem));
+        }
+        self.keyboard.quit
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        false
+    }
+}
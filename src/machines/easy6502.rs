@@ -0,0 +1,307 @@
+// The virtual machine of easy6502 (skilldrick.github.io/easy6502): 64K RAM, a random
+// byte at $FE, the last key pressed at $FF and a 32x32 screen at $0200-$05FF with
+// one byte per pixel, the low nibble selecting one of 16 colors.
+//
+// Like the website the terminal front end runs 97 instructions every 15ms, which is
+// the speed the games were written for.
+use std::{
+    cell::RefCell,
+    io::{Stdout, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use termion::{
+    color,
+    event::Key,
+    raw::{IntoRawMode, RawTerminal},
+};
+
+use crate::{
+    cpu::StepInfo,
+    dbg::Dbg,
+    input::InputSource,
+    mem::{Device, Memory},
+    reg::Registers,
+    screenshot::{Image, Video},
+};
+
+pub const SCREEN: u16 = 0x0200;
+pub const WIDTH: usize = 32;
+pub const HEIGHT: usize = 32;
+pub const STEPS_PER_FRAME: u64 = 97;
+pub const FRAME: Duration = Duration::from_millis(15);
+
+pub const PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0xff, 0xff, 0xff),
+    (0x88, 0x00, 0x00),
+    (0xaa, 0xff, 0xee),
+    (0xcc, 0x44, 0xcc),
+    (0x00, 0xcc, 0x55),
+    (0x00, 0x00, 0xaa),
+    (0xee, 0xee, 0x77),
+    (0xdd, 0x88, 0x55),
+    (0x66, 0x44, 0x00),
+    (0xff, 0x77, 0x77),
+    (0x33, 0x33, 0x33),
+    (0x77, 0x77, 0x77),
+    (0xaa, 0xff, 0x66),
+    (0x00, 0x88, 0xff),
+    (0xbb, 0xbb, 0xbb),
+];
+
+// color of a pixel as rgb
+pub fn pixel(mem: &Memory, x: usize, y: usize) -> (u8, u8, u8) {
+    PALETTE[(mem.load(SCREEN + (y * WIDTH + x) as u16) & 0x0f) as usize]
+}
+
+// the framebuffer, one image pixel per screen pixel
+pub struct Screen;
+
+impl Video for Screen {
+    fn capture(&self, mem: &Memory) -> Image {
+        let mut image = Image::new(WIDTH, HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                image.set(x, y, pixel(mem, x, y));
+            }
+        }
+        image
+    }
+}
+
+// $FE and $FF
+pub struct Io {
+    rng: Box<dyn RngCore>,
+    random: u8,
+    key: u8,
+}
+
+impl Io {
+    // keys are delivered as ASCII codes, arrows as the WASD keys the games check for
+    pub fn key(&mut self, key: Key) {
+        self.key = match key {
+            Key::Up => b'w',
+            Key::Left => b'a',
+            Key::Down => b's',
+            Key::Right => b'd',
+            Key::Char(c) if c.is_ascii() => c as u8,
+            _ => return,
+        };
+    }
+}
+
+impl Device for Io {
+    fn read(&mut self, addr: u16) -> u8 {
+        if addr == 0xfe {
+            // a new number for every read, the website changes it every instruction
+            self.random = self.rng.next_u32() as u8;
+        }
+        self.peek(addr)
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        if addr == 0xfe {
+            self.random
+        } else {
+            self.key
+        }
+    }
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr == 0xff {
+            self.key = value;
+        }
+    }
+}
+
+pub struct Easy6502 {
+    io: Rc<RefCell<Io>>,
+}
+
+impl Easy6502 {
+    // 'image' is loaded at $0000, programs usually start at $0600. The same seed gives
+    // the same random numbers, so with scripted input runs can be repeated exactly.
+    pub fn new(image: Vec<u8>, seed: u64) -> (Easy6502, Memory) {
+        Self::with_rng(image, Box::new(StdRng::seed_from_u64(seed)))
+    }
+    pub fn with_rng(mut image: Vec<u8>, rng: Box<dyn RngCore>) -> (Easy6502, Memory) {
+        image.resize(0x10000, 0);
+        let io = Rc::new(RefCell::new(Io {
+            rng,
+            random: 0,
+            key: 0,
+        }));
+        let mut mem = Memory::new(image);
+        mem.map_device(0xfe, 0xff, io.clone());
+        (Easy6502 { io }, mem)
+    }
+    pub fn io(&self) -> Rc<RefCell<Io>> {
+        self.io.clone()
+    }
+    // screen on the host terminal, keys from 'input'
+    pub fn terminal(&self, input: Box<dyn InputSource>) -> Terminal {
+        Terminal::new(self.io.clone(), input)
+    }
+    // no terminal and no pacing, keys from 'input'
+    pub fn headless(&self, input: Box<dyn InputSource>) -> Headless {
+        Headless {
+            io: self.io.clone(),
+            input,
+            steps: 0,
+            cycles: 0,
+            quit: false,
+        }
+    }
+}
+
+fn truecolor() -> bool {
+    std::env::var("COLORTERM").is_ok_and(|v| v == "truecolor" || v == "24bit")
+}
+
+fn fg(rgb: (u8, u8, u8), truecolor: bool) -> String {
+    if truecolor {
+        color::Rgb(rgb.0, rgb.1, rgb.2).fg_string()
+    } else {
+        to_ansi(rgb).fg_string()
+    }
+}
+fn bg(rgb: (u8, u8, u8), truecolor: bool) -> String {
+    if truecolor {
+        color::Rgb(rgb.0, rgb.1, rgb.2).bg_string()
+    } else {
+        to_ansi(rgb).bg_string()
+    }
+}
+// nearest color of the 6x6x6 cube of 256 color terminals
+fn to_ansi(rgb: (u8, u8, u8)) -> color::AnsiValue {
+    let level = |v: u8| ((v as u16 * 5 + 127) / 255) as u8;
+    color::AnsiValue::rgb(level(rgb.0), level(rgb.1), level(rgb.2))
+}
+
+// Screen on the host terminal, two pixels per character cell using half blocks.
+// Ctrl-C or ESC quit.
+pub struct Terminal {
+    io: Rc<RefCell<Io>>,
+    stdout: RawTerminal<Stdout>,
+    input: Box<dyn InputSource>,
+    truecolor: bool,
+    steps: u64,
+    cycles: u64,
+    next_frame: Instant,
+    dirty: bool,
+    quit: bool,
+}
+
+impl Terminal {
+    pub fn new(io: Rc<RefCell<Io>>, input: Box<dyn InputSource>) -> Self {
+        let mut stdout = std::io::stdout().into_raw_mode().unwrap();
+        write!(stdout, "{}{}", termion::clear::All, termion::cursor::Hide).unwrap();
+        Self {
+            io,
+            stdout,
+            input,
+            truecolor: truecolor(),
+            steps: 0,
+            cycles: 0,
+            next_frame: Instant::now(),
+            dirty: true,
+            quit: false,
+        }
+    }
+    fn draw(&mut self, mem: &Memory) {
+        let mut out = String::new();
+        for y in (0..HEIGHT).step_by(2) {
+            out += &termion::cursor::Goto(1, (y / 2) as u16 + 1).to_string();
+            for x in 0..WIDTH {
+                out += &fg(pixel(mem, x, y), self.truecolor);
+                out += &bg(pixel(mem, x, y + 1), self.truecolor);
+                out.push('▀');
+            }
+            out += &format!("{}{}", color::Fg(color::Reset), color::Bg(color::Reset));
+        }
+        self.stdout.write_all(out.as_bytes()).unwrap();
+        self.stdout.flush().unwrap();
+    }
+    fn frame(&mut self, mem: &Memory) {
+        for key in self.input.poll(self.cycles) {
+            match key {
+                Key::Ctrl('c') | Key::Esc => self.quit = true,
+                key => self.io.borrow_mut().key(key),
+            }
+        }
+        if self.dirty {
+            self.draw(mem);
+            self.dirty = false;
+        }
+        self.next_frame += FRAME;
+        match self.next_frame.checked_duration_since(Instant::now()) {
+            Some(wait) => std::thread::sleep(wait),
+            // too slow, don't try to catch up
+            None => self.next_frame = Instant::now(),
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        write!(
+            self.stdout,
+            "{}{}",
+            termion::cursor::Goto(1, (HEIGHT / 2) as u16 + 1),
+            termion::cursor::Show
+        )
+        .unwrap();
+    }
+}
+
+impl Dbg for Terminal {
+    fn step(&mut self, _reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.steps.is_multiple_of(STEPS_PER_FRAME) {
+            self.frame(mem);
+        }
+        self.quit
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.steps += 1;
+        self.cycles += info.cycles as u64;
+        false
+    }
+    fn mem_write(&mut self, addr: u16, _value: u8, _pc: u16) {
+        if (SCREEN..SCREEN + (WIDTH * HEIGHT) as u16).contains(&addr) {
+            self.dirty = true;
+        }
+    }
+    fn wants_accesses(&self) -> bool {
+        true
+    }
+}
+
+// Keys are polled on the same schedule as in the terminal, so scripts behave the same.
+// Ctrl-C or ESC quit.
+pub struct Headless {
+    io: Rc<RefCell<Io>>,
+    input: Box<dyn InputSource>,
+    steps: u64,
+    cycles: u64,
+    quit: bool,
+}
+
+impl Dbg for Headless {
+    fn step(&mut self, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        if self.steps.is_multiple_of(STEPS_PER_FRAME) {
+            for key in self.input.poll(self.cycles) {
+                match key {
+                    Key::Ctrl('c') | Key::Esc => self.quit = true,
+                    key => self.io.borrow_mut().key(key),
+                }
+            }
+        }
+        self.quit
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.steps += 1;
+        self.cycles += info.cycles as u64;
+        false
+    }
+}
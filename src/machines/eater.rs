@@ -0,0 +1,286 @@
+// Ben Eater's breadboard 6502 computer: 16K RAM at $0000, 32K ROM at $8000, a 6522 VIA
+// at $6000 with a 16x2 HD44780 LCD on its ports and optionally a 6551 ACIA at $5000.
+// The cpu runs at 1 MHz.
+//
+// The LCD is wired as in the videos: 8 bit with the data on port B and E/RW/RS on
+// PA7/PA6/PA5, or 4 bit with D4-D7 on PB0-PB3 and E/RW/RS on PB6/PB5/PB4.
+use std::{
+    cell::RefCell,
+    io::{self, Stdout, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use termion::{
+    event::Key,
+    raw::{IntoRawMode, RawTerminal},
+};
+
+use crate::{
+    cpu::StepInfo,
+    dbg::Dbg,
+    devices::{
+        acia::{self, Acia},
+        hd44780::{self, Hd44780},
+        via::{Pins, Via, ViaPort},
+    },
+    input::InputSource,
+    mem::Memory,
+    reg::Registers,
+};
+
+pub const CLOCK_HZ: u64 = 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LcdWiring {
+    EightBit,
+    FourBit,
+}
+
+pub struct Config {
+    pub rom: String,
+    pub lcd_wiring: LcdWiring,
+    // 'stdio', 'pty' or 'tcp:<port>', no ACIA without it
+    pub serial: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rom: "a.out".into(),
+            lcd_wiring: LcdWiring::EightBit,
+            serial: None,
+        }
+    }
+}
+
+// connects the LCD to the VIA ports
+struct LcdPort {
+    lcd: Rc<RefCell<Hd44780>>,
+    wiring: LcdWiring,
+    pins: Pins,
+}
+
+impl LcdPort {
+    // RS, R/W, E and D0-D7 as seen by the LCD
+    fn lines(&self) -> (bool, bool, bool, u8) {
+        let (pa, pb) = (self.pins.pa, self.pins.pb);
+        match self.wiring {
+            LcdWiring::EightBit => (pa & 0x20 != 0, pa & 0x40 != 0, pa & 0x80 != 0, pb),
+            LcdWiring::FourBit => (pb & 0x10 != 0, pb & 0x20 != 0, pb & 0x40 != 0, pb << 4),
+        }
+    }
+}
+
+impl ViaPort for LcdPort {
+    fn output(&mut self, pins: &Pins) {
+        self.pins = *pins;
+        let (rs, rw, e, data) = self.lines();
+        self.lcd.borrow_mut().bus(rs, rw, e, data);
+    }
+    fn input(&mut self) -> (u8, u8) {
+        let (rs, rw, e, _) = self.lines();
+        if !(rw && e) {
+            return (0xff, 0xff);
+        }
+        let data = self.lcd.borrow().read_bus(rs);
+        match self.wiring {
+            LcdWiring::EightBit => (0xff, data),
+            LcdWiring::FourBit => (0xff, 0xf0 | data >> 4),
+        }
+    }
+    fn tick(&mut self, cycles: u32) {
+        self.lcd.borrow_mut().tick(cycles);
+    }
+}
+
+pub struct Eater {
+    lcd: Rc<RefCell<Hd44780>>,
+    via: Rc<RefCell<Via>>,
+    acia: Option<Rc<RefCell<Acia>>>,
+}
+
+impl Eater {
+    pub fn new(config: &Config) -> io::Result<(Eater, Memory)> {
+        let rom = std::fs::read(&config.rom)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.rom, e)))?;
+        if rom.len() > 0x8000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {} bytes, the ROM is 32K", config.rom, rom.len()),
+            ));
+        }
+        let mut image = vec![0u8; 0x10000];
+        image[0x8000..0x8000 + rom.len()].copy_from_slice(&rom);
+        let lcd = Rc::new(RefCell::new(Hd44780::new(CLOCK_HZ)));
+        let via = Rc::new(RefCell::new(Via::new().with_port(Box::new(LcdPort {
+            lcd: lcd.clone(),
+            wiring: config.lcd_wiring,
+            pins: Pins::default(),
+        }))));
+        let mut mem = Memory::new(image);
+        // the address decoder selects the VIA for $6000-$7FFF, the ACIA for $5000-$5FFF
+        mem.unmap(0x4000, 0x7fff);
+        mem.map_device(0x6000, 0x7fff, via.clone());
+        mem.map_rom(0x8000, 0xffff);
+        let acia = match &config.serial {
+            Some(spec) => {
+                let acia = Rc::new(RefCell::new(
+                    Acia::new(CLOCK_HZ).with_host(acia::open_host(spec)?),
+                ));
+                mem.map_device(0x5000, 0x5fff, acia.clone());
+                Some(acia)
+            }
+            None => None,
+        };
+        Ok((Eater { lcd, via, acia }, mem))
+    }
+    pub fn lcd(&self) -> Rc<RefCell<Hd44780>> {
+        self.lcd.clone()
+    }
+    pub fn via(&self) -> Rc<RefCell<Via>> {
+        self.via.clone()
+    }
+    pub fn acia(&self) -> Option<Rc<RefCell<Acia>>> {
+        self.acia.clone()
+    }
+    // the LCD on the host terminal, Ctrl-C from 'input' quits
+    pub fn terminal(&self, input: Box<dyn InputSource>) -> Terminal {
+        Terminal::new(self.lcd.clone(), input)
+    }
+    // no terminal, the LCD contents are printed at the end
+    pub fn headless(&self, input: Box<dyn InputSource>) -> Headless {
+        Headless {
+            lcd: self.lcd.clone(),
+            input,
+            cycles: 0,
+            next_frame: 0,
+            quit: false,
+        }
+    }
+}
+
+// Real time on the host terminal with the LCD drawn in a frame.
+pub struct Terminal {
+    lcd: Rc<RefCell<Hd44780>>,
+    input: Box<dyn InputSource>,
+    stdout: RawTerminal<Stdout>,
+    start: Instant,
+    cycles: u64,
+    next_frame: u64,
+    quit: bool,
+}
+
+impl Terminal {
+    pub fn new(lcd: Rc<RefCell<Hd44780>>, input: Box<dyn InputSource>) -> Self {
+        let mut stdout = std::io::stdout().into_raw_mode().unwrap();
+        write!(stdout, "{}", termion::clear::All).unwrap();
+        Self {
+            lcd,
+            input,
+            stdout,
+            start: Instant::now(),
+            cycles: 0,
+            next_frame: 0,
+            quit: false,
+        }
+    }
+    fn draw(&mut self) {
+        let mut lcd = self.lcd.borrow_mut();
+        if !lcd.take_dirty() {
+            return;
+        }
+        let border = "─".repeat(hd44780::COLUMNS);
+        let mut out = format!("{}┌{}┐", termion::cursor::Goto(1, 1), border);
+        for (y, row) in lcd.rows().iter().enumerate() {
+            let text = row
+                .iter()
+                .map(|c| c.map_or(' ', hd44780::to_char))
+                .collect::<String>();
+            out += &format!("{}│{}│", termion::cursor::Goto(1, y as u16 + 2), text);
+        }
+        out += &format!(
+            "{}└{}┘",
+            termion::cursor::Goto(1, hd44780::ROWS as u16 + 2),
+            border
+        );
+        match lcd.cursor() {
+            Some((x, y)) => {
+                out += &format!(
+                    "{}{}",
+                    termion::cursor::Goto(x as u16 + 2, y as u16 + 2),
+                    termion::cursor::Show
+                )
+            }
+            None => out += termion::cursor::Hide.as_ref(),
+        }
+        self.stdout.write_all(out.as_bytes()).unwrap();
+        self.stdout.flush().unwrap();
+    }
+    fn frame(&mut self) {
+        for key in self.input.poll(self.cycles) {
+            if key == Key::Ctrl('c') {
+                self.quit = true;
+            }
+        }
+        self.draw();
+        let due = Duration::from_micros(self.cycles * 1_000_000 / CLOCK_HZ);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        write!(
+            self.stdout,
+            "{}{}",
+            termion::cursor::Goto(1, hd44780::ROWS as u16 + 3),
+            termion::cursor::Show
+        )
+        .unwrap();
+    }
+}
+
+impl Dbg for Terminal {
+    fn step(&mut self, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        if self.cycles >= self.next_frame {
+            self.next_frame = self.cycles + CLOCK_HZ / 60;
+            self.frame();
+        }
+        self.quit
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        false
+    }
+}
+
+pub struct Headless {
+    lcd: Rc<RefCell<Hd44780>>,
+    input: Box<dyn InputSource>,
+    cycles: u64,
+    next_frame: u64,
+    quit: bool,
+}
+
+impl Drop for Headless {
+    fn drop(&mut self) {
+        println!("{}", self.lcd.borrow().text());
+    }
+}
+
+impl Dbg for Headless {
+    fn step(&mut self, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        if self.cycles >= self.next_frame {
+            self.next_frame = self.cycles + CLOCK_HZ / 60;
+            self.quit |= self.input.poll(self.cycles).contains(&Key::Ctrl('c'));
+        }
+        self.quit
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        false
+    }
+}
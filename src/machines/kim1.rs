@@ -0,0 +1,619 @@
+// KIM-1: 1K RAM at $0000 and two 6530 RIOTs. The 6530-003 has its I/O and timer at
+// $1700, RAM at $1780 and ROM at $1800. The 6530-002 has them at $1740, $17C0 and $1C00
+// and drives the keypad, the six digit LED display and the TTY interface.
+//
+// The ROMs appear again at $F800, so the vectors at $FFFA-$FFFF are the ones at
+// $1FFA-$1FFF like on the real board, where only A0-A12 are decoded. Nothing else is
+// mirrored, $2000-$F7FF is unmapped.
+//
+// The display is multiplexed by the monitor: segments on PA0-PA6, the digit selected
+// through a 74145 decoder on PB1-PB4 (outputs 4-9). Decoder outputs 0-2 select the
+// keypad rows read back on PA0-PA6, output 3 is wired to PA0 by the TTY jumper.
+// In TTY mode the monitor bit-bangs serial data, in on PA7 and out on PB0.
+//
+// Host keys for the keypad: 0-9 and a-f, Ctrl-A AD, Ctrl-D DA, + or space +, Enter or
+// Ctrl-G GO, Ctrl-P PC, Ctrl-T or Esc ST, Ctrl-R RS and Ctrl-S toggles SST.
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Stdout, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use termion::{
+    event::Key,
+    raw::{IntoRawMode, RawTerminal},
+};
+
+use crate::{
+    cpu::StepInfo,
+    dbg::Dbg,
+    devices::riot::{Pins, Riot, RiotPort},
+    input::InputSource,
+    mem::Memory,
+    reg::Registers,
+};
+
+pub const CLOCK_HZ: u64 = 1_000_000;
+pub const DIGITS: usize = 6;
+
+// keypad codes as returned by the monitor's GETKEY
+pub const KEY_AD: u8 = 0x10;
+pub const KEY_DA: u8 = 0x11;
+pub const KEY_PLUS: u8 = 0x12;
+pub const KEY_GO: u8 = 0x13;
+pub const KEY_PC: u8 = 0x14;
+
+// how long a key is held down, and released before the next one
+const KEY_HOLD: u64 = CLOCK_HZ / 20;
+// segments lit for less than that are switching glitches
+const MIN_LIT: u64 = 50;
+
+// segment patterns of the monitor's hex table, segment a is bit 0
+const HEX_SEGMENTS: [u8; 16] = [
+    0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07, 0x7f, 0x6f, 0x77, 0x7c, 0x39, 0x5e, 0x79, 0x71,
+];
+
+pub struct Config {
+    // ROM images of the 6530-002 ($1C00) and 6530-003 ($1800)
+    pub rom_002: String,
+    pub rom_003: String,
+    // TTY jumper installed: the monitor talks over the serial line instead of the keypad
+    pub tty: bool,
+    pub baud: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rom_002: "6502-test-code/6530-002.bin".into(),
+            rom_003: "6502-test-code/6530-003.bin".into(),
+            tty: false,
+            baud: 1200,
+        }
+    }
+}
+
+// keypad, display and TTY on the 6530-002 ports
+pub struct Panel {
+    pins: Pins,
+    clock: u64,
+    // segments lit in each digit since the last take_display()
+    lit: [u8; DIGITS],
+    changed_at: u64,
+    keys: VecDeque<u8>,
+    // key down until the given cycle
+    pressed: Option<(u8, u64)>,
+    next_key_at: u64,
+    nmi: bool,
+    reset: bool,
+    sst: bool,
+    tty: bool,
+    bit_cycles: u64,
+    // serial into the KIM: bytes waiting and the one on the line with its start time
+    rx_queue: VecDeque<u8>,
+    rx: Option<(u8, u64)>,
+    next_rx_at: u64,
+    // serial from the KIM: start of the character and the bits so far
+    tx: Option<(u64, u8, u32)>,
+    output: Vec<u8>,
+}
+
+impl Panel {
+    pub fn new(tty: bool, baud: u64) -> Self {
+        let bit_cycles = CLOCK_HZ / baud;
+        let mut rx_queue = VecDeque::new();
+        if tty {
+            // the monitor measures the baud rate on a RUBOUT after reset
+            rx_queue.push_back(0x7f);
+        }
+        Self {
+            pins: Pins {
+                pa: 0xff,
+                pb: 0xff,
+                ddra: 0,
+                ddrb: 0,
+            },
+            clock: 0,
+            lit: [0; DIGITS],
+            changed_at: 0,
+            keys: VecDeque::new(),
+            pressed: None,
+            next_key_at: 0,
+            nmi: false,
+            reset: false,
+            sst: false,
+            tty,
+            bit_cycles,
+            rx_queue,
+            rx: None,
+            // give the monitor time to get to its baud rate detection
+            next_rx_at: bit_cycles * 20,
+            tx: None,
+            output: Vec::new(),
+        }
+    }
+
+    // a keypad key or a switch from the host keyboard, other keys are ignored
+    pub fn key(&mut self, key: Key) {
+        let code = match key {
+            Key::Char(c) if c.is_ascii_hexdigit() => c.to_digit(16).unwrap() as u8,
+            Key::Ctrl('a') => KEY_AD,
+            Key::Ctrl('d') => KEY_DA,
+            Key::Char('+') | Key::Char(' ') => KEY_PLUS,
+            Key::Char('\n') | Key::Ctrl('g') => KEY_GO,
+            Key::Ctrl('p') => KEY_PC,
+            Key::Ctrl('t') | Key::Esc => {
+                self.nmi = true;
+                return;
+            }
+            Key::Ctrl('r') => {
+                self.reset = true;
+                return;
+            }
+            Key::Ctrl('s') => {
+                self.sst = !self.sst;
+                return;
+            }
+            _ => return,
+        };
+        self.keys.push_back(code);
+    }
+    // a character for the TTY input
+    pub fn send(&mut self, value: u8) {
+        self.rx_queue.push_back(value);
+    }
+    pub fn tty(&self) -> bool {
+        self.tty
+    }
+    pub fn sst(&self) -> bool {
+        self.sst
+    }
+    // the SST circuit asks for an NMI after an instruction outside the ROM
+    pub fn single_step(&mut self) {
+        self.nmi = true;
+    }
+    // the RS key was pressed since the last call
+    pub fn take_reset(&mut self) -> bool {
+        std::mem::take(&mut self.reset)
+    }
+    // characters the KIM sent on the TTY line
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn decoder(&self) -> u8 {
+        (self.pins.pb >> 1) & 0x0f
+    }
+    fn digit(&self) -> Option<usize> {
+        match self.decoder() {
+            d @ 4..=9 => Some(d as usize - 4),
+            _ => None,
+        }
+    }
+    // segments of the selected digit have been on since the last pin change
+    fn account(&mut self) {
+        if self.clock - self.changed_at < MIN_LIT {
+            return;
+        }
+        if let Some(digit) = self.digit() {
+            self.lit[digit] |= self.pins.pa & 0x7f;
+        }
+    }
+    // segments lit in each digit since the last call, like the eye sees them
+    pub fn take_display(&mut self) -> [u8; DIGITS] {
+        self.account();
+        self.changed_at = self.clock;
+        std::mem::take(&mut self.lit)
+    }
+
+    fn rx_level(&self) -> bool {
+        let Some((value, start)) = self.rx else {
+            return true;
+        };
+        match (self.clock - start) / self.bit_cycles {
+            0 => false,
+            bit @ 1..=8 => value >> (bit - 1) & 1 != 0,
+            _ => true,
+        }
+    }
+    fn tick_rx(&mut self) {
+        if let Some((_, start)) = self.rx {
+            // start, 8 data and 2 stop bits
+            if self.clock - start >= 11 * self.bit_cycles {
+                self.rx = None;
+                // another character time for the monitor to deal with it
+                self.next_rx_at = self.clock + 11 * self.bit_cycles;
+            }
+        }
+        if self.rx.is_none() && self.clock >= self.next_rx_at {
+            if let Some(value) = self.rx_queue.pop_front() {
+                self.rx = Some((value, self.clock));
+            }
+        }
+    }
+    // samples PB0 in the middle of each bit
+    fn tick_tx(&mut self) {
+        let Some((start, value, bits)) = self.tx else {
+            return;
+        };
+        let sample_at = start + self.bit_cycles * (bits as u64 + 1) + self.bit_cycles / 2;
+        if self.clock < sample_at {
+            return;
+        }
+        let level = self.pins.pb & 1;
+        if bits < 8 {
+            self.tx = Some((start, value | level << bits, bits + 1));
+        } else {
+            // the stop bit
+            self.tx = None;
+            if level != 0 {
+                self.output.push(value & 0x7f);
+            }
+        }
+    }
+    fn tick_keys(&mut self) {
+        if let Some((_, until)) = self.pressed {
+            if self.clock >= until {
+                self.pressed = None;
+                self.next_key_at = self.clock + KEY_HOLD;
+            }
+        }
+        if self.pressed.is_none() && self.clock >= self.next_key_at {
+            if let Some(code) = self.keys.pop_front() {
+                self.pressed = Some((code, self.clock + KEY_HOLD));
+            }
+        }
+    }
+}
+
+// segments of a hex digit as shown by the monitor, '?' for anything else
+pub fn segments_to_char(segments: u8) -> char {
+    if segments == 0 {
+        return ' ';
+    }
+    match HEX_SEGMENTS.iter().position(|s| *s == segments) {
+        Some(n) => char::from_digit(n as u32, 16).unwrap().to_ascii_uppercase(),
+        None => '?',
+    }
+}
+
+pub fn display_text(display: &[u8; DIGITS]) -> String {
+    let digits = display
+        .iter()
+        .map(|s| segments_to_char(*s))
+        .collect::<String>();
+    format!("{} {}", &digits[..4], &digits[4..])
+}
+
+struct PanelPort(Rc<RefCell<Panel>>);
+
+impl RiotPort for PanelPort {
+    fn output(&mut self, pins: &Pins) {
+        let mut panel = self.0.borrow_mut();
+        panel.account();
+        // falling edge on PB0 while idle: a start bit
+        if panel.tty && panel.tx.is_none() && panel.pins.pb & 1 != 0 && pins.pb & 1 == 0 {
+            panel.tx = Some((panel.clock, 0, 0));
+        }
+        panel.pins = *pins;
+        panel.changed_at = panel.clock;
+    }
+    fn input(&mut self) -> (u8, u8) {
+        let panel = self.0.borrow();
+        let mut pa = 0x7f;
+        match panel.decoder() {
+            row @ 0..=2 => {
+                if let Some((code, _)) = panel.pressed {
+                    if code / 7 == row {
+                        pa &= !(0x40 >> (code % 7));
+                    }
+                }
+            }
+            3 if panel.tty => pa &= !0x01,
+            _ => (),
+        }
+        if panel.rx_level() {
+            pa |= 0x80;
+        }
+        (pa, 0xff)
+    }
+    fn tick(&mut self, cycles: u32) {
+        let mut panel = self.0.borrow_mut();
+        panel.clock += cycles as u64;
+        panel.tick_keys();
+        panel.tick_rx();
+        panel.tick_tx();
+    }
+    fn nmi(&mut self) -> bool {
+        std::mem::take(&mut self.0.borrow_mut().nmi)
+    }
+}
+
+pub struct Kim1 {
+    panel: Rc<RefCell<Panel>>,
+    riot_002: Rc<RefCell<Riot>>,
+    riot_003: Rc<RefCell<Riot>>,
+}
+
+fn read_rom(path: &str) -> io::Result<Vec<u8>> {
+    let rom =
+        std::fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    if rom.len() != 0x400 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {} bytes, a 6530 ROM is 1K", path, rom.len()),
+        ));
+    }
+    Ok(rom)
+}
+
+impl Kim1 {
+    pub fn new(config: &Config) -> io::Result<(Kim1, Memory)> {
+        let mut image = vec![0u8; 0x10000];
+        image[0x1800..0x1c00].copy_from_slice(&read_rom(&config.rom_003)?);
+        image[0x1c00..0x2000].copy_from_slice(&read_rom(&config.rom_002)?);
+        // the top 2K mirror the ROMs for the vectors
+        image.copy_within(0x1800..0x2000, 0xf800);
+        let panel = Rc::new(RefCell::new(Panel::new(config.tty, config.baud)));
+        let riot_002 = Rc::new(RefCell::new(
+            Riot::new().with_port(Box::new(PanelPort(panel.clone()))),
+        ));
+        let riot_003 = Rc::new(RefCell::new(Riot::new()));
+        let mut mem = Memory::new(image);
+        mem.unmap(0x0400, 0x16ff);
+        mem.map_device(0x1700, 0x173f, riot_003.clone());
+        mem.map_device(0x1740, 0x177f, riot_002.clone());
+        mem.map_rom(0x1800, 0x1fff);
+        mem.unmap(0x2000, 0xf7ff);
+        mem.map_rom(0xf800, 0xffff);
+        Ok((
+            Kim1 {
+                panel,
+                riot_002,
+                riot_003,
+            },
+            mem,
+        ))
+    }
+    pub fn panel(&self) -> Rc<RefCell<Panel>> {
+        self.panel.clone()
+    }
+    // the 6530-003's ports and timer are free for applications
+    pub fn riot_003(&self) -> Rc<RefCell<Riot>> {
+        self.riot_003.clone()
+    }
+    pub fn riot_002(&self) -> Rc<RefCell<Riot>> {
+        self.riot_002.clone()
+    }
+    // display or TTY on the host terminal, Ctrl-C from 'input' quits
+    pub fn terminal(&self, input: Box<dyn InputSource>) -> Terminal {
+        Terminal::new(self.panel.clone(), input)
+    }
+    // TTY output goes to stdout, the display is printed at the end
+    pub fn headless(&self, input: Box<dyn InputSource>) -> Headless {
+        Headless {
+            panel: self.panel.clone(),
+            input,
+            display: [0; DIGITS],
+            cycles: 0,
+            next_frame: 0,
+            quit: false,
+        }
+    }
+}
+
+// keys go to the keypad, or in TTY mode to the serial line. Returns true on Ctrl-C.
+fn feed_keys(input: &mut dyn InputSource, cycles: u64, panel: &RefCell<Panel>) -> bool {
+    let mut panel = panel.borrow_mut();
+    for key in input.poll(cycles) {
+        if key == Key::Ctrl('c') {
+            return true;
+        }
+        if !panel.tty() {
+            panel.key(key);
+            continue;
+        }
+        match key {
+            Key::Char('\n') => panel.send(b'\r'),
+            Key::Char(c) if c.is_ascii() => panel.send(c.to_ascii_uppercase() as u8),
+            Key::Backspace => panel.send(0x7f),
+            Key::Esc => panel.send(0x1b),
+            // the switches are still there in TTY mode
+            key => panel.key(key),
+        }
+    }
+    false
+}
+
+// what the SST switch and the RS key do outside of the chips
+fn switches(panel: &RefCell<Panel>, info: &StepInfo) {
+    let mut panel = panel.borrow_mut();
+    // K7 selects $1C00-$1FFF, stepping through the monitor would never end
+    if panel.sst() && !(0x1c00..0x2000).contains(&(info.pc & 0x1fff)) {
+        panel.single_step();
+    }
+}
+
+fn reset(panel: &RefCell<Panel>, reg: &mut Registers, mem: &Memory) {
+    if panel.borrow_mut().take_reset() {
+        // the monitor initializes the RIOTs itself
+        *reg = Registers::default();
+        reg.sr.i = true;
+        reg.pc = mem.load16(0xfffc);
+    }
+}
+
+// seven segment digits drawn with three lines of box characters
+fn draw_digits(display: &[u8; DIGITS]) -> [String; 3] {
+    let mut lines = [String::new(), String::new(), String::new()];
+    for (n, s) in display.iter().enumerate() {
+        let on = |bit: u8, c: char| if s & (1 << bit) != 0 { c } else { ' ' };
+        lines[0].extend([' ', on(0, '_'), ' ', ' ']);
+        lines[1].extend([on(5, '|'), on(6, '_'), on(1, '|'), ' ']);
+        lines[2].extend([on(4, '|'), on(3, '_'), on(2, '|'), ' ']);
+        if n == 3 {
+            for line in &mut lines {
+                line.push_str("  ");
+            }
+        }
+    }
+    lines
+}
+
+// Real time on the host terminal: the LED display, or the teletype in TTY mode.
+pub struct Terminal {
+    panel: Rc<RefCell<Panel>>,
+    input: Box<dyn InputSource>,
+    stdout: RawTerminal<Stdout>,
+    display: [u8; DIGITS],
+    sst: bool,
+    start: Instant,
+    cycles: u64,
+    next_frame: u64,
+    quit: bool,
+}
+
+impl Terminal {
+    pub fn new(panel: Rc<RefCell<Panel>>, input: Box<dyn InputSource>) -> Self {
+        let mut stdout = std::io::stdout().into_raw_mode().unwrap();
+        write!(stdout, "{}", termion::clear::All).unwrap();
+        if panel.borrow().tty() {
+            write!(stdout, "{}", termion::cursor::Goto(1, 1)).unwrap();
+        }
+        Self {
+            panel,
+            input,
+            stdout,
+            // nothing drawn yet
+            display: [0xff; DIGITS],
+            sst: false,
+            start: Instant::now(),
+            cycles: 0,
+            next_frame: 0,
+            quit: false,
+        }
+    }
+    fn draw(&mut self) {
+        let mut panel = self.panel.borrow_mut();
+        if panel.tty() {
+            let output = panel.take_output();
+            // NUL and RUBOUT are padding
+            let output = output
+                .into_iter()
+                .filter(|c| *c != 0 && *c != 0x7f)
+                .collect::<Vec<_>>();
+            self.stdout.write_all(&output).unwrap();
+            self.stdout.flush().unwrap();
+            return;
+        }
+        let display = panel.take_display();
+        if display == self.display && panel.sst() == self.sst {
+            return;
+        }
+        self.display = display;
+        self.sst = panel.sst();
+        let mut out = String::new();
+        for (y, line) in draw_digits(&display).iter().enumerate() {
+            out += &format!("{}{}", termion::cursor::Goto(2, y as u16 + 2), line);
+        }
+        out += &format!(
+            "{}SST {}  ^A AD  ^D DA  + +  Enter GO  ^P PC  ^T ST  ^R RS  ^S SST  ^C quit",
+            termion::cursor::Goto(1, 6),
+            if self.sst { "on " } else { "off" }
+        );
+        out += termion::cursor::Hide.as_ref();
+        self.stdout.write_all(out.as_bytes()).unwrap();
+        self.stdout.flush().unwrap();
+    }
+    fn frame(&mut self) {
+        self.quit |= feed_keys(self.input.as_mut(), self.cycles, &self.panel);
+        self.draw();
+        let due = Duration::from_micros(self.cycles * 1_000_000 / CLOCK_HZ);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if !self.panel.borrow().tty() {
+            write!(self.stdout, "{}", termion::cursor::Goto(1, 7)).unwrap();
+        }
+        write!(self.stdout, "\r\n{}", termion::cursor::Show).unwrap();
+    }
+}
+
+impl Dbg for Terminal {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.cycles >= self.next_frame {
+            self.next_frame = self.cycles + CLOCK_HZ / 60;
+            self.frame();
+        }
+        reset(&self.panel, reg, mem);
+        self.quit
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        switches(&self.panel, info);
+        false
+    }
+}
+
+pub struct Headless {
+    panel: Rc<RefCell<Panel>>,
+    input: Box<dyn InputSource>,
+    // the last frame with anything lit
+    display: [u8; DIGITS],
+    cycles: u64,
+    next_frame: u64,
+    quit: bool,
+}
+
+impl Headless {
+    fn frame(&mut self) {
+        self.quit |= feed_keys(self.input.as_mut(), self.cycles, &self.panel);
+        let mut panel = self.panel.borrow_mut();
+        let output = panel.take_output();
+        if !output.is_empty() {
+            let text = output
+                .into_iter()
+                .filter(|c| *c != 0 && *c != 0x7f && *c != b'\r')
+                .collect::<Vec<_>>();
+            let mut stdout = io::stdout();
+            stdout.write_all(&text).unwrap();
+            stdout.flush().unwrap();
+        }
+        let display = panel.take_display();
+        if display.iter().any(|s| *s != 0) {
+            self.display = display;
+        }
+    }
+}
+
+impl Drop for Headless {
+    fn drop(&mut self) {
+        self.frame();
+        if !self.panel.borrow().tty() {
+            println!("{}", display_text(&self.display));
+        }
+    }
+}
+
+impl Dbg for Headless {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.cycles >= self.next_frame {
+            self.next_frame = self.cycles + CLOCK_HZ / 60;
+            self.frame();
+        }
+        reset(&self.panel, reg, mem);
+        self.quit
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.cycles += info.cycles as u64;
+        switches(&self.panel, info);
+        false
+    }
+}
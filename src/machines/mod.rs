@@ -0,0 +1,6 @@
+// Complete systems: memory map, ROMs and devices of a real machine
+pub mod apple1;
+pub mod c64;
+pub mod easy6502;
+pub mod eater;
+pub mod kim1;
@@ -1,22 +1,158 @@
+use std::{
+    cell::{Cell, RefCell},
+    io,
+    rc::Rc,
+};
+
 use log::debug;
+
+// memory mapped hardware. Gets the full address, so devices decode (and mirror) their
+// registers themselves.
+pub trait Device {
+    // cpu read, may have side effects (clearing flags etc.)
+    fn read(&mut self, addr: u16) -> u8;
+    // what a read would return, without side effects. For debuggers and dumps.
+    fn peek(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+    // called after each instruction with the cycles it took
+    fn tick(&mut self, _cycles: u32) {}
+    // level of the device's IRQ output
+    fn irq(&self) -> bool {
+        false
+    }
+    // NMI is edge triggered: true once for every falling edge of the device's NMI output
+    fn nmi(&mut self) -> bool {
+        false
+    }
+}
+
+pub type DeviceRef = Rc<RefCell<dyn Device>>;
+
+// turns a mapping on and off, for bank switching
+pub type Switch = Rc<Cell<bool>>;
+
+enum Region {
+    // contents come from the image, writes are ignored
+    Rom,
+    // nothing there: reads return 0, writes are ignored
+    Unmapped,
+    Device(DeviceRef),
+    // ROM with its own contents over RAM: reads see the ROM, writes go to what is below
+    Overlay(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
 pub struct Memory {
     ram: Vec<u8>,
+    // data accesses done through read/write since the last drain_accesses(), while
+    // recording is on. load/store are not recorded, they are used for instruction fetch
+    // and by debugging tools.
+    accesses: RefCell<Vec<(u16, u8, Access)>>,
+    recording: bool,
+    // cpu reads from devices so far, a loop polling a device isn't stuck
+    device_reads: Cell<u64>,
+    // searched last to first, so later mappings win. Mappings with their switch off
+    // are skipped.
+    regions: Vec<(u16, u16, Region, Option<Switch>)>,
+    devices: Vec<DeviceRef>,
 }
 
 impl Default for Memory {
     fn default() -> Self {
-        Memory { ram: vec![0; 0] }
+        Memory::new(vec![0; 0])
     }
 }
 
 impl Memory {
     pub fn new(ram: Vec<u8>) -> Self {
-        Self { ram }
+        Self {
+            ram,
+            accesses: RefCell::new(Vec::new()),
+            recording: false,
+            device_reads: Cell::new(0),
+            regions: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+    // start..=end
+    pub fn map_device(&mut self, start: u16, end: u16, device: DeviceRef) {
+        if !self.devices.iter().any(|d| Rc::ptr_eq(d, &device)) {
+            self.devices.push(device.clone());
+        }
+        self.regions
+            .push((start, end, Region::Device(device), None));
+    }
+    // a device that is only there while 'switch' is on
+    pub fn map_switched_device(&mut self, start: u16, end: u16, device: DeviceRef, switch: Switch) {
+        if !self.devices.iter().any(|d| Rc::ptr_eq(d, &device)) {
+            self.devices.push(device.clone());
+        }
+        self.regions
+            .push((start, end, Region::Device(device), Some(switch)));
+    }
+    pub fn map_rom(&mut self, start: u16, end: u16) {
+        self.regions.push((start, end, Region::Rom, None));
+    }
+    // ROM contents at 'start' while 'switch' is on. Writes always go to the memory below.
+    // The contents must not be empty and must end at $FFFF at the latest.
+    pub fn map_overlay(&mut self, start: u16, data: Vec<u8>, switch: Switch) -> io::Result<()> {
+        if data.is_empty() || start as usize + data.len() > 0x10000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes don't fit at ${:04x}", data.len(), start),
+            ));
+        }
+        let end = (start as usize + data.len() - 1) as u16;
+        self.regions
+            .push((start, end, Region::Overlay(data), Some(switch)));
+        Ok(())
+    }
+    pub fn unmap(&mut self, start: u16, end: u16) {
+        self.regions.push((start, end, Region::Unmapped, None));
+    }
+    fn regions_at(&self, addr: u16) -> impl Iterator<Item = (u16, &Region)> {
+        self.regions
+            .iter()
+            .rev()
+            .filter(move |(start, end, _, switch)| {
+                (*start..=*end).contains(&addr) && switch.as_ref().is_none_or(|s| s.get())
+            })
+            .map(|(start, _, region, _)| (*start, region))
+    }
+    fn region(&self, addr: u16) -> Option<&Region> {
+        self.regions_at(addr).next().map(|(_, region)| region)
+    }
+    pub fn tick(&mut self, cycles: u32) {
+        for device in &self.devices {
+            device.borrow_mut().tick(cycles);
+        }
+    }
+    pub fn irq(&self) -> bool {
+        self.devices.iter().any(|d| d.borrow().irq())
+    }
+    pub fn nmi(&self) -> bool {
+        // ask every device, the edges are consumed
+        let mut nmi = false;
+        for device in &self.devices {
+            nmi |= device.borrow_mut().nmi();
+        }
+        nmi
     }
     pub fn get(&self) -> &[u8] {
         &self.ram
     }
     pub fn load(&self, addr: u16) -> u8 {
+        match self.regions_at(addr).next() {
+            Some((_, Region::Device(device))) => return device.borrow().peek(addr),
+            Some((_, Region::Unmapped)) => return 0,
+            Some((start, Region::Overlay(data))) => return data[(addr - start) as usize],
+            Some((_, Region::Rom)) | None => (),
+        }
         if addr as usize >= self.ram.len() {
             // debug!("LOAD (uninit): {:x} {:x}", addr, self.ram[addr as usize]);
             return 0;
@@ -35,7 +171,27 @@ impl Memory {
         // if addr == 0xd012 {
         //     println!("store to 0xd012: {:x}", v);
         // }
-        self.ram[addr as usize] = v;
+        let region = self
+            .regions_at(addr)
+            .map(|(_, region)| region)
+            .find(|region| !matches!(region, Region::Overlay(_)));
+        match region {
+            Some(Region::Device(device)) => device.borrow_mut().write(addr, v),
+            Some(Region::Rom) | Some(Region::Unmapped) | Some(Region::Overlay(_)) => (),
+            None => self.ram[addr as usize] = v,
+        }
+    }
+    // false if a store to 'addr' is dropped (ROM, nothing mapped or beyond the RAM)
+    pub fn is_writable(&self, addr: u16) -> bool {
+        let region = self
+            .regions_at(addr)
+            .map(|(_, region)| region)
+            .find(|region| !matches!(region, Region::Overlay(_)));
+        match region {
+            Some(Region::Device(_)) => true,
+            Some(Region::Rom) | Some(Region::Unmapped) | Some(Region::Overlay(_)) => false,
+            None => (addr as usize) < self.ram.len(),
+        }
     }
     pub fn store16(&mut self, addr: u16, v: u16) {
         let l = v as u8;
@@ -44,4 +200,51 @@ impl Memory {
         self.store(addr, l);
         self.store(addr + 1, h);
     }
+
+    // cpu data accesses
+    pub fn read(&self, addr: u16) -> u8 {
+        let v = match self.region(addr) {
+            Some(Region::Device(device)) => {
+                self.device_reads.set(self.device_reads.get() + 1);
+                device.borrow_mut().read(addr)
+            }
+            _ => self.load(addr),
+        };
+        if self.recording {
+            self.accesses.borrow_mut().push((addr, v, Access::Read));
+        }
+        v
+    }
+    pub fn read16(&self, addr: u16) -> u16 {
+        let l = self.read(addr) as u16;
+        let h = self.read(addr + 1) as u16;
+        l + (h << 8)
+    }
+    pub fn write(&mut self, addr: u16, v: u8) {
+        if self.recording {
+            self.accesses.get_mut().push((addr, v, Access::Write));
+        }
+        self.store(addr, v);
+    }
+    pub fn write16(&mut self, addr: u16, v: u16) {
+        self.write(addr, v as u8);
+        self.write(addr + 1, (v >> 8) as u8);
+    }
+    pub fn device_reads(&self) -> u64 {
+        self.device_reads.get()
+    }
+    // only worth it while someone looks at the accesses, see Dbg::wants_accesses
+    pub fn set_recording(&mut self, on: bool) {
+        self.recording = on;
+        if !on {
+            self.accesses.get_mut().clear();
+        }
+    }
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+    // keeps the buffer for the next instruction
+    pub fn drain_accesses(&mut self) -> std::vec::Drain<'_, (u16, u8, Access)> {
+        self.accesses.get_mut().drain(..)
+    }
 }
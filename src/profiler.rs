@@ -0,0 +1,288 @@
+// Instruction level profiler: execution counts and cycles per PC, attributed to
+// subroutines through the shadow call stack.
+//
+// Reports:
+//  - flat hot-spot list per PC
+//  - inclusive/exclusive cycles per routine
+//  - folded stacks ('main;GETLINE;ECHO 1234'), as consumed by flamegraph.pl / inferno
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    callstack::{CallStack, FrameKind},
+    cpu::StepInfo,
+    dbg::Dbg,
+    disasm,
+    mem::Memory,
+    reg::Registers,
+    symbols::SymbolTable,
+};
+
+// one node per distinct call path
+struct Node {
+    routine: u16,
+    interrupt: bool,
+    parent: Option<usize>,
+    children: HashMap<(u16, bool), usize>,
+    cycles: u64,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct RoutineStats {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+pub struct Profiler {
+    counts: Vec<u64>,
+    cycles: Vec<u64>,
+    total_cycles: u64,
+    instructions: u64,
+    callstack: CallStack,
+    nodes: Vec<Node>,
+    current: usize,
+    // (depth, sp and entry of the innermost frame) the current node was computed for
+    current_key: (usize, u16, u16),
+    calls: HashMap<u16, u64>,
+    symbols: Rc<SymbolTable>,
+}
+
+impl Profiler {
+    pub fn new(symbols: Rc<SymbolTable>) -> Self {
+        Self {
+            counts: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            total_cycles: 0,
+            instructions: 0,
+            callstack: CallStack::default(),
+            nodes: Vec::new(),
+            current: 0,
+            current_key: (0, 0, 0),
+            calls: HashMap::new(),
+            symbols,
+        }
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    fn child(&mut self, parent: usize, routine: u16, interrupt: bool) -> usize {
+        if let Some(child) = self.nodes[parent].children.get(&(routine, interrupt)) {
+            return *child;
+        }
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            routine,
+            interrupt,
+            parent: Some(parent),
+            children: HashMap::new(),
+            cycles: 0,
+        });
+        self.nodes[parent].children.insert((routine, interrupt), id);
+        id
+    }
+
+    pub fn account(&mut self, pc: u16, cycles: u32) {
+        self.counts[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles as u64;
+        self.nodes[self.current].cycles += cycles as u64;
+        self.total_cycles += cycles as u64;
+        self.instructions += 1;
+    }
+
+    // map the shadow call stack to a node, only re-walked when the stack changed
+    fn update_current(&mut self) {
+        let frames = self.callstack.frames();
+        let key = frames
+            .last()
+            .map(|f| (frames.len(), f.sp, f.target))
+            .unwrap_or((0, 0, 0));
+        if key == self.current_key {
+            return;
+        }
+        let entered = frames.len() > self.current_key.0;
+        self.current_key = key;
+        let path = frames
+            .iter()
+            .map(|f| (f.target, f.kind == FrameKind::Interrupt))
+            .collect::<Vec<_>>();
+        if entered {
+            if let Some((routine, _)) = path.last() {
+                *self.calls.entry(*routine).or_default() += 1;
+            }
+        }
+        let mut node = 0;
+        for (routine, interrupt) in path {
+            node = self.child(node, routine, interrupt);
+        }
+        self.current = node;
+    }
+
+    // to be called before each instruction, the cycles are accounted after it ran
+    pub fn observe(&mut self, reg: &Registers, mem: &Memory) {
+        if self.nodes.is_empty() {
+            // the code running at the start is the root of all call paths
+            self.nodes.push(Node {
+                routine: reg.pc,
+                interrupt: false,
+                parent: None,
+                children: HashMap::new(),
+                cycles: 0,
+            });
+        }
+        self.callstack.observe(reg, mem);
+        self.update_current();
+    }
+
+    fn routine_name(&self, node: &Node) -> String {
+        let name = match self.symbols.lookup(node.routine) {
+            Some(name) => name.to_string(),
+            None => format!("${:04x}", node.routine),
+        };
+        if node.interrupt {
+            format!("{} <interrupt>", name)
+        } else {
+            name
+        }
+    }
+
+    fn path(&self, mut id: usize) -> Vec<usize> {
+        let mut path = vec![id];
+        while let Some(parent) = self.nodes[id].parent {
+            path.push(parent);
+            id = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    pub fn routines(&self) -> HashMap<u16, RoutineStats> {
+        let mut out: HashMap<u16, RoutineStats> = HashMap::new();
+        for (id, node) in self.nodes.iter().enumerate() {
+            out.entry(node.routine).or_default().exclusive += node.cycles;
+            // recursive calls must not count twice towards inclusive time
+            let routines = self
+                .path(id)
+                .iter()
+                .map(|n| self.nodes[*n].routine)
+                .collect::<HashSet<_>>();
+            for routine in routines {
+                out.entry(routine).or_default().inclusive += node.cycles;
+            }
+        }
+        for (routine, calls) in &self.calls {
+            out.entry(*routine).or_default().calls = *calls;
+        }
+        out
+    }
+
+    pub fn write_hotspots(
+        &self,
+        out: &mut dyn Write,
+        mem: &Memory,
+        limit: usize,
+    ) -> io::Result<()> {
+        let mut pcs = (0..0x10000usize)
+            .filter(|pc| self.counts[*pc] != 0)
+            .collect::<Vec<_>>();
+        pcs.sort_by_key(|pc| std::cmp::Reverse(self.cycles[*pc]));
+        writeln!(
+            out,
+            "hot spots ({} instructions, {} cycles):",
+            self.instructions, self.total_cycles
+        )?;
+        writeln!(
+            out,
+            "{:>12} {:>12} {:>6}  {:<24} instruction",
+            "count", "cycles", "%", "location"
+        )?;
+        for pc in pcs.iter().take(limit) {
+            let inst = disasm::disassemble(mem, *pc as u16);
+            writeln!(
+                out,
+                "{:>12} {:>12} {:>6.2}  {:<24} {}",
+                self.counts[*pc],
+                self.cycles[*pc],
+                percent(self.cycles[*pc], self.total_cycles),
+                format!("${:04x} {}", pc, self.symbols.describe(*pc as u16, 0xffff)),
+                inst.text_with(Some(&self.symbols))
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_routines(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut routines = self.routines().into_iter().collect::<Vec<_>>();
+        routines.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.inclusive));
+        writeln!(out, "routines:")?;
+        writeln!(
+            out,
+            "{:>10} {:>12} {:>7} {:>12} {:>7}  routine",
+            "calls", "inclusive", "%", "exclusive", "%"
+        )?;
+        for (routine, stats) in routines {
+            let name = match self.symbols.lookup(routine) {
+                Some(name) => format!("${:04x} {}", routine, name),
+                None => format!("${:04x}", routine),
+            };
+            writeln!(
+                out,
+                "{:>10} {:>12} {:>7.2} {:>12} {:>7.2}  {}",
+                stats.calls,
+                stats.inclusive,
+                percent(stats.inclusive, self.total_cycles),
+                stats.exclusive,
+                percent(stats.exclusive, self.total_cycles),
+                name
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_report(&self, out: &mut dyn Write, mem: &Memory) -> io::Result<()> {
+        self.write_hotspots(out, mem, 40)?;
+        writeln!(out)?;
+        self.write_routines(out)
+    }
+
+    // one line per call path with the cycles spent in its innermost routine
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (id, node) in self.nodes.iter().enumerate() {
+            if node.cycles == 0 {
+                continue;
+            }
+            let path = self
+                .path(id)
+                .iter()
+                .map(|n| self.routine_name(&self.nodes[*n]).replace([';', ' '], "_"))
+                .collect::<Vec<_>>()
+                .join(";");
+            writeln!(out, "{} {}", path, node.cycles)?;
+        }
+        Ok(())
+    }
+}
+
+fn percent(v: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        v as f64 * 100.0 / total as f64
+    }
+}
+
+impl Dbg for Profiler {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        self.observe(reg, mem);
+        false
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, _mem: &mut Memory) -> bool {
+        self.account(info.pc, info.cycles);
+        false
+    }
+}
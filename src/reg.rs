@@ -1,3 +1,4 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Registers {
     pub pc: u16,
     pub sp: u16,
@@ -59,7 +60,7 @@ impl Registers {
         self.y = y;
     }
     pub fn and(&mut self, a: u8) {
-        self.a = self.a & a;
+        self.a &= a;
         self.sr.update_nz(self.a);
     }
     pub fn lsr(&mut self, v: u8) -> u8 {
@@ -89,8 +90,8 @@ impl Registers {
         res
     }
     pub fn bit(&mut self, v: u8) {
-        self.sr.n = (v & 0b1000000) != 0;
-        self.sr.v = (v & 0b100000) != 0;
+        self.sr.n = (v & 0b10000000) != 0;
+        self.sr.v = (v & 0b1000000) != 0;
         self.sr.z = (self.a & v) == 0;
     }
 }
@@ -116,6 +117,7 @@ impl std::fmt::Display for Registers {
         )
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct StatusRegister {
     pub n: bool,
     pub v: bool,
@@ -0,0 +1,298 @@
+// Pixel exact captures of a machine's video output as PPM or PNG, without a terminal.
+// Meant for golden image checks of graphical test programs.
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use log::{error, info};
+
+use crate::{cpu::StepInfo, dbg::Dbg, mem::Memory, reg::Registers};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    // rgb, row by row
+    pub pixels: Vec<(u8, u8, u8)>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![(0, 0, 0); width * height],
+        }
+    }
+    pub fn set(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        self.pixels[y * self.width + x] = rgb;
+    }
+    pub fn get(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        self.pixels[y * self.width + x]
+    }
+    // each pixel becomes a 'factor' x 'factor' block
+    pub fn scaled(&self, factor: usize) -> Image {
+        let mut out = Image::new(self.width * factor, self.height * factor);
+        for y in 0..out.height {
+            for x in 0..out.width {
+                out.set(x, y, self.get(x / factor, y / factor));
+            }
+        }
+        out
+    }
+    fn bytes(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|p| [p.0, p.1, p.2]).collect()
+    }
+    // binary PPM (P6)
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.bytes())
+    }
+    pub fn write_png<W: Write>(&self, out: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer
+            .write_image_data(&self.bytes())
+            .map_err(io::Error::other)?;
+        writer.finish().map_err(io::Error::other)
+    }
+    // format by extension: .ppm or .png
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let mut out = BufWriter::new(File::create(path)?);
+        match ext.as_deref() {
+            Some("ppm") => self.write_ppm(&mut out)?,
+            Some("png") => self.write_png(&mut out)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: unknown image format, use .png or .ppm", path),
+                ))
+            }
+        }
+        out.flush()
+    }
+}
+
+// the video output of a machine
+pub trait Video {
+    fn capture(&self, mem: &Memory) -> Image;
+}
+
+// 5x7 dots of ASCII $20-$5F, the 64 characters of text only machines. A byte per
+// column, bit 0 at the top.
+const FONT: [[u8; 5]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5f, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14],
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50],
+    [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00],
+    [0x14, 0x08, 0x3e, 0x08, 0x14],
+    [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3e, 0x51, 0x49, 0x45, 0x3e],
+    [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46],
+    [0x21, 0x41, 0x45, 0x4b, 0x31],
+    [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3c, 0x4a, 0x49, 0x49, 0x30],
+    [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x06, 0x49, 0x49, 0x29, 0x1e],
+    [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x51, 0x09, 0x06],
+    [0x32, 0x49, 0x79, 0x41, 0x3e],
+    [0x7e, 0x11, 0x11, 0x11, 0x7e],
+    [0x7f, 0x49, 0x49, 0x49, 0x36],
+    [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x22, 0x1c],
+    [0x7f, 0x49, 0x49, 0x49, 0x41],
+    [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x49, 0x49, 0x7a],
+    [0x7f, 0x08, 0x08, 0x08, 0x7f],
+    [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01],
+    [0x7f, 0x08, 0x14, 0x22, 0x41],
+    [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f],
+    [0x7f, 0x04, 0x08, 0x10, 0x7f],
+    [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06],
+    [0x3e, 0x41, 0x51, 0x21, 0x5e],
+    [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7f, 0x01, 0x01],
+    [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f],
+    [0x3f, 0x40, 0x38, 0x40, 0x3f],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07],
+    [0x61, 0x51, 0x49, 0x45, 0x43],
+    [0x00, 0x7f, 0x41, 0x41, 0x00],
+    [0x02, 0x04, 0x08, 0x10, 0x20],
+    [0x00, 0x41, 0x41, 0x7f, 0x00],
+    [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+];
+
+// size of a character cell of text_image
+pub const CELL_WIDTH: usize = 6;
+pub const CELL_HEIGHT: usize = 8;
+
+// A text screen drawn with the built in font. 'rows' are ASCII, characters outside
+// $20-$5F are blank. 'reverse' swaps the colors of a character.
+pub fn text_image(rows: &[Vec<(u8, bool)>], fg: (u8, u8, u8), bg: (u8, u8, u8)) -> Image {
+    let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let mut image = Image::new(columns * CELL_WIDTH, rows.len() * CELL_HEIGHT);
+    for (row, line) in rows.iter().enumerate() {
+        for (col, (c, reverse)) in line.iter().enumerate() {
+            let glyph = match c {
+                0x20..=0x5f => FONT[(c - 0x20) as usize],
+                _ => FONT[0],
+            };
+            for y in 0..CELL_HEIGHT {
+                for x in 0..CELL_WIDTH {
+                    let on = glyph.get(x).is_some_and(|dots| dots & (1 << y) != 0);
+                    let color = if on != *reverse { fg } else { bg };
+                    image.set(col * CELL_WIDTH + x, row * CELL_HEIGHT + y, color);
+                }
+            }
+        }
+    }
+    image
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    // once the cpu has run that many cycles
+    Cycle(u64),
+    // the first time the cpu is about to execute the instruction at this address. Give
+    // it again to also fire on the next visit.
+    Pc(u16),
+}
+
+// Trigger firing shared by the hooks that save something
+#[derive(Default)]
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    cycles: u64,
+}
+
+impl Triggers {
+    pub fn push(&mut self, trigger: Trigger) {
+        self.triggers.push(trigger);
+    }
+    // true if a Pc trigger is at the instruction about to run. The trigger is used up,
+    // a loop through it doesn't save a file every time around.
+    pub fn at_pc(&mut self, pc: u16) -> bool {
+        match self.triggers.iter().position(|t| *t == Trigger::Pc(pc)) {
+            Some(i) => {
+                self.triggers.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+    // counts the cycles of a step, true if a Cycle trigger fell into them
+    pub fn after_step(&mut self, cycles: u32) -> bool {
+        let before = self.cycles;
+        self.cycles += cycles as u64;
+        self.triggers
+            .iter()
+            .any(|t| matches!(t, Trigger::Cycle(c) if (before..self.cycles).contains(c)))
+    }
+}
+
+// 'name' for the first file, 'name-1.ext', 'name-2.ext', ... for the following ones
+pub fn numbered_path(path: &str, n: usize) -> String {
+    if n == 0 {
+        return path.to_string();
+    }
+    let p = Path::new(path);
+    let stem = p
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("screenshot");
+    let name = match p.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext),
+        None => format!("{}-{}", stem, n),
+    };
+    p.with_file_name(name).to_string_lossy().to_string()
+}
+
+// Saves a capture whenever one of the triggers fires
+pub struct Screenshot {
+    video: Box<dyn Video>,
+    path: String,
+    scale: usize,
+    triggers: Triggers,
+    saved: usize,
+}
+
+impl Screenshot {
+    pub fn new(video: Box<dyn Video>, path: &str) -> Self {
+        Self {
+            video,
+            path: path.to_string(),
+            scale: 1,
+            triggers: Triggers::default(),
+            saved: 0,
+        }
+    }
+    pub fn at(mut self, trigger: Trigger) -> Self {
+        self.triggers.push(trigger);
+        self
+    }
+    pub fn with_scale(mut self, scale: usize) -> Self {
+        self.scale = scale.max(1);
+        self
+    }
+    // capture now, returns the file written
+    pub fn save(&mut self, mem: &Memory) -> io::Result<String> {
+        let path = numbered_path(&self.path, self.saved);
+        self.saved += 1;
+        self.video.capture(mem).scaled(self.scale).save(&path)?;
+        info!("screenshot saved to {}", path);
+        Ok(path)
+    }
+    fn save_or_log(&mut self, mem: &Memory) {
+        if let Err(e) = self.save(mem) {
+            error!("screenshot failed: {}", e);
+        }
+    }
+}
+
+impl Dbg for Screenshot {
+    fn step(&mut self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.triggers.at_pc(reg.pc) {
+            self.save_or_log(mem);
+        }
+        false
+    }
+    fn post_step(&mut self, info: &StepInfo, _reg: &mut Registers, mem: &mut Memory) -> bool {
+        if self.triggers.after_step(info.cycles) {
+            self.save_or_log(mem);
+        }
+        false
+    }
+}
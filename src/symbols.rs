@@ -0,0 +1,271 @@
+// Symbol tables for addresses. Supported inputs:
+//  - ca65/ld65 debug info (.dbg, 'ld65 --dbgfile'), including source line information
+//  - VICE label files (.lbl/.vs, 'al C:1234 .name')
+//  - plain maps with one 'name = $addr' per line
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    io,
+    path::Path,
+};
+
+#[derive(Default)]
+pub struct SymbolTable {
+    names: HashMap<String, u16>,
+    addrs: BTreeMap<u16, Vec<String>>,
+    // (file, line) -> first address generated for it
+    lines: HashMap<(String, u32), u16>,
+    addr_lines: BTreeMap<u16, (String, u32)>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// 'line' is 0 based, like the enumerate() index
+fn address(addr: u32, line: usize) -> io::Result<u16> {
+    u16::try_from(addr)
+        .map_err(|_| invalid(format!("line {}: address ${:x} past $ffff", line + 1, addr)))
+}
+
+// '$1234', '0x1234' or decimal
+pub fn parse_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+// split 'key=value,key="quoted, value"' attribute lists of ca65 debug files
+fn dbg_attributes(s: &str) -> HashMap<&str, &str> {
+    let mut out = HashMap::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let Some(eq) = rest.find('=') else { break };
+        let key = &rest[..eq];
+        let value_start = &rest[eq + 1..];
+        let (value, next) = if let Some(quoted) = value_start.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            (&quoted[..end], quoted.get(end + 1..).unwrap_or(""))
+        } else {
+            let end = value_start.find(',').unwrap_or(value_start.len());
+            (&value_start[..end], &value_start[end..])
+        };
+        out.insert(key.trim(), value);
+        rest = next.strip_prefix(',').unwrap_or(next);
+    }
+    out
+}
+
+impl SymbolTable {
+    // pick the format from the file extension
+    pub fn load(&mut self, name: &str) -> io::Result<()> {
+        let text = std::fs::read_to_string(name)?;
+        match Path::new(name).extension().and_then(|e| e.to_str()) {
+            Some("dbg") => self.load_ca65_dbg(&text),
+            Some("lbl") | Some("vs") => self.load_vice(&text),
+            _ => self.load_map(&text),
+        }
+        .map_err(|e| invalid(format!("{}: {}", name, e)))
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        if let Some(old) = self.names.insert(name.to_string(), addr) {
+            if let Some(names) = self.addrs.get_mut(&old) {
+                names.retain(|n| n != name);
+            }
+        }
+        self.addrs.entry(addr).or_default().push(name.to_string());
+    }
+
+    pub fn load_map(&mut self, text: &str) -> io::Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap_or("");
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(invalid(format!("line {}: expected 'name = $addr'", i + 1)));
+            };
+            let Some(addr) = parse_number(value) else {
+                return Err(invalid(format!("line {}: bad address '{}'", i + 1, value)));
+            };
+            self.insert(name.trim(), address(addr, i)?);
+        }
+        Ok(())
+    }
+
+    pub fn load_vice(&mut self, text: &str) -> io::Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("al") => (),
+                // other monitor commands (break, etc.) are not labels
+                _ => continue,
+            }
+            let (Some(addr), Some(name)) = (parts.next(), parts.next()) else {
+                return Err(invalid(format!(
+                    "line {}: expected 'al <addr> <label>'",
+                    i + 1
+                )));
+            };
+            let addr = addr.rsplit(':').next().unwrap_or(addr);
+            let Ok(addr) = u32::from_str_radix(addr, 16) else {
+                return Err(invalid(format!("line {}: bad address '{}'", i + 1, addr)));
+            };
+            self.insert(name.trim_start_matches('.'), address(addr, i)?);
+        }
+        Ok(())
+    }
+
+    pub fn load_ca65_dbg(&mut self, text: &str) -> io::Result<()> {
+        let mut files = HashMap::new();
+        let mut segs = HashMap::new();
+        let mut spans = HashMap::new();
+        let mut lines = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let Some((kind, attrs)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let attrs = dbg_attributes(attrs.trim());
+            let num = |key: &str| attrs.get(key).and_then(|v| parse_number(v));
+            let id = num("id");
+            match kind {
+                "file" => {
+                    if let (Some(id), Some(name)) = (id, attrs.get("name")) {
+                        files.insert(id, name.to_string());
+                    }
+                }
+                "seg" => {
+                    if let (Some(id), Some(start)) = (id, num("start")) {
+                        segs.insert(id, start);
+                    }
+                }
+                "span" => {
+                    if let (Some(id), Some(seg), Some(start)) = (id, num("seg"), num("start")) {
+                        spans.insert(id, (seg, start));
+                    }
+                }
+                "line" => {
+                    if let (Some(file), Some(line), Some(span)) =
+                        (num("file"), num("line"), attrs.get("span"))
+                    {
+                        lines.push((i, file, line, span.to_string()));
+                    }
+                }
+                "sym" => {
+                    let (Some(name), Some(val)) = (attrs.get("name"), num("val")) else {
+                        continue;
+                    };
+                    match attrs.get("type") {
+                        Some(&"lab") => self.insert(name, address(val, i)?),
+                        // equates are constants as well, only those that fit can be addresses
+                        Some(&"equ") if val <= 0xffff => self.insert(name, val as u16),
+                        _ => (),
+                    }
+                }
+                "version" | "info" | "mod" | "scope" | "lib" | "type" | "csym" => (),
+                _ => {
+                    return Err(invalid(format!(
+                        "line {}: unknown record '{}'",
+                        i + 1,
+                        kind
+                    )));
+                }
+            }
+        }
+        for (i, file, line, span_list) in lines {
+            let Some(file) = files.get(&file) else {
+                continue;
+            };
+            // a line can be associated with several spans (e.g. macros), use the lowest address
+            let addr = span_list
+                .split('+')
+                .filter_map(|s| s.parse::<u32>().ok())
+                .filter_map(|span| spans.get(&span))
+                .filter_map(|(seg, start)| segs.get(seg).map(|base| address(base + start, i)))
+                .collect::<io::Result<Vec<_>>>()?
+                .into_iter()
+                .min();
+            if let Some(addr) = addr {
+                let key = (file.clone(), line);
+                let entry = self.lines.entry(key.clone()).or_insert(addr);
+                *entry = (*entry).min(addr);
+                self.addr_lines.entry(addr).or_insert(key);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.lines.is_empty()
+    }
+    pub fn has_lines(&self) -> bool {
+        !self.lines.is_empty()
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<u16> {
+        self.names.get(name).copied()
+    }
+    pub fn lookup(&self, addr: u16) -> Option<&str> {
+        self.addrs
+            .get(&addr)
+            .and_then(|names| names.first())
+            .map(|s| s.as_str())
+    }
+    // labels in start..=end
+    pub fn labels_in(&self, start: u16, end: u16) -> impl Iterator<Item = (u16, &str)> {
+        self.addrs
+            .range(start..=end)
+            .flat_map(|(addr, names)| names.iter().map(move |n| (*addr, n.as_str())))
+    }
+    // nearest label at or below addr, with the distance to it
+    pub fn nearest(&self, addr: u16) -> Option<(&str, u16)> {
+        let (label_addr, names) = self.addrs.range(..=addr).next_back()?;
+        Some((names.first()?.as_str(), addr - label_addr))
+    }
+    // 'NAME', 'NAME+3' or '$1234' if there is no label within 'max_offset'
+    pub fn describe(&self, addr: u16, max_offset: u16) -> String {
+        match self.nearest(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offs)) if offs <= max_offset => format!("{}+{}", name, offs),
+            _ => format!("${:04x}", addr),
+        }
+    }
+
+    // match source paths by their last components, editors usually send absolute paths
+    pub fn line_address(&self, file: &str, line: u32) -> Option<u16> {
+        let file = Path::new(file.trim_start_matches("./"));
+        self.lines
+            .iter()
+            .filter(|((f, l), _)| {
+                let f = Path::new(f.trim_start_matches("./"));
+                *l == line && (file.ends_with(f) || f.ends_with(file))
+            })
+            .map(|(_, addr)| *addr)
+            .min()
+    }
+    pub fn source_line(&self, addr: u16) -> Option<(&str, u32)> {
+        self.addr_lines
+            .get(&addr)
+            .map(|(file, line)| (file.as_str(), *line))
+    }
+
+    // address expressions: 'GETLINE', '$e000', 'BUF+2', 'PTR-1'
+    pub fn eval(&self, expr: &str) -> Option<u16> {
+        let expr = expr.trim();
+        if let Some(pos) = expr.rfind(['+', '-']).filter(|p| *p > 0) {
+            let base = self.eval(&expr[..pos])?;
+            let offs = parse_number(&expr[pos + 1..])? as u16;
+            return Some(if &expr[pos..pos + 1] == "+" {
+                base.wrapping_add(offs)
+            } else {
+                base.wrapping_sub(offs)
+            });
+        }
+        self.resolve(expr)
+            .or_else(|| parse_number(expr).map(|v| v as u16))
+    }
+}
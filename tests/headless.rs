@@ -0,0 +1,58 @@
+// Headless Apple-1 runs: keys typed from a script, checks on the text the machine
+// printed.
+use std::fs;
+
+use mos6502::{
+    cpu::Cpu,
+    input::Script,
+    machines::apple1::{self, Apple1},
+};
+
+// In place of Wozmon: sets up the PIA like it does and echoes every key.
+//
+//   ff00  ldy #$7f / sty DSP / lda #$a7 / sta KBDCR / sta DSPCR
+//   ff0d  lda KBDCR / bpl ff0d / lda KBD / jsr ff1b / jmp ff0d
+//   ff1b  bit DSP / bmi ff1b / sta DSP / rts
+const ECHO: [u8; 0x24] = [
+    0xa0, 0x7f, 0x8c, 0x12, 0xd0, 0xa9, 0xa7, 0x8d, 0x11, 0xd0, 0x8d, 0x13, 0xd0, 0xad, 0x11, 0xd0,
+    0x10, 0xfb, 0xad, 0x10, 0xd0, 0x20, 0x1b, 0xff, 0x4c, 0x0d, 0xff, 0x2c, 0x12, 0xd0, 0x30, 0xfb,
+    0x8d, 0x12, 0xd0, 0x60,
+];
+
+fn echo_rom(name: &str) -> String {
+    let mut rom = vec![0; 0x100];
+    rom[..ECHO.len()].copy_from_slice(&ECHO);
+    // NMI, reset and IRQ all at $ff00
+    rom[0xfa..].copy_from_slice(&[0x00, 0xff, 0x00, 0xff, 0x00, 0xff]);
+    let path = format!("{}/{}", env!("CARGO_TARGET_TMPDIR"), name);
+    fs::write(&path, rom).unwrap();
+    path
+}
+
+// runs the Apple-1 until the script presses Ctrl-C, returns what it printed
+fn run_apple1(script: &str) -> String {
+    let config = apple1::Config {
+        wozmon: echo_rom("echo.bin"),
+        basic: None,
+        chars_per_second: 0,
+        ..apple1::Config::default()
+    };
+    let (apple1, mem) = Apple1::new(&config).unwrap();
+    let mut cpu = Cpu::new(mem);
+    cpu.reset();
+    let mut headless = apple1.headless(Box::new(Script::parse(script).unwrap()));
+    cpu.run(&mut headless);
+    headless.output().to_string()
+}
+
+#[test]
+fn typed_lines_are_printed() {
+    let output = run_apple1(
+        "after 100k\n\
+         type 10 print \"hi\"\\n\n\
+         type run\\n\n\
+         after 500k\n\
+         key ctrl-c\n",
+    );
+    assert_eq!(output, "10 PRINT \"HI\"\nRUN\n");
+}